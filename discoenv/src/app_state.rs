@@ -1,6 +1,12 @@
 use crate::auth;
+use crate::crypto::Keyring;
+use crate::db::analyses::AnalysisStatusEvent;
+use crate::dispatch::JobDispatcher;
 use crate::handlers;
+use chrono_tz::Tz;
 use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use tokio::sync::broadcast;
 
 #[derive(Debug, Clone)]
 pub struct DiscoenvState {
@@ -8,4 +14,9 @@ pub struct DiscoenvState {
     pub handler_config: handlers::config::HandlerConfiguration,
     pub auth: auth::Authenticator,
     pub admin_entitlements: Vec<String>,
+    pub service_accounts: Vec<String>,
+    pub keyring: Option<Keyring>,
+    pub status_events: broadcast::Sender<AnalysisStatusEvent>,
+    pub db_timezone: Tz,
+    pub dispatcher: Option<Arc<dyn JobDispatcher>>,
 }
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::{write::DeflateEncoder, Compression, Crc};
+use std::io::{self, Write};
+
+/// A file that's been added to a zip archive, for the central directory.
+struct ZipEntry {
+    path: String,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a zip file front to back without seeking, so that it can be sent
+/// while it's being written. Entries are deflated in memory before they're
+/// written, so their sizes and checksums go in their local headers. ZIP64
+/// isn't supported, which limits archives to 4 GiB and 65,535 files.
+///
+/// The zip crate can't be used for this. Its writer in the version that builds
+/// with our Rust toolchain needs `Seek` to go back and fill in each entry's
+/// sizes once it's been compressed, and the version that can stream needs a
+/// newer compiler than the one the images are built with.
+pub struct ZipStream {
+    out: Vec<u8>,
+    written: u64,
+    entries: Vec<ZipEntry>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "the archive is too large for a zip file",
+    )
+}
+
+fn zip_u32(n: impl TryInto<u32>) -> io::Result<u32> {
+    n.try_into().map_err(|_| too_large())
+}
+
+impl ZipStream {
+    /// Starts an archive whose entries are all modified at mtime.
+    pub fn new(mtime: DateTime<Utc>) -> Self {
+        // DOS dates start in 1980 and times have two-second resolution.
+        let year = mtime.year().clamp(1980, 2107) as u16;
+        ZipStream {
+            out: Vec::new(),
+            written: 0,
+            entries: Vec::new(),
+            dos_time: ((mtime.hour() as u16) << 11)
+                | ((mtime.minute() as u16) << 5)
+                | (mtime.second() as u16 / 2),
+            dos_date: ((year - 1980) << 9) | ((mtime.month() as u16) << 5) | mtime.day() as u16,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+        self.written += bytes.len() as u64;
+    }
+
+    /// Writes the fields that local headers and central directory entries share,
+    /// from the version needed to extract through the extra field length.
+    fn write_common_header(&mut self, crc: u32, compressed_size: u32, size: u32, path_len: u16) {
+        let mut header = Vec::with_capacity(26);
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed to extract
+        header.extend_from_slice(&0x0800u16.to_le_bytes()); // UTF-8 names
+        header.extend_from_slice(&8u16.to_le_bytes()); // deflate
+        header.extend_from_slice(&self.dos_time.to_le_bytes());
+        header.extend_from_slice(&self.dos_date.to_le_bytes());
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&compressed_size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&path_len.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        self.write(&header);
+    }
+
+    /// Compresses the file and adds it to the archive.
+    pub fn add(&mut self, path: &str, contents: &[u8]) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(contents)?;
+        let compressed = encoder.finish()?;
+
+        let mut crc = Crc::new();
+        crc.update(contents);
+
+        let entry = ZipEntry {
+            path: path.to_owned(),
+            crc: crc.sum(),
+            compressed_size: zip_u32(compressed.len())?,
+            size: zip_u32(contents.len())?,
+            offset: zip_u32(self.written)?,
+        };
+        let path_len = u16::try_from(path.len()).map_err(|_| too_large())?;
+
+        self.write(&0x04034b50u32.to_le_bytes());
+        self.write_common_header(entry.crc, entry.compressed_size, entry.size, path_len);
+        self.write(path.as_bytes());
+        self.write(&compressed);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Returns the part of the archive written since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    /// Writes the central directory and returns whatever hasn't been taken yet.
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        let directory_start = zip_u32(self.written)?;
+        let entries = std::mem::take(&mut self.entries);
+
+        for entry in &entries {
+            self.write(&0x02014b50u32.to_le_bytes());
+            self.write(&((3u16 << 8) | 20).to_le_bytes()); // made by Unix, version 2.0
+            self.write_common_header(
+                entry.crc,
+                entry.compressed_size,
+                entry.size,
+                entry.path.len() as u16,
+            );
+            self.write(&0u16.to_le_bytes()); // comment length
+            self.write(&0u16.to_le_bytes()); // disk number
+            self.write(&0u16.to_le_bytes()); // internal attributes
+            self.write(&(0o100644u32 << 16).to_le_bytes()); // a regular file, rw-r--r--
+            self.write(&entry.offset.to_le_bytes());
+            self.write(entry.path.as_bytes());
+        }
+
+        let count = u16::try_from(entries.len()).map_err(|_| too_large())?;
+        let directory_size = zip_u32(self.written - directory_start as u64)?;
+        self.write(&0x06054b50u32.to_le_bytes());
+        self.write(&0u16.to_le_bytes()); // this disk
+        self.write(&0u16.to_le_bytes()); // the disk the directory starts on
+        self.write(&count.to_le_bytes());
+        self.write(&count.to_le_bytes());
+        self.write(&directory_size.to_le_bytes());
+        self.write(&directory_start.to_le_bytes());
+        self.write(&0u16.to_le_bytes()); // comment length
+
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_zip_stream() {
+        let mut zip = ZipStream::new(Utc::now());
+        zip.add("a.json", b"{\"a\": 1}").unwrap();
+        let mut written = zip.take_output();
+        assert!(!written.is_empty());
+
+        zip.add("dir/b.txt", &[b'b'; 4096]).unwrap();
+        written.extend(zip.finish().unwrap());
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(written)).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        let mut contents = String::new();
+        archive
+            .by_name("a.json")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "{\"a\": 1}");
+
+        let mut contents = Vec::new();
+        archive
+            .by_name("dir/b.txt")
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, vec![b'b'; 4096]);
+    }
+}
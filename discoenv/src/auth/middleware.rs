@@ -45,6 +45,22 @@ where
     Ok(next.run(req).await)
 }
 
+/// Returns whether any of the user's entitlements are admin entitlements.
+pub fn is_admin(user_info: &UserInfo, admin_entitlements: &[String]) -> bool {
+    match &user_info.entitlement {
+        Some(user_ents) => user_ents.iter().any(|e| admin_entitlements.contains(e)),
+        None => false,
+    }
+}
+
+/// Returns whether the user is one of the configured service accounts.
+pub fn is_service_account(user_info: &UserInfo, service_accounts: &[String]) -> bool {
+    match &user_info.preferred_username {
+        Some(username) => service_accounts.contains(username),
+        None => false,
+    }
+}
+
 pub async fn require_entitlements<B>(
     State(state): State<Arc<DiscoenvState>>,
     request: Request<B>,
@@ -98,3 +114,29 @@ where
     let req = Request::from_parts(parts, body);
     Ok(next.run(req).await)
 }
+
+/// Only lets requests from the configured service accounts and admins through.
+pub async fn require_service_account<B>(
+    State(state): State<Arc<DiscoenvState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode>
+where
+    B: Send,
+{
+    let (mut parts, body) = request.into_parts();
+
+    let user_info: Extension<UserInfo> = parts
+        .extract()
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    if !is_service_account(&user_info, &state.service_accounts)
+        && !is_admin(&user_info, &state.admin_entitlements)
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let req = Request::from_parts(parts, body);
+    Ok(next.run(req).await)
+}
@@ -1,6 +1,7 @@
 pub mod middleware;
 
 use cached::{proc_macro::cached, stores::CanExpire};
+use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 use url::{ParseError, Url};
@@ -95,6 +96,23 @@ pub struct UserInfo {
     pub resource_access: Option<ResourceAccess>,
     pub entitlement: Option<Vec<String>>,
 }
+impl UserInfo {
+    /// Returns when the token expires, if the introspection result said.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.exp
+            .and_then(|exp| i64::try_from(exp).ok())
+            .and_then(|exp| Utc.timestamp_opt(exp, 0).single())
+    }
+
+    /// Returns the user's realm roles.
+    pub fn roles(&self) -> Vec<String> {
+        self.realm_access
+            .as_ref()
+            .and_then(|r| r.roles.clone())
+            .unwrap_or_default()
+    }
+}
+
 impl CanExpire for UserInfo {
     fn is_expired(&self) -> bool {
         // Don't cache inactive token introspection results.
@@ -27,16 +27,37 @@ pub struct Cli {
     /// Whether to use TLS.
     #[arg(short, long)]
     pub no_tls: bool,
+
+    /// Re-encrypt stored sessions and sensitive bags with the current key, then exit.
+    #[arg(long)]
+    pub reencrypt: bool,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ConfigDB {
     pub uri: String,
+
+    /// The time zone timestamps in the database are stored in, e.g.
+    /// "America/Phoenix". Defaults to the database's TimeZone setting, or UTC
+    /// if that isn't an IANA time zone name.
+    #[serde(default)]
+    pub timezone: Option<String>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ConfigUsers {
     pub domain: String,
+
+    /// Whether to add users to the database the first time they make an
+    /// authenticated request, rather than waiting for another service to. The
+    /// user is added in the handler's transaction, so a request that fails
+    /// doesn't leave the user in place.
+    #[serde(default)]
+    pub provision: bool,
+
+    /// Whether to record when users first and last logged in.
+    #[serde(default)]
+    pub track_logins: bool,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -51,6 +72,83 @@ pub struct ConfigOauth {
     pub client_id: String,
     pub client_secret: String,
     pub entitlements: Option<ConfigEntitlements>,
+
+    /// The usernames of the service accounts that other services authenticate
+    /// as, e.g. "service-account-vice-proxy".
+    #[serde(default)]
+    pub service_accounts: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigSessions {
+    /// How often expired sessions are deleted, in seconds.
+    pub purge_interval_seconds: u64,
+}
+
+impl Default for ConfigSessions {
+    fn default() -> Self {
+        ConfigSessions {
+            purge_interval_seconds: 300,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigAnalyses {
+    /// How long analysis status events are kept for clients resuming an event
+    /// stream, in hours.
+    pub event_retention_hours: i32,
+
+    /// The most a single request can extend an interactive analysis's time
+    /// limit by, in hours.
+    pub max_extension_hours: i32,
+
+    /// The longest an interactive analysis can run for after it starts,
+    /// including extensions, in hours.
+    pub max_time_limit_hours: i32,
+}
+
+impl Default for ConfigAnalyses {
+    fn default() -> Self {
+        ConfigAnalyses {
+            event_retention_hours: 72,
+            max_extension_hours: 24,
+            max_time_limit_hours: 168,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigNats {
+    /// The NATS server URL, e.g. "tls://nats:4222".
+    pub uri: String,
+
+    /// Path to the NATS credentials file, if the server requires one.
+    pub creds_file: Option<String>,
+
+    /// The subject analysis cancellation requests are published on.
+    #[serde(default = "default_cancel_subject")]
+    pub cancel_subject: String,
+
+    /// The subject analysis submissions are published on.
+    #[serde(default = "default_submit_subject")]
+    pub submit_subject: String,
+}
+
+fn default_cancel_subject() -> String {
+    String::from("cyverse.discoenv.analyses.cancel")
+}
+
+fn default_submit_subject() -> String {
+    String::from("cyverse.discoenv.analyses.submit")
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ConfigEncryption {
+    /// Path to the YAML file containing the encryption keys.
+    pub key_file: String,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -58,4 +156,14 @@ pub struct Config {
     pub db: ConfigDB,
     pub users: ConfigUsers,
     pub oauth: Option<ConfigOauth>,
+
+    #[serde(default)]
+    pub sessions: ConfigSessions,
+
+    pub encryption: Option<ConfigEncryption>,
+
+    #[serde(default)]
+    pub analyses: ConfigAnalyses,
+
+    pub nats: Option<ConfigNats>,
 }
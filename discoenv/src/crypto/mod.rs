@@ -0,0 +1,346 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::errors::DiscoError;
+
+const NONCE_LEN: usize = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CryptoError {
+    #[error("error reading key file: {0}")]
+    KeyFile(String),
+
+    #[error("unknown key id: {0}")]
+    UnknownKey(String),
+
+    #[error("invalid key {0}: keys must be 32 bytes encoded as base64")]
+    InvalidKey(String),
+
+    #[error("encrypted data found but no keys are configured")]
+    NotConfigured,
+
+    #[error("encryption failed")]
+    Encrypt,
+
+    #[error("decryption failed")]
+    Decrypt,
+}
+
+impl From<CryptoError> for sqlx::Error {
+    fn from(e: CryptoError) -> Self {
+        sqlx::Error::Decode(Box::new(e))
+    }
+}
+
+impl From<CryptoError> for DiscoError {
+    fn from(e: CryptoError) -> Self {
+        DiscoError::Internal(e.to_string())
+    }
+}
+
+/// The layout of the key file. Keys are base64 encoded and indexed by key ID.
+/// Old keys should stay in the file until everything encrypted with them has
+/// been re-encrypted.
+#[derive(Deserialize)]
+struct KeyFile {
+    current: String,
+    keys: HashMap<String, String>,
+}
+
+/// The row an encrypted value is stored in. It's included in the associated
+/// data along with the key ID, so a ciphertext copied into another row, or into
+/// another user's row, fails to decrypt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Owner<'a> {
+    /// The user the row belongs to.
+    pub user_id: &'a Uuid,
+
+    /// The table the row is in.
+    pub table: &'a str,
+
+    /// The row's ID.
+    pub row_id: &'a Uuid,
+}
+
+impl Owner<'_> {
+    fn aad(&self, key_id: &str) -> Vec<u8> {
+        format!("{}:{}:{}:{}", key_id, self.table, self.row_id, self.user_id).into_bytes()
+    }
+}
+
+/// A value encrypted with one of the keys in a Keyring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encrypted {
+    /// The ID of the key used to encrypt the value.
+    pub key_id: String,
+
+    /// The nonce followed by the ciphertext, encoded as base64.
+    pub ciphertext: String,
+}
+
+/// The set of keys used to encrypt sensitive fields before they're stored in
+/// the database. New values are always encrypted with the current key.
+#[derive(Clone)]
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, Key<Aes256Gcm>>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the keys themselves.
+        let mut ids: Vec<&String> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("key_ids", &ids)
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub fn new(current: &str, encoded_keys: &HashMap<String, String>) -> Result<Self, CryptoError> {
+        let mut keys = HashMap::new();
+        for (id, encoded) in encoded_keys {
+            let raw = STANDARD
+                .decode(encoded.trim())
+                .map_err(|_| CryptoError::InvalidKey(id.clone()))?;
+            if raw.len() != 32 {
+                return Err(CryptoError::InvalidKey(id.clone()));
+            }
+            keys.insert(id.clone(), *Key::<Aes256Gcm>::from_slice(&raw));
+        }
+
+        if !keys.contains_key(current) {
+            return Err(CryptoError::UnknownKey(current.to_owned()));
+        }
+
+        Ok(Keyring {
+            current: current.to_owned(),
+            keys,
+        })
+    }
+
+    /// Loads the keys from a YAML file.
+    pub fn from_file(path: &str) -> Result<Self, CryptoError> {
+        let f = std::fs::File::open(path).map_err(|e| CryptoError::KeyFile(e.to_string()))?;
+        let kf: KeyFile =
+            serde_yaml::from_reader(f).map_err(|e| CryptoError::KeyFile(e.to_string()))?;
+        Keyring::new(&kf.current, &kf.keys)
+    }
+
+    /// The ID of the key new values are encrypted with.
+    pub fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<Aes256Gcm, CryptoError> {
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| CryptoError::UnknownKey(key_id.to_owned()))?;
+        Ok(Aes256Gcm::new(key))
+    }
+
+    /// Encrypts the plaintext with the current key. The key ID and the row the
+    /// value is stored in are used as associated data so a ciphertext can't be
+    /// passed off as belonging to a different key or row.
+    pub fn encrypt(&self, plaintext: &str, owner: Owner) -> Result<Encrypted, CryptoError> {
+        let cipher = self.cipher(&self.current)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ct = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: &owner.aad(&self.current),
+                },
+            )
+            .map_err(|_| CryptoError::Encrypt)?;
+
+        let mut out = nonce.to_vec();
+        out.extend(ct);
+
+        Ok(Encrypted {
+            key_id: self.current.clone(),
+            ciphertext: STANDARD.encode(out),
+        })
+    }
+
+    /// Decrypts a value that was encrypted with the key with the given ID for
+    /// the given row.
+    pub fn decrypt(
+        &self,
+        key_id: &str,
+        ciphertext: &str,
+        owner: Owner,
+    ) -> Result<String, CryptoError> {
+        let cipher = self.cipher(key_id)?;
+        let raw = STANDARD
+            .decode(ciphertext)
+            .map_err(|_| CryptoError::Decrypt)?;
+        if raw.len() < NONCE_LEN {
+            return Err(CryptoError::Decrypt);
+        }
+
+        let (nonce, ct) = raw.split_at(NONCE_LEN);
+        let pt = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ct,
+                    aad: &owner.aad(key_id),
+                },
+            )
+            .map_err(|_| CryptoError::Decrypt)?;
+
+        String::from_utf8(pt).map_err(|_| CryptoError::Decrypt)
+    }
+}
+
+/// Encrypts the plaintext if a keyring is configured. Returns the value to
+/// store along with the ID of the key used, which is None if the value was
+/// left as plaintext.
+pub fn seal(
+    keyring: Option<&Keyring>,
+    plaintext: &str,
+    owner: Owner,
+) -> Result<(String, Option<String>), CryptoError> {
+    match keyring {
+        Some(k) => {
+            let enc = k.encrypt(plaintext, owner)?;
+            Ok((enc.ciphertext, Some(enc.key_id)))
+        }
+        None => Ok((plaintext.to_owned(), None)),
+    }
+}
+
+/// The inverse of seal(). Values stored without a key ID are returned as-is.
+pub fn open(
+    keyring: Option<&Keyring>,
+    key_id: Option<&str>,
+    value: String,
+    owner: Owner,
+) -> Result<String, CryptoError> {
+    match (key_id, keyring) {
+        (None, _) => Ok(value),
+        (Some(_), None) => Err(CryptoError::NotConfigured),
+        (Some(id), Some(k)) => k.decrypt(id, &value, owner),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const USER_ID: Uuid = Uuid::from_u128(1);
+    const ROW_ID: Uuid = Uuid::from_u128(2);
+    const OTHER_ID: Uuid = Uuid::from_u128(3);
+
+    fn owner() -> Owner<'static> {
+        Owner {
+            user_id: &USER_ID,
+            table: "bags",
+            row_id: &ROW_ID,
+        }
+    }
+
+    fn keyring(current: &str) -> Keyring {
+        let mut keys = HashMap::new();
+        keys.insert("old".to_owned(), STANDARD.encode([1u8; 32]));
+        keys.insert("new".to_owned(), STANDARD.encode([2u8; 32]));
+        Keyring::new(current, &keys).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let k = keyring("new");
+        let enc = k.encrypt("{\"token\":\"secret\"}", owner()).unwrap();
+        assert_eq!(enc.key_id, "new");
+        assert!(!enc.ciphertext.contains("secret"));
+        assert_eq!(
+            k.decrypt(&enc.key_id, &enc.ciphertext, owner()).unwrap(),
+            "{\"token\":\"secret\"}"
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = keyring("old").encrypt("data", owner()).unwrap();
+        let k = keyring("new");
+
+        // Values encrypted with an older key are still readable.
+        assert_eq!(k.decrypt(&old.key_id, &old.ciphertext, owner()).unwrap(), "data");
+
+        // But claiming they belong to a different key fails.
+        assert!(k.decrypt("new", &old.ciphertext, owner()).is_err());
+    }
+
+    #[test]
+    fn test_bad_keys() {
+        let mut keys = HashMap::new();
+        keys.insert("short".to_owned(), STANDARD.encode([1u8; 16]));
+        assert!(Keyring::new("short", &keys).is_err());
+
+        keys.insert("good".to_owned(), STANDARD.encode([1u8; 32]));
+        keys.remove("short");
+        assert!(Keyring::new("missing", &keys).is_err());
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let k = keyring("new");
+
+        let (stored, key_id) = seal(None, "plain", owner()).unwrap();
+        assert_eq!((stored.as_str(), key_id), ("plain", None));
+        assert_eq!(open(Some(&k), None, stored, owner()).unwrap(), "plain");
+
+        let (stored, key_id) = seal(Some(&k), "secret", owner()).unwrap();
+        assert_eq!(key_id.as_deref(), Some("new"));
+        assert!(open(None, key_id.as_deref(), stored.clone(), owner()).is_err());
+        assert_eq!(open(Some(&k), key_id.as_deref(), stored, owner()).unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_tampering() {
+        let k = keyring("new");
+        let enc = k.encrypt("data", owner()).unwrap();
+        let mut raw = STANDARD.decode(&enc.ciphertext).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        assert!(k.decrypt("new", &STANDARD.encode(raw), owner()).is_err());
+    }
+
+    #[test]
+    fn test_owner_binding() {
+        let k = keyring("new");
+        let enc = k.encrypt("data", owner()).unwrap();
+
+        // Copying the ciphertext into another row fails.
+        let other_row = Owner {
+            row_id: &OTHER_ID,
+            ..owner()
+        };
+        assert!(k.decrypt("new", &enc.ciphertext, other_row).is_err());
+
+        // So does copying it into another user's row or another table.
+        let other_user = Owner {
+            user_id: &OTHER_ID,
+            ..owner()
+        };
+        assert!(k.decrypt("new", &enc.ciphertext, other_user).is_err());
+
+        let other_table = Owner {
+            table: "user_sessions",
+            ..owner()
+        };
+        assert!(k.decrypt("new", &enc.ciphertext, other_table).is_err());
+    }
+}
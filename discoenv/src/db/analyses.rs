@@ -1,129 +1,1562 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, FixedOffset, LocalResult, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
 use debuff::{
     analysis::{Analysis, AnalysisType},
     apps::{App, AppVersion, IntegrationData},
 };
-use sqlx::{query, types::chrono::NaiveDateTime};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    query, query_as,
+    types::{chrono::NaiveDateTime, JsonValue, Uuid},
+    PgPool, Postgres, QueryBuilder,
+};
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::mpsc;
+use utoipa::{IntoParams, ToSchema};
 
 use pbjson_types::Timestamp;
 
-fn convert_ts(from: NaiveDateTime) -> Timestamp {
-    let ts = from.timestamp_nanos();
-    Timestamp {
-        seconds: ts / 1_000_000_000,
-        nanos: (ts % 1_000_000_000) as i32,
+use super::annotations::Note;
+use crate::errors::DiscoError;
+
+/// Interprets a timestamp read from the database, which is stored without a
+/// time zone, as a time in the given zone. Ambiguous times from the end of
+/// daylight saving time resolve to the earlier instant. Times skipped at the
+/// start of daylight saving time use the offset in effect before the change.
+pub fn localize(from: NaiveDateTime, tz: &Tz) -> DateTime<Utc> {
+    match tz.from_local_datetime(&from) {
+        LocalResult::Single(dt) => dt.with_timezone(&Utc),
+        LocalResult::Ambiguous(earliest, _) => earliest.with_timezone(&Utc),
+        LocalResult::None => {
+            let offset: FixedOffset = tz.offset_from_utc_datetime(&from).fix();
+            offset
+                .from_local_datetime(&from)
+                .single()
+                .unwrap_or_else(|| Utc.from_utc_datetime(&from).into())
+                .with_timezone(&Utc)
+        }
+    }
+}
+
+/// Converts a time passed in by a client into the time zone the database stores
+/// timestamps in, so that it can be compared with them.
+pub fn delocalize(from: DateTime<Utc>, tz: &Tz) -> NaiveDateTime {
+    from.with_timezone(tz).naive_local()
+}
+
+/// Converts a database timestamp into a protobuf Timestamp. Missing
+/// timestamps stay missing so they're left out of responses.
+pub fn convert_ts(from: Option<NaiveDateTime>, tz: &Tz) -> Option<Timestamp> {
+    from.map(|f| {
+        let dt = localize(f, tz);
+        Timestamp {
+            seconds: dt.timestamp(),
+            nanos: dt.timestamp_subsec_nanos() as i32,
+        }
+    })
+}
+
+/// The default number of analyses returned in a page.
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+
+/// The largest number of analyses that can be requested in a page.
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Filters that can be applied when listing a user's analyses.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AnalysisFilter {
+    /// A comma-separated list of statuses to include, e.g. "Running,Submitted".
+    pub status: Option<String>,
+
+    /// Only include analyses of this app.
+    pub app_id: Option<Uuid>,
+
+    /// Only include analyses of this job type, e.g. "DE" or "Interactive".
+    pub job_type: Option<String>,
+
+    /// Whether to list deleted analyses instead of undeleted ones. Deleted analyses
+    /// are left out by default.
+    pub deleted: Option<bool>,
+
+    /// Only include analyses that started at or after this time, e.g.
+    /// "2023-01-15T09:00:00Z".
+    pub start_date_from: Option<DateTime<Utc>>,
+
+    /// Only include analyses that started before this time.
+    pub start_date_to: Option<DateTime<Utc>>,
+
+    /// Only include analyses that ended at or after this time.
+    pub end_date_from: Option<DateTime<Utc>>,
+
+    /// Only include analyses that ended before this time.
+    pub end_date_to: Option<DateTime<Utc>>,
+
+    /// Only include analyses with names containing this string. Case insensitive.
+    pub name: Option<String>,
+
+    /// A comma-separated list of tags. Only analyses with all of them are included.
+    pub tags: Option<String>,
+
+    /// Whether to leave out analyses launched as part of a batch analysis.
+    pub top_level: Option<bool>,
+
+    /// Only include analyses launched as part of this batch analysis.
+    #[serde(skip)]
+    pub parent_id: Option<Uuid>,
+}
+
+/// The fields analyses can be sorted by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalysisSortField {
+    #[default]
+    StartDate,
+    EndDate,
+    Name,
+    Status,
+    AppName,
+}
+
+impl AnalysisSortField {
+    /// The SQL expression to sort by and the type it has. Nulls are replaced
+    /// so that the keyset comparisons work.
+    fn sql(&self) -> (&'static str, &'static str) {
+        match self {
+            AnalysisSortField::StartDate => {
+                ("COALESCE(j.start_date, '-infinity'::timestamp)", "timestamp")
+            }
+            AnalysisSortField::EndDate => {
+                ("COALESCE(j.end_date, '-infinity'::timestamp)", "timestamp")
+            }
+            AnalysisSortField::Name => ("j.job_name", "text"),
+            AnalysisSortField::Status => ("j.status", "text"),
+            AnalysisSortField::AppName => ("COALESCE(a.name, '')", "text"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Sorting and pagination settings for a listing of analyses.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AnalysisPage {
+    /// The field to sort by. Defaults to start_date.
+    pub sort_field: Option<AnalysisSortField>,
+
+    /// The sort direction. Defaults to desc.
+    pub sort_order: Option<SortOrder>,
+
+    /// The maximum number of analyses to return.
+    pub limit: Option<i64>,
+
+    /// The next_cursor value from the previous page. It can only be used with the same
+    /// sort_field and sort_order as that page.
+    pub cursor: Option<String>,
+}
+
+impl AnalysisPage {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// The position of the last analysis on a page. Clients get it as an opaque
+/// string. The sort settings of the page are included since the position only
+/// makes sense with them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisCursor {
+    sort_field: AnalysisSortField,
+    sort_order: SortOrder,
+    sort_key: String,
+    id: Uuid,
+}
+
+impl AnalysisCursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// Returns None if the cursor wasn't produced by encode().
+    pub fn decode(s: &str) -> Option<Self> {
+        let raw = URL_SAFE_NO_PAD.decode(s).ok()?;
+        serde_json::from_slice(&raw).ok()
+    }
+
+    /// Whether the cursor came from a page with the same sort settings.
+    pub fn matches(&self, page: &AnalysisPage) -> bool {
+        self.sort_field == page.sort_field.unwrap_or_default()
+            && self.sort_order == page.sort_order.unwrap_or_default()
+    }
+}
+
+/// The number of analyses launched as part of a batch analysis, by status.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ChildCounts {
+    /// The total number of child analyses.
+    pub total: i64,
+
+    /// The number of child analyses with each status.
+    pub by_status: BTreeMap<String, i64>,
+}
+
+/// An analysis in a listing.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct ListedAnalysis {
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub analysis: Analysis,
+
+    /// The status counts of the analysis's children. Only present for batch analyses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub child_counts: Option<ChildCounts>,
+
+    /// The tags the user has put on the analysis.
+    pub tags: Vec<String>,
+}
+
+/// A page of analyses.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AnalysisListing {
+    /// The analyses on this page. Timestamps are RFC 3339 strings in UTC. An analysis's
+    /// start_date, end_date and planned_end_date, and its app version's integration_date and
+    /// edited_date, are left out when they aren't set.
+    pub analyses: Vec<ListedAnalysis>,
+
+    /// The number of analyses matching the filters across all pages.
+    pub total: i64,
+
+    /// Pass this as the cursor parameter to get the next page. Absent on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// A single step of an analysis. Most analyses only have one.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AnalysisStep {
+    /// The position of the step in the analysis, starting at 1.
+    pub step_number: i32,
+
+    /// The ID the execution system uses for the step.
+    pub external_id: Option<String>,
+
+    /// The status of the step.
+    pub status: String,
+
+    /// The name of the job type that ran the step.
+    pub job_type: String,
+
+    /// When the step started running. Absent if it hasn't started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_date: Option<DateTime<Utc>>,
+
+    /// When the step finished running. Absent if it hasn't finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_date: Option<DateTime<Utc>>,
+}
+
+/// An analysis along with its steps, the parameters it was submitted with,
+/// and the analyses launched under it and their status counts if it's a batch.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AnalysisDetail {
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub analysis: Analysis,
+
+    /// The steps of the analysis, in order.
+    pub steps: Vec<AnalysisStep>,
+
+    /// The submission the analysis was launched with, including its parameter values.
+    #[schema(value_type = Object)]
+    pub submission: Option<JsonValue>,
+
+    /// The child analyses of a batch analysis that haven't been deleted.
+    #[schema(value_type = Vec<Object>)]
+    pub children: Vec<Analysis>,
+
+    /// The status counts of the analysis's children. Only present for batch analyses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub child_counts: Option<ChildCounts>,
+
+    /// The tags the user has put on the analysis.
+    pub tags: Vec<String>,
+
+    /// The user's notes about the analysis, oldest first.
+    pub notes: Vec<Note>,
+}
+
+/// A row from the jobs table joined with the tables needed to build an
+/// Analysis.
+#[derive(Debug, sqlx::FromRow)]
+struct AnalysisRecord {
+    id: Uuid,
+    job_name: String,
+    job_description: Option<String>,
+    result_folder_path: Option<String>,
+    start_date: Option<NaiveDateTime>,
+    end_date: Option<NaiveDateTime>,
+    planned_end_date: Option<NaiveDateTime>,
+    status: String,
+    deleted: bool,
+    notify: bool,
+    subdomain: Option<String>,
+    parent_id: Option<Uuid>,
+    millicores_reserved: i32,
+    users_id: Uuid,
+    users_username: String,
+    apps_id: Uuid,
+    apps_name: Option<String>,
+    apps_description: Option<String>,
+    job_types_id: Uuid,
+    job_types_name: String,
+    job_types_system_id: String,
+    av_id: Uuid,
+    av_version: String,
+    av_version_order: i32,
+    av_deleted: bool,
+    av_disabled: bool,
+    av_integration_date: Option<NaiveDateTime>,
+    av_edited_date: Option<NaiveDateTime>,
+    integration_data_id: Uuid,
+    integrator_name: String,
+    integrator_email: String,
+
+    /// The value the row was sorted by, when the query sorts.
+    #[sqlx(default)]
+    sort_key: Option<String>,
+}
+
+impl AnalysisRecord {
+    /// Builds the Analysis, interpreting the record's timestamps in the given
+    /// time zone.
+    fn into_analysis(self, tz: &Tz) -> Analysis {
+        let mut analysis = Analysis::default();
+        let mut app = App::default();
+        let mut intd = IntegrationData::default();
+        let mut av = AppVersion::default();
+        let mut at = AnalysisType::default();
+        let mut user = debuff::user::User::default();
+
+        analysis.id = self.id.to_string();
+        analysis.name = self.job_name;
+        analysis.description = self.job_description.unwrap_or("".to_owned());
+        analysis.deleted = self.deleted;
+        analysis.notify = self.notify;
+        analysis.result_folder_path = self.result_folder_path.unwrap_or_default();
+        analysis.status = self.status;
+        analysis.subdomain = self.subdomain.unwrap_or_default();
+        analysis.parent_id = self.parent_id.map(|p| p.to_string()).unwrap_or_default();
+        analysis.millicores_reserved = self.millicores_reserved.into();
+        analysis.start_date = convert_ts(self.start_date, tz);
+        analysis.end_date = convert_ts(self.end_date, tz);
+        analysis.planned_end_date = convert_ts(self.planned_end_date, tz);
+
+        user.uuid = self.users_id.to_string();
+        user.username = self.users_username;
+        analysis.user = Some(user);
+
+        at.id = self.job_types_id.to_string();
+        at.name = self.job_types_name;
+        at.system_id = self.job_types_system_id;
+        analysis.kind = Some(at);
+
+        app.id = self.apps_id.to_string();
+        app.name = self.apps_name.unwrap_or("".to_owned());
+        app.description = self.apps_description.unwrap_or("".to_owned());
+        analysis.app = Some(app);
+
+        intd.id = self.integration_data_id.to_string();
+        intd.integrator_name = self.integrator_name;
+        intd.integrator_email = self.integrator_email;
+
+        av.id = self.av_id.to_string();
+        av.app_id = self.apps_id.to_string();
+        av.version = self.av_version;
+        av.version_order = self.av_version_order.into();
+        av.deleted = self.av_deleted;
+        av.disabled = self.av_disabled;
+        av.integration_date = convert_ts(self.av_integration_date, tz);
+        av.edited_date = convert_ts(self.av_edited_date, tz);
+        av.integration = Some(intd);
+        analysis.app_version = Some(av);
+
+        analysis
     }
 }
 
-/// Return a user's analyses.
+const ANALYSIS_COLUMNS: &str = r#"
+    SELECT
+        j.id,
+        j.job_name,
+        j.job_description,
+        j.result_folder_path,
+        j.start_date,
+        j.end_date,
+        j.planned_end_date,
+        j.status,
+        j.deleted,
+        j.notify,
+        j.subdomain,
+        j.parent_id,
+        j.millicores_reserved,
+        u.id                  as users_id,
+        u.username            as users_username,
+        a.id                  as apps_id,
+        a.name                as apps_name,
+        a.description         as apps_description,
+        a.wiki_url            as apps_wiki_url,
+        t.id                  as job_types_id,
+        t.name                as job_types_name,
+        t.system_id           as job_types_system_id,
+        av.id                 as av_id,
+        av.app_id             as av_app_id,
+        av.version            as av_version,
+        av.version_order      as av_version_order,
+        av.deleted            as av_deleted,
+        av.disabled           as av_disabled,
+        av.integration_date   as av_integration_date,
+        av.edited_date        as av_edited_date,
+        intd.id               as integration_data_id,
+        intd.integrator_name  as integrator_name,
+        intd.integrator_email as integrator_email
+"#;
+
+const ANALYSIS_JOINS: &str = r#"
+    FROM jobs j
+    JOIN users u ON u.id = j.user_id
+    JOIN job_types t ON j.job_type_id = t.id
+    JOIN app_versions av ON j.app_version_id = av.id
+    JOIN apps a ON av.app_id = a.id
+    JOIN integration_data intd on av.integration_data_id = intd.id
+"#;
+
+/// Escapes the LIKE wildcards in a user-supplied search string.
+pub(crate) fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Splits a comma-separated list of tags, leaving out blank and repeated ones.
+/// Repeated tags would otherwise never match, since the filter compares the
+/// number of distinct tags on an analysis with the length of the list.
+fn tag_list(tags: &str) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .split(',')
+        .map(|t| t.trim().to_owned())
+        .filter(|t| !t.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+impl AnalysisFilter {
+    /// Adds the WHERE clause for the user and the filters to the query. Times
+    /// are converted into the database's time zone before they're compared.
+    fn push_where(&self, qb: &mut QueryBuilder<'_, Postgres>, username: &str, tz: &Tz) {
+        qb.push(" WHERE u.username = ");
+        qb.push_bind(username.to_owned());
+
+        qb.push(" AND j.deleted = ");
+        qb.push_bind(self.deleted.unwrap_or(false));
+
+        if let Some(status) = &self.status {
+            let statuses: Vec<String> = status
+                .split(',')
+                .map(|s| s.trim().to_owned())
+                .filter(|s| !s.is_empty())
+                .collect();
+            if !statuses.is_empty() {
+                qb.push(" AND j.status = ANY(");
+                qb.push_bind(statuses);
+                qb.push(")");
+            }
+        }
+
+        if let Some(app_id) = self.app_id {
+            qb.push(" AND a.id = ");
+            qb.push_bind(app_id);
+        }
+
+        if let Some(job_type) = &self.job_type {
+            qb.push(" AND t.name = ");
+            qb.push_bind(job_type.clone());
+        }
+
+        if let Some(from) = self.start_date_from {
+            qb.push(" AND j.start_date >= ");
+            qb.push_bind(delocalize(from, tz));
+        }
+
+        if let Some(to) = self.start_date_to {
+            qb.push(" AND j.start_date < ");
+            qb.push_bind(delocalize(to, tz));
+        }
+
+        if let Some(from) = self.end_date_from {
+            qb.push(" AND j.end_date >= ");
+            qb.push_bind(delocalize(from, tz));
+        }
+
+        if let Some(to) = self.end_date_to {
+            qb.push(" AND j.end_date < ");
+            qb.push_bind(delocalize(to, tz));
+        }
+
+        if let Some(name) = &self.name {
+            qb.push(" AND j.job_name ILIKE ");
+            qb.push_bind(format!("%{}%", escape_like(name)));
+        }
+
+        if let Some(tags) = &self.tags {
+            let tags = tag_list(tags);
+            if !tags.is_empty() {
+                let count = tags.len() as i64;
+                qb.push(
+                    " AND j.id IN (SELECT jt.job_id FROM job_tags jt WHERE jt.user_id = u.id AND jt.tag = ANY(",
+                );
+                qb.push_bind(tags);
+                qb.push(") GROUP BY jt.job_id HAVING COUNT(DISTINCT jt.tag) = ");
+                qb.push_bind(count);
+                qb.push(")");
+            }
+        }
+
+        if self.top_level.unwrap_or(false) {
+            qb.push(" AND j.parent_id IS NULL");
+        }
+
+        if let Some(parent_id) = self.parent_id {
+            qb.push(" AND j.parent_id = ");
+            qb.push_bind(parent_id);
+        }
+    }
+}
+
+/// Return the number of a user's analyses that match the filter.
+pub async fn count_user_analyses<'a, E>(
+    conn: E,
+    username: &str,
+    filter: &AnalysisFilter,
+    tz: &Tz,
+) -> Result<i64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let mut qb = QueryBuilder::new("SELECT COUNT(*)");
+    qb.push(ANALYSIS_JOINS);
+    filter.push_where(&mut qb, username, tz);
+
+    let count: (i64,) = qb.build_query_as().fetch_one(conn).await?;
+    Ok(count.0)
+}
+
+/// Return a page of a user's analyses along with the cursor for the next page,
+/// if there is one.
 ///
 /// Note that that analyses are stored in the jobs table.
-pub async fn get_user_analyses<'a, E>(conn: E, username: &str) -> Result<Vec<Analysis>, sqlx::Error>
+pub async fn get_user_analyses<'a, E>(
+    conn: E,
+    username: &str,
+    filter: &AnalysisFilter,
+    page: &AnalysisPage,
+    cursor: Option<&AnalysisCursor>,
+    tz: &Tz,
+) -> Result<(Vec<Analysis>, Option<AnalysisCursor>), sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let (sort_expr, sort_type) = page.sort_field.unwrap_or_default().sql();
+    let order = page.sort_order.unwrap_or_default();
+    let limit = page.limit();
+
+    let mut qb = QueryBuilder::new(ANALYSIS_COLUMNS);
+    qb.push(format!(", ({sort_expr})::text AS sort_key"));
+    qb.push(ANALYSIS_JOINS);
+    filter.push_where(&mut qb, username, tz);
+
+    if let Some(c) = cursor {
+        let cmp = match order {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        qb.push(format!(" AND ({sort_expr}, j.id) {cmp} (CAST("));
+        qb.push_bind(c.sort_key.clone());
+        qb.push(format!(" AS {sort_type}), "));
+        qb.push_bind(c.id);
+        qb.push(")");
+    }
+
+    let dir = match order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    qb.push(format!(" ORDER BY {sort_expr} {dir}, j.id {dir} LIMIT "));
+    qb.push_bind(limit + 1);
+
+    let mut records: Vec<AnalysisRecord> = qb.build_query_as().fetch_all(conn).await?;
+
+    let next = if records.len() as i64 > limit {
+        records.truncate(limit as usize);
+        records.last().map(|r| AnalysisCursor {
+            sort_field: page.sort_field.unwrap_or_default(),
+            sort_order: order,
+            sort_key: r.sort_key.clone().unwrap_or_default(),
+            id: r.id,
+        })
+    } else {
+        None
+    };
+
+    Ok((records.into_iter().map(|r| r.into_analysis(tz)).collect(), next))
+}
+
+/// Sends each of the user's analyses that match the filter to the channel as
+/// it's read from the database, oldest first, so that the whole list never has
+/// to be held in memory. Errors are sent to the channel as well. Stops early
+/// if the receiver is dropped.
+pub async fn stream_user_analyses(
+    pool: &PgPool,
+    username: &str,
+    filter: &AnalysisFilter,
+    tz: &Tz,
+    sender: mpsc::Sender<Result<Analysis, sqlx::Error>>,
+) {
+    let mut qb = QueryBuilder::new(ANALYSIS_COLUMNS);
+    qb.push(ANALYSIS_JOINS);
+    filter.push_where(&mut qb, username, tz);
+    qb.push(" ORDER BY j.start_date, j.id");
+
+    let mut rows = qb.build_query_as::<AnalysisRecord>().fetch(pool);
+    loop {
+        let next = match rows.try_next().await {
+            Ok(Some(r)) => Ok(r.into_analysis(tz)),
+            Ok(None) => break,
+            Err(e) => Err(e),
+        };
+        let failed = next.is_err();
+
+        if sender.send(next).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// Returns whether the user owns the analysis.
+pub async fn analysis_exists<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+) -> Result<bool, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
-    let records = query!(
+    let result = query!(
         r#"
-            SELECT 
+            SELECT COUNT(*) > 0 AS analysis_exists
+            FROM jobs j
+            JOIN users u ON j.user_id = u.id
+            WHERE u.username = $1
+            AND j.id = $2
+        "#,
+        username,
+        analysis_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.analysis_exists.unwrap_or(false))
+}
+
+/// Returns a single analysis owned by the user.
+pub async fn get_user_analysis<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+    tz: &Tz,
+) -> Result<Analysis, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let mut qb = QueryBuilder::new(ANALYSIS_COLUMNS);
+    qb.push(ANALYSIS_JOINS);
+    qb.push(" WHERE u.username = ");
+    qb.push_bind(username.to_owned());
+    qb.push(" AND j.id = ");
+    qb.push_bind(*analysis_id);
+
+    let record: AnalysisRecord = qb.build_query_as().fetch_one(conn).await?;
+    Ok(record.into_analysis(tz))
+}
+
+/// Returns the analyses launched as part of a batch analysis owned by the user.
+/// Deleted children are left out, the same as in `count_children`.
+pub async fn get_child_analyses<'a, E>(
+    conn: E,
+    username: &str,
+    parent_id: &Uuid,
+    tz: &Tz,
+) -> Result<Vec<Analysis>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let mut qb = QueryBuilder::new(ANALYSIS_COLUMNS);
+    qb.push(ANALYSIS_JOINS);
+    qb.push(" WHERE u.username = ");
+    qb.push_bind(username.to_owned());
+    qb.push(" AND j.parent_id = ");
+    qb.push_bind(*parent_id);
+    qb.push(" AND j.deleted = false");
+    qb.push(" ORDER BY j.start_date, j.id");
+
+    let records: Vec<AnalysisRecord> = qb.build_query_as().fetch_all(conn).await?;
+    Ok(records.into_iter().map(|r| r.into_analysis(tz)).collect())
+}
+
+/// Returns the status counts of the children of each of the analyses. Analyses
+/// without children are left out, and deleted children aren't counted.
+pub async fn count_children<'a, E>(
+    conn: E,
+    parent_ids: &[Uuid],
+) -> Result<HashMap<Uuid, ChildCounts>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            SELECT
+                parent_id AS "parent_id!",
+                status,
+                COUNT(*) AS "count!"
+            FROM jobs
+            WHERE parent_id = ANY($1)
+            AND deleted = false
+            GROUP BY parent_id, status
+        "#,
+        parent_ids,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    let mut counts: HashMap<Uuid, ChildCounts> = HashMap::new();
+    for r in results {
+        let c = counts.entry(r.parent_id).or_default();
+        c.total += r.count;
+        c.by_status.insert(r.status, r.count);
+    }
+
+    Ok(counts)
+}
+
+/// Returns the steps of an analysis, in order.
+pub async fn get_analysis_steps<'a, E>(
+    conn: E,
+    analysis_id: &Uuid,
+    tz: &Tz,
+) -> Result<Vec<AnalysisStep>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let steps = query!(
+        r#"
+            SELECT
+                s.step_number,
+                s.external_id,
+                s.status,
+                t.name AS job_type,
+                s.start_date,
+                s.end_date
+            FROM job_steps s
+            JOIN job_types t ON s.job_type_id = t.id
+            WHERE s.job_id = $1
+            ORDER BY s.step_number
+        "#,
+        analysis_id,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(steps
+        .into_iter()
+        .map(|s| AnalysisStep {
+            step_number: s.step_number,
+            external_id: s.external_id,
+            status: s.status,
+            job_type: s.job_type,
+            start_date: s.start_date.map(|d| localize(d, tz)),
+            end_date: s.end_date.map(|d| localize(d, tz)),
+        })
+        .collect())
+}
+
+/// Returns the submission an analysis was launched with.
+pub async fn get_analysis_submission<'a, E>(
+    conn: E,
+    analysis_id: &Uuid,
+) -> Result<Option<JsonValue>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"SELECT submission::jsonb AS submission FROM jobs WHERE id = $1"#,
+        analysis_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.submission)
+}
+
+/// Who owns the interactive analysis at a VICE subdomain, and its state.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SubdomainAnalysis {
+    /// The UUID of the analysis.
+    pub analysis_id: Uuid,
+
+    /// The username of the user that launched the analysis.
+    pub owner: String,
+
+    /// The status of the analysis.
+    pub status: String,
+
+    /// When the analysis will be shut down. Absent if it isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub planned_end_date: Option<DateTime<Utc>>,
+}
+
+/// Returns the undeleted analysis using the subdomain. If more than one has
+/// used it, the most recently started one is returned.
+pub async fn get_analysis_by_subdomain<'a, E>(
+    conn: E,
+    subdomain: &str,
+    tz: &Tz,
+) -> Result<Option<SubdomainAnalysis>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            SELECT
                 j.id,
-                j.job_name,
-                j.job_description,
-                j.result_folder_path,
-                j.start_date,
-                j.end_date,
-                j.planned_end_date,
+                u.username,
                 j.status,
-                j.deleted,
-                j.notify,
-                j.subdomain,
-                j.parent_id,
-                j.millicores_reserved,
-                u.id                  as users_id,
-                u.username            as users_username,
-                a.id                  as apps_id,
-                a.name                as apps_name,
-                a.description         as apps_description,
-                a.wiki_url            as apps_wiki_url,
-                t.id                  as job_types_id,
-                t.name                as job_types_name,
-                t.system_id           as job_types_system_id,
-                av.id                 as av_id,
-                av.app_id             as av_app_id,
-                av.version            as av_version,
-                av.version_order      as av_version_order,
-                av.deleted            as av_deleted,
-                av.disabled           as av_disabled,
-                av.integration_date   as av_integration_date,
-                av.edited_date        as av_edited_date,
-                intd.id               as integration_data_id,
-                intd.integrator_name  as integrator_name,
-                intd.integrator_email as integrator_email
+                j.planned_end_date
+            FROM jobs j
+            JOIN users u ON j.user_id = u.id
+            WHERE j.subdomain = $1
+            AND NOT j.deleted
+            ORDER BY j.start_date DESC NULLS LAST
+            LIMIT 1
+        "#,
+        subdomain,
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(result.map(|r| SubdomainAnalysis {
+        analysis_id: r.id,
+        owner: r.username,
+        status: r.status,
+        planned_end_date: r.planned_end_date.map(|d| localize(d, tz)),
+    }))
+}
+
+/// The job type of interactive (VICE) analyses.
+pub const INTERACTIVE_JOB_TYPE: &str = "Interactive";
+
+/// The parts of an analysis that decide whether its time limit can be
+/// extended.
+#[derive(Debug)]
+pub struct TimeLimitState {
+    pub status: String,
+    pub job_type: String,
+    pub planned_end_date: Option<NaiveDateTime>,
+}
+
+/// Returns the analysis's status, job type and planned end date, locking the
+/// analysis until the transaction ends.
+pub async fn lock_time_limit_state<'a, E>(
+    conn: E,
+    analysis_id: &Uuid,
+) -> Result<TimeLimitState, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as!(
+        TimeLimitState,
+        r#"
+            SELECT
+                j.status,
+                t.name AS job_type,
+                j.planned_end_date
             FROM jobs j
-            JOIN users u ON u.id = j.user_id
             JOIN job_types t ON j.job_type_id = t.id
-            JOIN app_versions av ON j.app_version_id = av.id
-            JOIN apps a ON av.app_id = a.id
-            JOIN integration_data intd on av.integration_data_id = intd.id
-            WHERE u.username = $1
+            WHERE j.id = $1
+            FOR UPDATE OF j
+        "#,
+        analysis_id,
+    )
+    .fetch_one(conn)
+    .await
+}
+
+/// Pushes the analysis's planned end date out by `extension_hours`, counting
+/// from now if it has already passed. The new end date is capped at
+/// `max_time_limit_hours` after the analysis started, and is never moved
+/// earlier. Returns the new planned end date.
+pub async fn extend_time_limit<'a, E>(
+    conn: E,
+    analysis_id: &Uuid,
+    extension_hours: i32,
+    max_time_limit_hours: i32,
+) -> Result<Option<NaiveDateTime>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            UPDATE jobs
+            SET planned_end_date = GREATEST(
+                LEAST(
+                    GREATEST(COALESCE(planned_end_date, now()::timestamp), now()::timestamp)
+                        + make_interval(hours => $2),
+                    COALESCE(start_date, now()::timestamp) + make_interval(hours => $3)
+                ),
+                planned_end_date
+            )
+            WHERE id = $1
+            RETURNING planned_end_date
+        "#,
+        analysis_id,
+        extension_hours,
+        max_time_limit_hours,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.planned_end_date)
+}
+
+/// Records that the user extended the analysis's time limit.
+pub async fn record_time_limit_extension<'a, E>(
+    conn: E,
+    analysis_id: &Uuid,
+    username: &str,
+    previous_end_date: Option<NaiveDateTime>,
+    new_end_date: NaiveDateTime,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            INSERT INTO job_time_limit_extensions
+                (job_id, user_id, previous_end_date, new_end_date)
+            VALUES
+                ($1, (SELECT id FROM users WHERE username = $2), $3, $4)
+            RETURNING id
         "#,
-        username
+        analysis_id,
+        username,
+        previous_end_date,
+        new_end_date,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.id)
+}
+
+/// How often a user extended the time limits of their analyses.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TimeLimitExtensionCount {
+    pub username: String,
+
+    /// The number of extensions.
+    pub extensions: i64,
+
+    /// The number of analyses that were extended.
+    pub analyses: i64,
+
+    /// When the user last extended a time limit.
+    pub last_extended_date: DateTime<Utc>,
+}
+
+/// Returns the users that extended time limits the most during the window.
+pub async fn count_time_limit_extensions<'a, E>(
+    conn: E,
+    window: &UsageWindow,
+    limit: i64,
+    tz: &Tz,
+) -> Result<Vec<TimeLimitExtensionCount>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let (from, to) = window.bounds(tz);
+    let results = query!(
+        r#"
+            SELECT
+                u.username,
+                COUNT(*) AS "extensions!",
+                COUNT(DISTINCT e.job_id) AS "analyses!",
+                MAX(e.created_date) AS "last_extended_date!"
+            FROM job_time_limit_extensions e
+            JOIN users u ON e.user_id = u.id
+            WHERE e.created_date >= COALESCE(
+                $1,
+                CASE WHEN $4 THEN '-infinity'::timestamp
+                ELSE COALESCE($2, now()::timestamp) - interval '30 days' END
+            )
+            AND e.created_date < COALESCE($2, now()::timestamp)
+            GROUP BY u.username
+            ORDER BY 2 DESC, u.username
+            LIMIT $3
+        "#,
+        from,
+        to,
+        limit,
+        window.all_time,
     )
     .fetch_all(conn)
     .await?;
 
-    Ok(records
+    Ok(results
         .into_iter()
-        .map(|r| {
-            let mut analysis = Analysis::default();
-            let mut app = App::default();
-            let mut intd = IntegrationData::default();
-            let mut av = AppVersion::default();
-            let mut at = AnalysisType::default();
-            let mut user = debuff::user::User::default();
-
-            analysis.id = r.id.to_string();
-            analysis.name = r.job_name;
-            analysis.description = r.job_description.unwrap_or("".to_owned());
-            analysis.deleted = r.deleted;
-            analysis.notify = r.notify;
-            analysis.result_folder_path = r.result_folder_path.unwrap_or_default();
-            analysis.status = r.status;
-            analysis.subdomain = r.subdomain.unwrap_or_default();
-            analysis.parent_id = r.parent_id.unwrap_or_default().to_string();
-            analysis.millicores_reserved = r.millicores_reserved.into();
-            analysis.start_date = Some(convert_ts(r.start_date.unwrap_or_default()));
-            analysis.end_date = Some(convert_ts(r.end_date.unwrap_or_default()));
-            analysis.planned_end_date = Some(convert_ts(r.planned_end_date.unwrap_or_default()));
-
-            user.uuid = r.users_id.to_string();
-            user.username = r.users_username;
-            analysis.user = Some(user);
-
-            at.id = r.job_types_id.to_string();
-            at.name = r.job_types_name;
-            at.system_id = r.job_types_system_id;
-            analysis.kind = Some(at);
-
-            app.id = r.apps_id.to_string();
-            app.name = r.apps_name.unwrap_or("".to_owned());
-            app.description = r.apps_description.unwrap_or("".to_owned());
-            analysis.app = Some(app);
-
-            intd.id = r.integration_data_id.to_string();
-            intd.integrator_name = r.integrator_name;
-            intd.integrator_email = r.integrator_email;
-
-            av.id = r.av_id.to_string();
-            av.app_id = r.apps_id.to_string();
-            av.version = r.av_version;
-            av.version_order = r.av_version_order.into();
-            av.deleted = r.av_deleted;
-            av.disabled = r.av_disabled;
-            av.integration_date = Some(convert_ts(r.av_integration_date.unwrap_or_default()));
-            av.edited_date = Some(convert_ts(r.av_edited_date.unwrap_or_default()));
-            av.integration = Some(intd);
-            analysis.app_version = Some(av);
-
-            analysis
+        .map(|r| TimeLimitExtensionCount {
+            username: r.username,
+            extensions: r.extensions,
+            analyses: r.analyses,
+            last_extended_date: localize(r.last_extended_date, tz),
         })
         .collect())
 }
+
+/// The statuses of analyses that are finished or on their way to finishing.
+pub const FINISHED_STATUSES: [&str; 4] = ["Completed", "Failed", "Canceled", "Canceling"];
+
+/// Sets the analysis's status to Canceling, unless it has already finished or
+/// is being canceled. Returns the number of analyses that were updated.
+pub async fn mark_canceling<'a, E>(conn: E, analysis_id: &Uuid) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let finished: Vec<String> = FINISHED_STATUSES.iter().map(|s| s.to_string()).collect();
+    let result = query!(
+        r#"
+            UPDATE jobs
+            SET status = 'Canceling'
+            WHERE id = $1
+            AND status <> ALL($2)
+        "#,
+        analysis_id,
+        &finished,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Returns the IDs the execution system uses for the analysis's steps.
+pub async fn get_external_ids<'a, E>(
+    conn: E,
+    analysis_id: &Uuid,
+) -> Result<Vec<String>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            SELECT external_id AS "external_id!"
+            FROM job_steps
+            WHERE job_id = $1
+            AND external_id IS NOT NULL
+            ORDER BY step_number
+        "#,
+        analysis_id,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results.into_iter().map(|r| r.external_id).collect())
+}
+
+/// What's needed to launch an analysis again.
+#[derive(Debug)]
+pub struct RelaunchSource {
+    pub job_name: String,
+    pub app_version_id: Uuid,
+    pub submission: Option<JsonValue>,
+}
+
+/// Returns the name, app version and submission of an analysis.
+pub async fn get_relaunch_source<'a, E>(
+    conn: E,
+    analysis_id: &Uuid,
+) -> Result<RelaunchSource, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as!(
+        RelaunchSource,
+        r#"
+            SELECT
+                job_name,
+                app_version_id AS "app_version_id!",
+                submission::jsonb AS submission
+            FROM jobs
+            WHERE id = $1
+        "#,
+        analysis_id,
+    )
+    .fetch_one(conn)
+    .await
+}
+
+/// The channel that analysis status changes are sent on with NOTIFY.
+pub const STATUS_EVENTS_CHANNEL: &str = "job_status_events";
+
+/// A change in an analysis's status. Recorded in the job_status_events table
+/// by a trigger on the jobs table.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AnalysisStatusEvent {
+    /// The event's sequence number. Sent to SSE clients as the event ID.
+    pub id: i64,
+
+    /// The UUID of the analysis whose status changed.
+    #[serde(alias = "job_id")]
+    pub analysis_id: Uuid,
+
+    /// The UUID of the user that owns the analysis.
+    pub user_id: Uuid,
+
+    /// The new status.
+    pub status: String,
+
+    /// The status before the change. Absent for newly submitted analyses.
+    pub previous_status: Option<String>,
+
+    /// When the status changed.
+    pub created_date: DateTime<Utc>,
+}
+
+/// A status event as it's stored in the job_status_events table and sent with
+/// NOTIFY, with the time in the database's time zone.
+#[derive(Debug, Deserialize)]
+pub struct StatusEventRecord {
+    id: i64,
+    #[serde(alias = "job_id")]
+    analysis_id: Uuid,
+    user_id: Uuid,
+    status: String,
+    previous_status: Option<String>,
+    created_date: NaiveDateTime,
+}
+
+impl StatusEventRecord {
+    pub fn into_event(self, tz: &Tz) -> AnalysisStatusEvent {
+        AnalysisStatusEvent {
+            id: self.id,
+            analysis_id: self.analysis_id,
+            user_id: self.user_id,
+            status: self.status,
+            previous_status: self.previous_status,
+            created_date: localize(self.created_date, tz),
+        }
+    }
+}
+
+/// Returns up to `limit` of the user's status events that came after the event
+/// with the given ID, oldest first.
+pub async fn list_status_events_since<'a, E>(
+    conn: E,
+    user_id: &Uuid,
+    after_id: i64,
+    limit: i64,
+    tz: &Tz,
+) -> Result<Vec<AnalysisStatusEvent>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query_as!(
+        StatusEventRecord,
+        r#"
+            SELECT
+                id,
+                job_id AS analysis_id,
+                user_id,
+                status,
+                previous_status,
+                created_date
+            FROM job_status_events
+            WHERE user_id = $1
+            AND id > $2
+            ORDER BY id
+            LIMIT $3
+        "#,
+        user_id,
+        after_id,
+        limit,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results.into_iter().map(|r| r.into_event(tz)).collect())
+}
+
+/// Deletes status events recorded more than `retention_hours` ago.
+pub async fn purge_status_events<'a, E>(conn: E, retention_hours: i32) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM job_status_events
+            WHERE created_date < now() - make_interval(hours => $1)
+        "#,
+        retention_hours,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// The number of top consumers included in an admin usage summary by default.
+pub const DEFAULT_TOP_USERS: i64 = 10;
+
+/// The time window a usage summary covers. The window ends now and starts 30
+/// days before its end unless other times are passed in. Times are RFC 3339
+/// timestamps, e.g. "2023-01-15T09:00:00Z".
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageWindow {
+    /// The start of the window. Defaults to 30 days before the end of the window.
+    pub from: Option<DateTime<Utc>>,
+
+    /// The end of the window. Defaults to now.
+    pub to: Option<DateTime<Utc>>,
+
+    /// Whether a window without a start goes all the way back instead of 30
+    /// days. Can't be set by clients.
+    #[serde(skip)]
+    pub all_time: bool,
+}
+
+/// How many of the top users to list in an admin report.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TopUsersParams {
+    /// The number of users to list, from 1 to 100. Defaults to 10.
+    pub top: Option<i64>,
+}
+
+impl TopUsersParams {
+    pub fn top(&self) -> i64 {
+        self.top.unwrap_or(DEFAULT_TOP_USERS).clamp(1, 100)
+    }
+}
+
+impl UsageWindow {
+    /// A window covering everything up to now.
+    pub fn all_time() -> Self {
+        UsageWindow {
+            all_time: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the start and end of the window in the database's time zone.
+    pub fn bounds(&self, tz: &Tz) -> (Option<NaiveDateTime>, Option<NaiveDateTime>) {
+        (
+            self.from.map(|f| delocalize(f, tz)),
+            self.to.map(|t| delocalize(t, tz)),
+        )
+    }
+
+    pub fn validate(&self) -> Result<(), DiscoError> {
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from >= to {
+                return Err(DiscoError::ParameterInvalid(
+                    "from must be earlier than to".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the analyses in a usage summary are grouped by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsageGroup {
+    Status,
+    App,
+    JobType,
+    User,
+}
+
+impl UsageGroup {
+    /// The SQL expressions for the group's key and display name.
+    fn sql(&self) -> (&'static str, &'static str) {
+        match self {
+            UsageGroup::Status => ("status", "NULL::text"),
+            UsageGroup::App => ("app_id", "app_name"),
+            UsageGroup::JobType => ("job_type", "NULL::text"),
+            UsageGroup::User => ("username", "NULL::text"),
+        }
+    }
+}
+
+/// The analyses and resources used by one group of analyses in a summary.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct UsageBucket {
+    /// The value the analyses were grouped by: a status, app ID, job type or
+    /// username.
+    pub key: String,
+
+    /// The app's name, when grouped by app.
+    pub name: Option<String>,
+
+    /// The number of analyses in the group.
+    pub analyses: i64,
+
+    /// The CPU-core-hours reserved by the analyses during the window.
+    pub core_hours: f64,
+}
+
+/// A summary of the analyses that ran during a window of time and the CPU
+/// time reserved for them.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AnalysisSummary {
+    /// The start of the window, if one was requested.
+    pub from: Option<DateTime<Utc>>,
+
+    /// The end of the window, if one was requested.
+    pub to: Option<DateTime<Utc>>,
+
+    /// The number of analyses that ran during the window.
+    pub total_analyses: i64,
+
+    /// The CPU-core-hours reserved during the window.
+    pub total_core_hours: f64,
+
+    /// The analyses by status. Analyses that haven't started yet aren't included, so there
+    /// are no buckets for statuses like Submitted or Queued.
+    pub by_status: Vec<UsageBucket>,
+
+    pub by_app: Vec<UsageBucket>,
+    pub by_job_type: Vec<UsageBucket>,
+}
+
+/// A usage summary across all users.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AdminAnalysisSummary {
+    #[serde(flatten)]
+    pub summary: AnalysisSummary,
+
+    /// The users that reserved the most CPU time during the window.
+    pub top_users: Vec<UsageBucket>,
+}
+
+/// Adds a CTE named usage to the query, containing a row for each analysis
+/// that was running at some point during the window. Core-hours only count
+/// the part of each run inside the window; analyses that are still running
+/// count up to now. Deleted analyses are included since they still used
+/// resources. Analyses without a start date haven't run yet and are left out.
+fn push_usage_cte(
+    qb: &mut QueryBuilder<'_, Postgres>,
+    username: Option<&str>,
+    window: &UsageWindow,
+    tz: &Tz,
+) {
+    let (from, to) = window.bounds(tz);
+    qb.push("WITH upper_bound AS (SELECT COALESCE(");
+    qb.push_bind(to);
+    qb.push("::timestamp, now()::timestamp) AS upper), bounds AS (SELECT upper, COALESCE(");
+    qb.push_bind(from);
+    qb.push("::timestamp, CASE WHEN ");
+    qb.push_bind(window.all_time);
+    qb.push(
+        r#" THEN '-infinity'::timestamp ELSE upper - interval '30 days' END) AS lower
+        FROM upper_bound),
+        usage AS (
+            SELECT
+                j.status,
+                t.name AS job_type,
+                a.id::text AS app_id,
+                a.name AS app_name,
+                u.username,
+                GREATEST(
+                    EXTRACT(EPOCH FROM
+                        LEAST(COALESCE(j.end_date, now()::timestamp), b.upper)
+                        - GREATEST(j.start_date, b.lower)
+                    )::float8,
+                    0
+                ) / 3600.0 * j.millicores_reserved / 1000.0 AS core_hours
+            FROM jobs j
+            JOIN users u ON u.id = j.user_id
+            JOIN job_types t ON j.job_type_id = t.id
+            JOIN app_versions av ON j.app_version_id = av.id
+            JOIN apps a ON av.app_id = a.id
+            CROSS JOIN bounds b
+            WHERE j.start_date < b.upper
+            AND COALESCE(j.end_date, now()::timestamp) >= b.lower"#,
+    );
+
+    if let Some(username) = username {
+        qb.push(" AND u.username = ");
+        qb.push_bind(username.to_owned());
+    }
+
+    qb.push(")");
+}
+
+/// Returns the number of analyses and core-hours used during the window,
+/// grouped as requested and ordered by core-hours. Only the given user's
+/// analyses are included if a username is passed in.
+pub async fn summarize_usage<'a, E>(
+    conn: E,
+    username: Option<&str>,
+    window: &UsageWindow,
+    group: UsageGroup,
+    limit: Option<i64>,
+    tz: &Tz,
+) -> Result<Vec<UsageBucket>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let (key, name) = group.sql();
+
+    let mut qb = QueryBuilder::new("");
+    push_usage_cte(&mut qb, username, window, tz);
+    qb.push(format!(
+        r#"
+            SELECT
+                {key} AS key,
+                {name} AS name,
+                COUNT(*) AS analyses,
+                COALESCE(SUM(core_hours), 0)::float8 AS core_hours
+            FROM usage
+            GROUP BY 1, 2
+            ORDER BY core_hours DESC, analyses DESC, key
+        "#
+    ));
+
+    if let Some(limit) = limit {
+        qb.push(" LIMIT ");
+        qb.push_bind(limit);
+    }
+
+    qb.build_query_as().fetch_all(conn).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn naive(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&naive(y, m, d, h, min))
+    }
+
+    #[test]
+    fn test_tag_list() {
+        assert_eq!(tag_list("b, a,,a , b"), vec!["a", "b"]);
+        assert_eq!(tag_list("A,a"), vec!["A", "a"]);
+        assert!(tag_list(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_cursor_sort_settings() {
+        let cursor = AnalysisCursor {
+            sort_field: AnalysisSortField::Name,
+            sort_order: SortOrder::Asc,
+            sort_key: "analysis".to_owned(),
+            id: Uuid::nil(),
+        };
+        let decoded = AnalysisCursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded, cursor);
+
+        let mut page = AnalysisPage {
+            sort_field: Some(AnalysisSortField::Name),
+            sort_order: Some(SortOrder::Asc),
+            ..Default::default()
+        };
+        assert!(decoded.matches(&page));
+
+        page.sort_order = None;
+        assert!(!decoded.matches(&page));
+
+        page.sort_order = Some(SortOrder::Asc);
+        page.sort_field = None;
+        assert!(!decoded.matches(&page));
+    }
+
+    #[test]
+    fn test_convert_ts_missing() {
+        assert_eq!(convert_ts(None, &Tz::UTC), None);
+    }
+
+    #[test]
+    fn test_convert_ts() {
+        let from = NaiveDate::from_ymd_opt(2023, 5, 1)
+            .unwrap()
+            .and_hms_nano_opt(12, 0, 0, 250_000_000)
+            .unwrap();
+
+        let ts = convert_ts(Some(from), &Tz::UTC).unwrap();
+        assert_eq!(ts.seconds, 1_682_942_400);
+        assert_eq!(ts.nanos, 250_000_000);
+
+        // Arizona doesn't observe daylight saving time.
+        let ts = convert_ts(Some(from), &Tz::America__Phoenix).unwrap();
+        assert_eq!(ts.seconds, 1_682_942_400 + 7 * 3600);
+        assert_eq!(ts.nanos, 250_000_000);
+    }
+
+    #[test]
+    fn test_convert_ts_before_epoch() {
+        let ts = convert_ts(Some(naive(1969, 12, 31, 23, 59)), &Tz::UTC).unwrap();
+        assert_eq!(ts.seconds, -60);
+        assert_eq!(ts.nanos, 0);
+    }
+
+    #[test]
+    fn test_localize_daylight_saving() {
+        let tz = Tz::America__New_York;
+
+        assert_eq!(localize(naive(2023, 1, 15, 9, 0), &tz), utc(2023, 1, 15, 14, 0));
+        assert_eq!(localize(naive(2023, 7, 15, 9, 0), &tz), utc(2023, 7, 15, 13, 0));
+
+        // 1:30 happens twice when the clocks go back; the first one wins.
+        assert_eq!(localize(naive(2022, 11, 6, 1, 30), &tz), utc(2022, 11, 6, 5, 30));
+
+        // 2:30 never happens when the clocks go forward.
+        assert_eq!(localize(naive(2022, 3, 13, 2, 30), &tz), utc(2022, 3, 13, 7, 30));
+    }
+
+    #[test]
+    fn test_delocalize() {
+        let tz = Tz::America__New_York;
+
+        assert_eq!(delocalize(utc(2023, 1, 15, 14, 0), &tz), naive(2023, 1, 15, 9, 0));
+        assert_eq!(delocalize(utc(2023, 7, 15, 13, 0), &tz), naive(2023, 7, 15, 9, 0));
+        assert_eq!(delocalize(utc(2023, 7, 15, 13, 0), &Tz::UTC), naive(2023, 7, 15, 13, 0));
+    }
+}
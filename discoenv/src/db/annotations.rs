@@ -0,0 +1,302 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    query, query_as,
+    types::{chrono::NaiveDateTime, Uuid},
+};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+use super::analyses::{escape_like, localize};
+
+/// One of the tags a user has used, along with how many analyses it's on.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct TagCount {
+    pub tag: String,
+
+    /// The number of the user's analyses with the tag.
+    pub analyses: i64,
+}
+
+/// A user's note about one of their analyses.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct Note {
+    /// The note's UUID.
+    pub id: Uuid,
+
+    /// The UUID of the analysis the note is about.
+    pub analysis_id: Uuid,
+
+    /// The text of the note.
+    pub note: String,
+
+    /// When the note was added.
+    pub created_date: DateTime<Utc>,
+
+    /// When the note was last changed.
+    pub modified_date: DateTime<Utc>,
+}
+
+struct NoteRecord {
+    id: Uuid,
+    job_id: Uuid,
+    note: String,
+    created_date: NaiveDateTime,
+    modified_date: NaiveDateTime,
+}
+
+impl NoteRecord {
+    fn into_note(self, tz: &Tz) -> Note {
+        Note {
+            id: self.id,
+            analysis_id: self.job_id,
+            note: self.note,
+            created_date: localize(self.created_date, tz),
+            modified_date: localize(self.modified_date, tz),
+        }
+    }
+}
+
+/// Returns the tags the user has used that start with the prefix, most used
+/// first. Tags are case sensitive, so the prefix is too.
+pub async fn list_user_tags<'a, E>(
+    conn: E,
+    username: &str,
+    prefix: &str,
+    limit: i64,
+) -> Result<Vec<TagCount>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let pattern = format!("{}%", escape_like(prefix));
+
+    let results = query!(
+        r#"
+            SELECT
+                t.tag,
+                COUNT(*) AS "analyses!"
+            FROM job_tags t
+            JOIN users u ON t.user_id = u.id
+            WHERE u.username = $1
+            AND t.tag LIKE $2
+            GROUP BY t.tag
+            ORDER BY 2 DESC, t.tag
+            LIMIT $3
+        "#,
+        username,
+        pattern,
+        limit,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results
+        .into_iter()
+        .map(|r| TagCount {
+            tag: r.tag,
+            analyses: r.analyses,
+        })
+        .collect())
+}
+
+/// Returns the tags the user has put on each of the analyses. Analyses without
+/// tags are left out.
+pub async fn get_analyses_tags<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_ids: &[Uuid],
+) -> Result<HashMap<Uuid, Vec<String>>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            SELECT t.job_id, t.tag
+            FROM job_tags t
+            JOIN users u ON t.user_id = u.id
+            WHERE u.username = $1
+            AND t.job_id = ANY($2)
+            ORDER BY t.tag
+        "#,
+        username,
+        analysis_ids,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    let mut tags: HashMap<Uuid, Vec<String>> = HashMap::new();
+    for r in results {
+        tags.entry(r.job_id).or_default().push(r.tag);
+    }
+
+    Ok(tags)
+}
+
+/// Tags the analysis. Adding a tag that's already there does nothing.
+pub async fn add_analysis_tag<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+    tag: &str,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query!(
+        r#"
+            INSERT INTO job_tags (user_id, job_id, tag)
+            VALUES ((SELECT id FROM users WHERE username = $1), $2, $3)
+            ON CONFLICT (user_id, job_id, tag) DO NOTHING
+        "#,
+        username,
+        analysis_id,
+        tag,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_analysis_tag<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+    tag: &str,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM job_tags
+            WHERE user_id = (SELECT id FROM users WHERE username = $1)
+            AND job_id = $2
+            AND tag = $3
+        "#,
+        username,
+        analysis_id,
+        tag,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Returns the user's notes about the analysis, oldest first.
+pub async fn list_notes<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+    tz: &Tz,
+) -> Result<Vec<Note>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let records = query_as!(
+        NoteRecord,
+        r#"
+            SELECT n.id, n.job_id, n.note, n.created_date, n.modified_date
+            FROM job_notes n
+            JOIN users u ON n.user_id = u.id
+            WHERE u.username = $1
+            AND n.job_id = $2
+            ORDER BY n.created_date, n.id
+        "#,
+        username,
+        analysis_id,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(records.into_iter().map(|r| r.into_note(tz)).collect())
+}
+
+pub async fn add_note<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+    note: &str,
+    tz: &Tz,
+) -> Result<Note, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let record = query_as!(
+        NoteRecord,
+        r#"
+            INSERT INTO job_notes (user_id, job_id, note)
+            VALUES ((SELECT id FROM users WHERE username = $1), $2, $3)
+            RETURNING id, job_id, note, created_date, modified_date
+        "#,
+        username,
+        analysis_id,
+        note,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(record.into_note(tz))
+}
+
+/// Replaces the text of one of the user's notes about the analysis. Returns
+/// None if there's no such note.
+pub async fn update_note<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+    note_id: &Uuid,
+    note: &str,
+    tz: &Tz,
+) -> Result<Option<Note>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let record = query_as!(
+        NoteRecord,
+        r#"
+            UPDATE job_notes
+            SET note = $4, modified_date = now()
+            WHERE user_id = (SELECT id FROM users WHERE username = $1)
+            AND job_id = $2
+            AND id = $3
+            RETURNING id, job_id, note, created_date, modified_date
+        "#,
+        username,
+        analysis_id,
+        note_id,
+        note,
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|r| r.into_note(tz)))
+}
+
+pub async fn delete_note<'a, E>(
+    conn: E,
+    username: &str,
+    analysis_id: &Uuid,
+    note_id: &Uuid,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM job_notes
+            WHERE user_id = (SELECT id FROM users WHERE username = $1)
+            AND job_id = $2
+            AND id = $3
+        "#,
+        username,
+        analysis_id,
+        note_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
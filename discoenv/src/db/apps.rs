@@ -0,0 +1,511 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use debuff::apps::{App, AppVersion, IntegrationData};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    query, query_as,
+    types::{chrono::NaiveDateTime, Uuid},
+    Postgres, QueryBuilder,
+};
+use utoipa::{IntoParams, ToSchema};
+
+use super::analyses::{convert_ts, escape_like, localize};
+
+/// The number of apps returned in a page if no limit is requested.
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+
+/// The largest number of apps that can be requested in a page.
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Filters and paging for the app listing. An app counts as deleted when all of
+/// its versions are deleted, and as disabled when all of them are disabled.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AppFilter {
+    /// Only include apps with names or descriptions containing this string. Case insensitive.
+    pub search: Option<String>,
+
+    /// Only include apps with a version added by this integrator, given as a username or an
+    /// email address.
+    pub integrator: Option<String>,
+
+    /// Whether to list deleted apps instead of undeleted ones. Deleted apps are left out by
+    /// default.
+    pub deleted: Option<bool>,
+
+    /// Only include disabled apps if true, or apps that aren't disabled if false.
+    pub disabled: Option<bool>,
+
+    /// The maximum number of apps to return.
+    pub limit: Option<i64>,
+
+    /// The number of apps to skip.
+    pub offset: Option<i64>,
+
+    /// The integrator's username after it's been normalized. Set by the handler.
+    #[serde(skip)]
+    pub integrator_username: Option<String>,
+}
+
+impl AppFilter {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// Adds the FROM and WHERE clauses for the filters to the query.
+    fn push_from_where(&self, qb: &mut QueryBuilder<'_, Postgres>) {
+        qb.push(
+            r#"
+            FROM apps a
+            JOIN (
+                SELECT
+                    app_id,
+                    bool_and(deleted) AS deleted,
+                    bool_and(disabled) AS disabled
+                FROM app_versions
+                GROUP BY app_id
+            ) s ON s.app_id = a.id
+            WHERE s.deleted = "#,
+        );
+        qb.push_bind(self.deleted.unwrap_or(false));
+
+        if let Some(disabled) = self.disabled {
+            qb.push(" AND s.disabled = ");
+            qb.push_bind(disabled);
+        }
+
+        if let Some(search) = &self.search {
+            let pattern = format!("%{}%", escape_like(search.trim()));
+            qb.push(" AND (a.name ILIKE ");
+            qb.push_bind(pattern.clone());
+            qb.push(" OR a.description ILIKE ");
+            qb.push_bind(pattern);
+            qb.push(")");
+        }
+
+        if let Some(integrator) = &self.integrator {
+            qb.push(
+                r#" AND EXISTS (
+                    SELECT 1
+                    FROM app_versions v
+                    JOIN integration_data i ON v.integration_data_id = i.id
+                    LEFT JOIN users u ON i.user_id = u.id
+                    WHERE v.app_id = a.id
+                    AND (i.integrator_email = "#,
+            );
+            qb.push_bind(integrator.clone());
+            qb.push(" OR u.username = ");
+            qb.push_bind(
+                self.integrator_username
+                    .clone()
+                    .unwrap_or_else(|| integrator.clone()),
+            );
+            qb.push("))");
+        }
+    }
+}
+
+/// A page of apps.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AppListing {
+    #[schema(value_type = Vec<Object>)]
+    pub apps: Vec<App>,
+
+    /// The total number of apps matching the filters.
+    pub total: i64,
+}
+
+/// An app along with all of its versions.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AppDetail {
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub app: App,
+
+    /// The app's versions, ordered by version_order. Timestamps are RFC 3339 strings in UTC.
+    #[schema(value_type = Vec<Object>)]
+    pub versions: Vec<AppVersion>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AppRecord {
+    id: Uuid,
+    name: Option<String>,
+    description: Option<String>,
+}
+
+impl AppRecord {
+    fn into_app(self) -> App {
+        App {
+            id: self.id.to_string(),
+            name: self.name.unwrap_or_default(),
+            description: self.description.unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+struct AppVersionRecord {
+    id: Uuid,
+    app_id: Uuid,
+    version: String,
+    version_order: i32,
+    deleted: bool,
+    disabled: bool,
+    integration_date: Option<NaiveDateTime>,
+    edited_date: Option<NaiveDateTime>,
+    integration_data_id: Uuid,
+    integrator_name: String,
+    integrator_email: String,
+}
+
+impl AppVersionRecord {
+    fn into_app_version(self, tz: &Tz) -> AppVersion {
+        let mut av = AppVersion::default();
+        let intd = IntegrationData {
+            id: self.integration_data_id.to_string(),
+            integrator_name: self.integrator_name,
+            integrator_email: self.integrator_email,
+            user: None,
+        };
+
+        av.id = self.id.to_string();
+        av.app_id = self.app_id.to_string();
+        av.version = self.version;
+        av.version_order = self.version_order.into();
+        av.deleted = self.deleted;
+        av.disabled = self.disabled;
+        av.integration_date = convert_ts(self.integration_date, tz);
+        av.edited_date = convert_ts(self.edited_date, tz);
+        av.integration = Some(intd);
+
+        av
+    }
+}
+
+pub async fn count_apps<'a, E>(conn: E, filter: &AppFilter) -> Result<i64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let mut qb = QueryBuilder::new("SELECT COUNT(*)");
+    filter.push_from_where(&mut qb);
+
+    let count: (i64,) = qb.build_query_as().fetch_one(conn).await?;
+    Ok(count.0)
+}
+
+/// Returns a page of the apps matching the filter, sorted by name.
+pub async fn list_apps<'a, E>(conn: E, filter: &AppFilter) -> Result<Vec<App>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let mut qb = QueryBuilder::new("SELECT a.id, a.name, a.description");
+    filter.push_from_where(&mut qb);
+    qb.push(" ORDER BY a.name, a.id LIMIT ");
+    qb.push_bind(filter.limit());
+    qb.push(" OFFSET ");
+    qb.push_bind(filter.offset());
+
+    let records: Vec<AppRecord> = qb.build_query_as().fetch_all(conn).await?;
+
+    Ok(records.into_iter().map(|r| r.into_app()).collect())
+}
+
+/// Returns the app, or None if it doesn't exist.
+pub async fn get_app<'a, E>(conn: E, app_id: &Uuid) -> Result<Option<App>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let record = query_as!(
+        AppRecord,
+        r#"
+            SELECT id, name, description
+            FROM apps
+            WHERE id = $1
+        "#,
+        app_id,
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|r| r.into_app()))
+}
+
+/// Returns all of the app's versions, including deleted and disabled ones,
+/// ordered by version_order.
+pub async fn get_app_versions<'a, E>(
+    conn: E,
+    app_id: &Uuid,
+    tz: &Tz,
+) -> Result<Vec<AppVersion>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let records = query_as!(
+        AppVersionRecord,
+        r#"
+            SELECT
+                av.id,
+                av.app_id,
+                av.version,
+                av.version_order,
+                av.deleted,
+                av.disabled,
+                av.integration_date,
+                av.edited_date,
+                i.id AS integration_data_id,
+                i.integrator_name,
+                i.integrator_email
+            FROM app_versions av
+            JOIN integration_data i ON av.integration_data_id = i.id
+            WHERE av.app_id = $1
+            ORDER BY av.version_order, av.id
+        "#,
+        app_id,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(records.into_iter().map(|r| r.into_app_version(tz)).collect())
+}
+
+/// One of the user's favorite apps. Favorites aren't removed when the app is
+/// deleted or disabled, so those flags are included.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FavoriteApp {
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub app: App,
+
+    /// Whether all of the app's versions are deleted.
+    pub deleted: bool,
+
+    /// Whether all of the app's versions are disabled.
+    pub disabled: bool,
+
+    /// When the user added the app to their favorites.
+    pub favorited_date: DateTime<Utc>,
+}
+
+/// A user's favorite apps.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct FavoriteApps {
+    pub apps: Vec<FavoriteApp>,
+}
+
+/// An app the user has run recently.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RecentApp {
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub app: App,
+
+    /// Whether all of the app's versions are deleted.
+    pub deleted: bool,
+
+    /// Whether all of the app's versions are disabled.
+    pub disabled: bool,
+
+    /// When the user's most recent analysis of the app started.
+    pub last_start_date: DateTime<Utc>,
+
+    /// The number of analyses of the app the user has run.
+    pub analyses: i64,
+}
+
+/// The apps a user has run most recently.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct RecentApps {
+    pub apps: Vec<RecentApp>,
+}
+
+pub async fn app_exists<'a, E>(conn: E, app_id: &Uuid) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            SELECT COUNT(*) > 0 AS app_exists
+            FROM apps
+            WHERE id = $1
+        "#,
+        app_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.app_exists.unwrap_or(false))
+}
+
+/// Returns the user's favorite apps, most recently added first.
+pub async fn list_favorite_apps<'a, E>(
+    conn: E,
+    username: &str,
+    tz: &Tz,
+) -> Result<Vec<FavoriteApp>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            SELECT
+                a.id,
+                a.name,
+                a.description,
+                COALESCE(s.deleted, true) AS "deleted!",
+                COALESCE(s.disabled, true) AS "disabled!",
+                f.created_date
+            FROM app_favorites f
+            JOIN users u ON f.user_id = u.id
+            JOIN apps a ON f.app_id = a.id
+            LEFT JOIN (
+                SELECT
+                    app_id,
+                    bool_and(deleted) AS deleted,
+                    bool_and(disabled) AS disabled
+                FROM app_versions
+                GROUP BY app_id
+            ) s ON s.app_id = a.id
+            WHERE u.username = $1
+            ORDER BY f.created_date DESC, a.name
+        "#,
+        username,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results
+        .into_iter()
+        .map(|r| FavoriteApp {
+            app: AppRecord {
+                id: r.id,
+                name: r.name,
+                description: r.description,
+            }
+            .into_app(),
+            deleted: r.deleted,
+            disabled: r.disabled,
+            favorited_date: localize(r.created_date, tz),
+        })
+        .collect())
+}
+
+/// Adds the app to the user's favorites. Adding an app that's already a
+/// favorite does nothing.
+pub async fn add_favorite_app<'a, E>(
+    conn: E,
+    username: &str,
+    app_id: &Uuid,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query!(
+        r#"
+            INSERT INTO app_favorites (user_id, app_id)
+            VALUES ((SELECT id FROM users WHERE username = $1), $2)
+            ON CONFLICT (user_id, app_id) DO NOTHING
+        "#,
+        username,
+        app_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_favorite_app<'a, E>(
+    conn: E,
+    username: &str,
+    app_id: &Uuid,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM app_favorites
+            WHERE user_id = (SELECT id FROM users WHERE username = $1)
+            AND app_id = $2
+        "#,
+        username,
+        app_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Returns the apps the user has run, ordered by when the user last started
+/// an analysis of each.
+pub async fn list_recent_apps<'a, E>(
+    conn: E,
+    username: &str,
+    limit: i64,
+    tz: &Tz,
+) -> Result<Vec<RecentApp>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            SELECT
+                a.id,
+                a.name,
+                a.description,
+                COALESCE(s.deleted, true) AS "deleted!",
+                COALESCE(s.disabled, true) AS "disabled!",
+                r.last_start_date AS "last_start_date!",
+                r.analyses AS "analyses!"
+            FROM (
+                SELECT
+                    av.app_id,
+                    MAX(j.start_date) AS last_start_date,
+                    COUNT(*) AS analyses
+                FROM jobs j
+                JOIN users u ON j.user_id = u.id
+                JOIN app_versions av ON j.app_version_id = av.id
+                WHERE u.username = $1
+                AND j.start_date IS NOT NULL
+                GROUP BY av.app_id
+            ) r
+            JOIN apps a ON r.app_id = a.id
+            LEFT JOIN (
+                SELECT
+                    app_id,
+                    bool_and(deleted) AS deleted,
+                    bool_and(disabled) AS disabled
+                FROM app_versions
+                GROUP BY app_id
+            ) s ON s.app_id = a.id
+            ORDER BY r.last_start_date DESC, a.name
+            LIMIT $2
+        "#,
+        username,
+        limit,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results
+        .into_iter()
+        .map(|r| RecentApp {
+            app: AppRecord {
+                id: r.id,
+                name: r.name,
+                description: r.description,
+            }
+            .into_app(),
+            deleted: r.deleted,
+            disabled: r.disabled,
+            last_start_date: localize(r.last_start_date, tz),
+            analyses: r.analyses,
+        })
+        .collect())
+}
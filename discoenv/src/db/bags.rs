@@ -6,6 +6,8 @@ use sqlx::{
 };
 use utoipa::ToSchema;
 
+use crate::crypto::{self, CryptoError, Keyring, Owner};
+
 /// A JSON document stored in the database as a Bag.
 #[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
 pub struct Bag {
@@ -18,6 +20,74 @@ pub struct Bag {
     // The JSON contents of the bag.
     #[schema(value_type = Object)]
     pub contents: Json<Map<String, JsonValue>>,
+
+    /// Whether the bag's contents are encrypted at rest.
+    pub sensitive: bool,
+
+    /// The ID of the key the stored contents are encrypted with. Never sent to
+    /// clients.
+    #[serde(skip)]
+    pub contents_key_id: Option<String>,
+}
+
+impl Bag {
+    /// Decrypts the bag's contents if they were stored encrypted. Encrypted
+    /// contents are stored as an object with a single ciphertext field.
+    fn open(mut self, keyring: Option<&Keyring>) -> Result<Self, sqlx::Error> {
+        if self.contents_key_id.is_none() {
+            return Ok(self);
+        }
+
+        let ciphertext = self
+            .contents
+            .get("ciphertext")
+            .and_then(|v| v.as_str())
+            .ok_or(CryptoError::Decrypt)?
+            .to_owned();
+        let plaintext = crypto::open(
+            keyring,
+            self.contents_key_id.as_deref(),
+            ciphertext,
+            bag_owner(&self.user_id, &self.id),
+        )?;
+        let contents: Map<String, JsonValue> =
+            serde_json::from_str(&plaintext).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+
+        self.contents = Json(contents);
+        self.contents_key_id = None;
+        Ok(self)
+    }
+}
+
+/// The row an encrypted bag is bound to.
+fn bag_owner<'a>(user_id: &'a Uuid, bag_id: &'a Uuid) -> Owner<'a> {
+    Owner {
+        user_id,
+        table: "bags",
+        row_id: bag_id,
+    }
+}
+
+/// Prepares bag contents for storage. Contents of sensitive bags are encrypted,
+/// which requires a keyring to be configured.
+fn seal_contents(
+    contents: Map<String, JsonValue>,
+    sensitive: bool,
+    keyring: Option<&Keyring>,
+    owner: Owner,
+) -> Result<(JsonValue, Option<String>), sqlx::Error> {
+    if !sensitive {
+        return Ok((JsonValue::Object(contents), None));
+    }
+
+    let keyring = keyring.ok_or(CryptoError::NotConfigured)?;
+    let plaintext = JsonValue::Object(contents).to_string();
+    let (ciphertext, key_id) = crypto::seal(Some(keyring), &plaintext, owner)?;
+
+    let mut sealed = Map::new();
+    sealed.insert("ciphertext".to_owned(), JsonValue::String(ciphertext));
+
+    Ok((JsonValue::Object(sealed), key_id))
 }
 
 /// A vector of Bags.
@@ -26,19 +96,34 @@ pub struct Bags {
     pub bags: Vec<Bag>,
 }
 
-pub async fn list_bags<'a, E>(conn: E) -> Result<Vec<Bag>, sqlx::Error>
+pub async fn list_bags<'a, E>(conn: E, keyring: Option<&Keyring>) -> Result<Vec<Bag>, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
     query_as!(
         Bag,
-        r#"select id, user_id, contents as "contents: Json<Map<String, JsonValue>>" from bags"#
+        r#"
+            select
+                id,
+                user_id,
+                contents as "contents: Json<Map<String, JsonValue>>",
+                sensitive,
+                contents_key_id
+            from bags
+        "#
     )
     .fetch_all(conn)
-    .await
+    .await?
+    .into_iter()
+    .map(|b| b.open(keyring))
+    .collect()
 }
 
-pub async fn list_user_bags<'a, E>(conn: E, username: &str) -> Result<Bags, sqlx::Error>
+pub async fn list_user_bags<'a, E>(
+    conn: E,
+    username: &str,
+    keyring: Option<&Keyring>,
+) -> Result<Bags, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
@@ -48,7 +133,9 @@ where
             select 
                 bags.id, 
                 user_id, 
-                contents as "contents: Json<Map<String, JsonValue>>" 
+                contents as "contents: Json<Map<String, JsonValue>>",
+                sensitive,
+                contents_key_id
             from bags
             join users on users.id = bags.user_id 
             where users.username = $1
@@ -58,25 +145,39 @@ where
     .fetch_all(conn)
     .await?;
 
-    Ok(Bags { bags })
+    Ok(Bags {
+        bags: bags
+            .into_iter()
+            .map(|b| b.open(keyring))
+            .collect::<Result<Vec<Bag>, sqlx::Error>>()?,
+    })
 }
 
+/// Adds a bag for the user. The bag's ID is generated up front so that the
+/// contents of a sensitive bag can be bound to the row they're stored in.
 pub async fn add_user_bag<'a, E>(
     conn: E,
-    username: &str,
+    user_id: &Uuid,
     contents: Map<String, JsonValue>,
+    sensitive: bool,
+    keyring: Option<&Keyring>,
 ) -> Result<Uuid, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
+    let id = Uuid::new_v4();
+    let (contents, key_id) = seal_contents(contents, sensitive, keyring, bag_owner(user_id, &id))?;
     let r = query!(
         r#"
             insert into bags 
-                (user_id, contents) 
+                (id, user_id, contents, sensitive, contents_key_id) 
             values 
-                ((SELECT id from users where username = $1), $2) returning id"#,
-        username,
-        JsonValue::Object(contents)
+                ($1, $2, $3, $4, $5) returning id"#,
+        id,
+        user_id,
+        contents,
+        sensitive,
+        key_id,
     )
     .fetch_one(conn)
     .await?;
@@ -170,18 +271,68 @@ where
     Ok(result.bag_exists.unwrap_or(false))
 }
 
+pub async fn bag_is_sensitive<'a, E>(
+    conn: E,
+    username: &str,
+    bag_id: &Uuid,
+) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            select bool_or(bags.sensitive) as sensitive
+            from bags
+            join users on bags.user_id = users.id
+            and users.username = $1
+            and bags.id = $2
+        "#,
+        username,
+        bag_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.sensitive.unwrap_or(false))
+}
+
+pub async fn default_bag_id<'a, E>(conn: E, username: &str) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            select d.bag_id
+            from default_bags d
+            join users u on d.user_id = u.id
+            where u.username = $1
+        "#,
+        username
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.bag_id)
+}
+
 pub async fn update_bag_contents<'a, E>(
     conn: E,
     id: &Uuid,
+    user_id: &Uuid,
     contents: Map<String, JsonValue>,
+    sensitive: bool,
+    keyring: Option<&Keyring>,
 ) -> Result<u64, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
+    let (contents, key_id) = seal_contents(contents, sensitive, keyring, bag_owner(user_id, id))?;
     let result = query!(
-        r#"update bags set contents = $2 where id = $1"#,
+        r#"update bags set contents = $2, sensitive = $3, contents_key_id = $4 where id = $1"#,
         id,
-        JsonValue::Object(contents)
+        contents,
+        sensitive,
+        key_id,
     )
     .execute(conn)
     .await?;
@@ -189,7 +340,11 @@ where
     Ok(result.rows_affected())
 }
 
-pub async fn get_default_bag<'a, E>(conn: E, username: &str) -> Result<Bag, sqlx::Error>
+pub async fn get_default_bag<'a, E>(
+    conn: E,
+    username: &str,
+    keyring: Option<&Keyring>,
+) -> Result<Bag, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
@@ -199,7 +354,9 @@ where
             SELECT
                 b.id,
                 b.user_id,
-                b.contents as "contents: Json<Map<String, JsonValue>>"
+                b.contents as "contents: Json<Map<String, JsonValue>>",
+                b.sensitive,
+                b.contents_key_id
             FROM bags b
             JOIN default_bags d ON b.id = d.bag_id
             JOIN users u ON d.user_id = u.id
@@ -211,10 +368,15 @@ where
     .fetch_one(conn)
     .await?;
 
-    Ok(row)
+    row.open(keyring)
 }
 
-pub async fn get_bag<'a, E>(conn: E, username: &str, bag_id: &Uuid) -> Result<Bag, sqlx::Error>
+pub async fn get_bag<'a, E>(
+    conn: E,
+    username: &str,
+    bag_id: &Uuid,
+    keyring: Option<&Keyring>,
+) -> Result<Bag, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
@@ -224,7 +386,9 @@ where
             SELECT
                 b.id,
                 b.user_id,
-                b.contents as "contents: Json<Map<String, JsonValue>>"
+                b.contents as "contents: Json<Map<String, JsonValue>>",
+                b.sensitive,
+                b.contents_key_id
             FROM bags b
             JOIN users u ON b.user_id = u.id
             WHERE
@@ -236,7 +400,8 @@ where
         bag_id,
     )
     .fetch_one(conn)
-    .await
+    .await?
+    .open(keyring)
 }
 
 pub async fn set_default_bag<'a, E>(
@@ -270,52 +435,31 @@ where
     Ok(())
 }
 
-pub async fn update_default_bag<'a, E>(
-    executor: E,
-    username: &str,
-    contents: Map<String, JsonValue>,
-) -> Result<u64, sqlx::Error>
-where
-    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
-{
-    Ok(query!(
-        r#"
-            UPDATE bags
-            SET contents = $2
-            FROM default_bags, users
-            WHERE bags.id = default_bags.bag_id
-            AND default_bags.user_id = users.id
-            AND users.username = $1
-        "#,
-        username,
-        JsonValue::Object(contents)
-    )
-    .execute(executor)
-    .await?
-    .rows_affected())
-}
-
 pub async fn update_bag<'a, E>(
     executor: E,
-    username: &str,
+    user_id: &Uuid,
     bag_id: &Uuid,
     contents: Map<String, JsonValue>,
+    sensitive: bool,
+    keyring: Option<&Keyring>,
 ) -> Result<u64, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
+    let (contents, key_id) =
+        seal_contents(contents, sensitive, keyring, bag_owner(user_id, bag_id))?;
     Ok(query!(
         r#"
             UPDATE bags
-            SET contents = $3
-            FROM users
+            SET contents = $3, sensitive = $4, contents_key_id = $5
             WHERE bags.id = $2
-            AND bags.user_id = users.id
-            AND users.username = $1
+            AND bags.user_id = $1
         "#,
-        username,
+        user_id,
         bag_id,
-        JsonValue::Object(contents)
+        contents,
+        sensitive,
+        key_id,
     )
     .execute(executor)
     .await?
@@ -393,3 +537,62 @@ where
     .await?
     .rows_affected())
 }
+
+/// A sensitive bag that isn't encrypted with the current key.
+pub struct StaleBag {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub contents: JsonValue,
+    pub contents_key_id: Option<String>,
+}
+
+/// Returns up to `limit` sensitive bags that are stored in plaintext or
+/// encrypted with a key other than the current one.
+pub async fn list_stale_bags<'a, E>(
+    conn: E,
+    current_key_id: &str,
+    limit: i64,
+) -> Result<Vec<StaleBag>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as!(
+        StaleBag,
+        r#"
+            SELECT id, user_id, contents, contents_key_id
+            FROM bags
+            WHERE sensitive
+            AND contents_key_id IS DISTINCT FROM $1
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        "#,
+        current_key_id,
+        limit,
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Re-encrypts a single sensitive bag under the keyring's current key.
+pub async fn reencrypt_bag<'a, E>(
+    conn: E,
+    stale: StaleBag,
+    keyring: &Keyring,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let bag = Bag {
+        id: stale.id,
+        user_id: stale.user_id,
+        contents: Json(match stale.contents {
+            JsonValue::Object(m) => m,
+            _ => Map::new(),
+        }),
+        sensitive: true,
+        contents_key_id: stale.contents_key_id,
+    }
+    .open(Some(keyring))?;
+
+    update_bag_contents(conn, &bag.id, &bag.user_id, bag.contents.0, true, Some(keyring)).await
+}
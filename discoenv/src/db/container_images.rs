@@ -0,0 +1,265 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, types::Uuid};
+use utoipa::{IntoParams, ToSchema};
+
+use super::analyses::{escape_like, localize};
+
+/// The default number of days an analysis counts as recent for.
+pub const DEFAULT_RECENT_DAYS: i32 = 30;
+
+/// Filters that can be applied when listing container images.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ContainerImageFilter {
+    /// Only include deprecated images if true, or images that aren't deprecated if false.
+    pub deprecated: Option<bool>,
+
+    /// Only include images from this registry host, e.g. "harbor.cyverse.org".
+    pub registry: Option<String>,
+
+    /// Only include images with names containing this string. Case insensitive.
+    pub name: Option<String>,
+
+    /// How many days back analyses count as recent. Defaults to 30.
+    pub recent_days: Option<i32>,
+}
+
+impl ContainerImageFilter {
+    pub fn recent_days(&self) -> i32 {
+        self.recent_days.unwrap_or(DEFAULT_RECENT_DAYS)
+    }
+}
+
+/// A container image along with how much it's used.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ContainerImage {
+    pub id: Uuid,
+    pub name: String,
+    pub tag: String,
+    pub url: Option<String>,
+    pub osg_image_path: Option<String>,
+    pub deprecated: bool,
+
+    /// The number of tools that run in the image.
+    pub tools: i64,
+
+    /// The number of apps with a version that runs one of those tools.
+    pub apps: i64,
+
+    /// The number of analyses of those apps started in the last `recent_days` days.
+    pub recent_analyses: i64,
+}
+
+/// A list of container images.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ContainerImages {
+    pub container_images: Vec<ContainerImage>,
+}
+
+/// A tool that runs in a container image.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct DependentTool {
+    pub id: Uuid,
+    pub name: String,
+    pub version: String,
+
+    /// The username of the user who added the tool.
+    pub integrator: String,
+
+    /// The number of apps with a version that runs the tool.
+    pub apps: i64,
+}
+
+/// A user who recently ran an analysis that used a container image.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct AffectedUser {
+    pub username: String,
+
+    /// The number of the user's recent analyses that used the image.
+    pub analyses: i64,
+
+    /// When the most recent of those analyses started.
+    pub last_start_date: DateTime<Utc>,
+}
+
+/// Everything that depends on a container image.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ImageDependents {
+    pub container_image: ContainerImage,
+
+    /// The tools that run in the image.
+    pub tools: Vec<DependentTool>,
+
+    /// The users who ran analyses that used the image in the last `recent_days` days, most
+    /// active first.
+    pub users: Vec<AffectedUser>,
+}
+
+/// Returns the container images matching the filter along with their usage
+/// counts, sorted by name and tag. If image_id is set, only that image is
+/// returned.
+pub async fn list_container_images<'a, E>(
+    conn: E,
+    filter: &ContainerImageFilter,
+    image_id: Option<&Uuid>,
+) -> Result<Vec<ContainerImage>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let registry = filter
+        .registry
+        .as_deref()
+        .map(|r| format!("{}/%", escape_like(r)));
+    let name = filter
+        .name
+        .as_deref()
+        .map(|n| format!("%{}%", escape_like(n)));
+
+    query_as!(
+        ContainerImage,
+        r#"
+            WITH tool_app_versions AS (
+                SELECT DISTINCT tk.tool_id, s.app_version_id
+                FROM tasks tk
+                JOIN app_steps s ON s.task_id = tk.id
+            ), image_versions AS (
+                SELECT DISTINCT t.container_images_id, tav.app_version_id
+                FROM tools t
+                JOIN tool_app_versions tav ON tav.tool_id = t.id
+            )
+            SELECT
+                c.id,
+                c.name,
+                c.tag,
+                c.url,
+                c.osg_image_path,
+                c.deprecated,
+                (
+                    SELECT COUNT(*)
+                    FROM tools t
+                    WHERE t.container_images_id = c.id
+                ) AS "tools!",
+                (
+                    SELECT COUNT(DISTINCT av.app_id)
+                    FROM image_versions iv
+                    JOIN app_versions av ON iv.app_version_id = av.id
+                    WHERE iv.container_images_id = c.id
+                ) AS "apps!",
+                (
+                    SELECT COUNT(*)
+                    FROM jobs j
+                    WHERE j.app_version_id IN (
+                        SELECT iv.app_version_id
+                        FROM image_versions iv
+                        WHERE iv.container_images_id = c.id
+                    )
+                    AND j.start_date >= now() - make_interval(days => $5)
+                ) AS "recent_analyses!"
+            FROM container_images c
+            WHERE ($1::uuid IS NULL OR c.id = $1)
+            AND ($2::boolean IS NULL OR c.deprecated = $2)
+            AND ($3::text IS NULL OR c.name LIKE $3)
+            AND ($4::text IS NULL OR c.name ILIKE $4)
+            ORDER BY c.name, c.tag, c.id
+        "#,
+        image_id,
+        filter.deprecated,
+        registry,
+        name,
+        filter.recent_days(),
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Returns the tools that run in the container image, sorted by name and
+/// version.
+pub async fn list_dependent_tools<'a, E>(
+    conn: E,
+    image_id: &Uuid,
+) -> Result<Vec<DependentTool>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as!(
+        DependentTool,
+        r#"
+            WITH tool_app_versions AS (
+                SELECT DISTINCT tk.tool_id, s.app_version_id
+                FROM tasks tk
+                JOIN app_steps s ON s.task_id = tk.id
+            )
+            SELECT
+                t.id,
+                t.name,
+                t.version,
+                u.username AS integrator,
+                (
+                    SELECT COUNT(DISTINCT av.app_id)
+                    FROM tool_app_versions tav
+                    JOIN app_versions av ON tav.app_version_id = av.id
+                    WHERE tav.tool_id = t.id
+                ) AS "apps!"
+            FROM tools t
+            JOIN integration_data i ON t.integration_data_id = i.id
+            JOIN users u ON i.user_id = u.id
+            WHERE t.container_images_id = $1
+            ORDER BY t.name, t.version, t.id
+        "#,
+        image_id,
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Returns the users who ran analyses that used the container image in the
+/// last recent_days days, most active first.
+pub async fn list_affected_users<'a, E>(
+    conn: E,
+    image_id: &Uuid,
+    recent_days: i32,
+    tz: &Tz,
+) -> Result<Vec<AffectedUser>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            WITH tool_app_versions AS (
+                SELECT DISTINCT tk.tool_id, s.app_version_id
+                FROM tasks tk
+                JOIN app_steps s ON s.task_id = tk.id
+            )
+            SELECT
+                u.username,
+                COUNT(*) AS "analyses!",
+                MAX(j.start_date) AS "last_start_date!"
+            FROM jobs j
+            JOIN users u ON j.user_id = u.id
+            WHERE j.app_version_id IN (
+                SELECT tav.app_version_id
+                FROM tools t
+                JOIN tool_app_versions tav ON tav.tool_id = t.id
+                WHERE t.container_images_id = $1
+            )
+            AND j.start_date >= now() - make_interval(days => $2)
+            GROUP BY u.username
+            ORDER BY 2 DESC, u.username
+        "#,
+        image_id,
+        recent_days,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results
+        .into_iter()
+        .map(|r| AffectedUser {
+            username: r.username,
+            analyses: r.analyses,
+            last_start_date: localize(r.last_start_date, tz),
+        })
+        .collect())
+}
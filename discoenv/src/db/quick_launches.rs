@@ -0,0 +1,316 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    query, query_as,
+    types::{JsonValue, Uuid},
+};
+use utoipa::ToSchema;
+
+/// A named set of parameters that an app version can be launched with.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq, sqlx::FromRow)]
+pub struct QuickLaunch {
+    /// The quick launch's UUID.
+    pub id: Uuid,
+
+    pub name: String,
+    pub description: Option<String>,
+
+    /// The username of the user that created the quick launch.
+    pub creator: String,
+
+    pub app_id: Uuid,
+    pub app_version_id: Uuid,
+
+    /// Whether other users can see and use the quick launch.
+    pub is_public: bool,
+
+    /// The analysis submission the app version is launched with.
+    #[schema(value_type = Object)]
+    pub submission: JsonValue,
+}
+
+/// The fields of a quick launch that its creator sets.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct QuickLaunchRequest {
+    pub name: String,
+    pub description: Option<String>,
+
+    /// The app version the quick launch launches.
+    pub app_version_id: Uuid,
+
+    /// Whether other users can see and use the quick launch. Defaults to false.
+    #[serde(default)]
+    pub is_public: bool,
+
+    /// The analysis submission to launch the app version with. Its config maps each
+    /// parameter's step ID and parameter ID, joined by an underscore, to the parameter's value.
+    #[schema(value_type = Object)]
+    pub submission: JsonValue,
+}
+
+/// A list of quick launches.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct QuickLaunches {
+    pub quick_launches: Vec<QuickLaunch>,
+}
+
+/// A parameter of one of an app version's steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppParameter {
+    /// The key the parameter's value is stored under in a submission's config,
+    /// made up of the step ID and the parameter ID.
+    pub key: String,
+
+    /// Whether the parameter has to have a value.
+    pub required: bool,
+
+    /// Whether the parameter has a default value to fall back on.
+    pub has_default: bool,
+}
+
+const QUICK_LAUNCH_COLUMNS: &str = r#"
+    SELECT
+        q.id,
+        q.name,
+        q.description,
+        u.username AS creator,
+        q.app_id,
+        q.app_version_id,
+        q.is_public,
+        s.submission::jsonb AS submission
+    FROM quick_launches q
+    JOIN users u ON q.creator = u.id
+    JOIN submissions s ON q.submission_id = s.id
+"#;
+
+/// Returns the ID of the app the version belongs to, or None if there's no
+/// such version.
+pub async fn get_version_app_id<'a, E>(
+    conn: E,
+    app_version_id: &Uuid,
+) -> Result<Option<Uuid>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            SELECT app_id
+            FROM app_versions
+            WHERE id = $1
+        "#,
+        app_version_id,
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(result.map(|r| r.app_id))
+}
+
+/// Returns the parameters of all of the app version's steps.
+pub async fn list_app_version_parameters<'a, E>(
+    conn: E,
+    app_version_id: &Uuid,
+) -> Result<Vec<AppParameter>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            SELECT
+                s.id AS step_id,
+                p.id AS parameter_id,
+                COALESCE(p.required, false) AS "required!",
+                EXISTS (
+                    SELECT 1
+                    FROM parameter_values pv
+                    WHERE pv.parameter_id = p.id
+                    AND pv.is_default
+                ) AS "has_default!"
+            FROM app_steps s
+            JOIN parameter_groups g ON g.task_id = s.task_id
+            JOIN parameters p ON p.parameter_group_id = g.id
+            WHERE s.app_version_id = $1
+            ORDER BY s.step, g.display_order, p.display_order
+        "#,
+        app_version_id,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results
+        .into_iter()
+        .map(|r| AppParameter {
+            key: format!("{}_{}", r.step_id, r.parameter_id),
+            required: r.required,
+            has_default: r.has_default,
+        })
+        .collect())
+}
+
+/// Returns the user's own quick launches, sorted by name.
+pub async fn list_user_quick_launches<'a, E>(
+    conn: E,
+    username: &str,
+) -> Result<Vec<QuickLaunch>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as(&format!(
+        "{} WHERE u.username = $1 ORDER BY q.name, q.id",
+        QUICK_LAUNCH_COLUMNS
+    ))
+    .bind(username)
+    .fetch_all(conn)
+    .await
+}
+
+/// Returns the quick launches for the app that the user can see: their own
+/// and the public ones. Sorted by name.
+pub async fn list_app_quick_launches<'a, E>(
+    conn: E,
+    username: &str,
+    app_id: &Uuid,
+) -> Result<Vec<QuickLaunch>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as(&format!(
+        "{} WHERE q.app_id = $2 AND (u.username = $1 OR q.is_public) ORDER BY q.name, q.id",
+        QUICK_LAUNCH_COLUMNS
+    ))
+    .bind(username)
+    .bind(app_id)
+    .fetch_all(conn)
+    .await
+}
+
+/// Returns the quick launch if the user created it or it's public.
+pub async fn get_quick_launch<'a, E>(
+    conn: E,
+    username: &str,
+    quick_launch_id: &Uuid,
+) -> Result<Option<QuickLaunch>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as(&format!(
+        "{} WHERE q.id = $2 AND (u.username = $1 OR q.is_public)",
+        QUICK_LAUNCH_COLUMNS
+    ))
+    .bind(username)
+    .bind(quick_launch_id)
+    .fetch_optional(conn)
+    .await
+}
+
+/// Adds a quick launch for the user, storing the submission alongside it.
+pub async fn add_quick_launch<'a, E>(
+    conn: E,
+    username: &str,
+    app_id: &Uuid,
+    ql: &QuickLaunchRequest,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            WITH s AS (
+                INSERT INTO submissions (submission)
+                VALUES ($7::jsonb::json)
+                RETURNING id
+            )
+            INSERT INTO quick_launches
+                (name, description, creator, app_id, app_version_id, is_public, submission_id)
+            SELECT $2, $3, (SELECT id FROM users WHERE username = $1), $4, $5, $6, s.id
+            FROM s
+            RETURNING id
+        "#,
+        username,
+        ql.name,
+        ql.description,
+        app_id,
+        ql.app_version_id,
+        ql.is_public,
+        ql.submission,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.id)
+}
+
+/// Replaces one of the user's quick launches. Returns the number of quick
+/// launches changed, which is 0 if the user didn't create it.
+pub async fn update_quick_launch<'a, E>(
+    conn: E,
+    username: &str,
+    quick_launch_id: &Uuid,
+    app_id: &Uuid,
+    ql: &QuickLaunchRequest,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            WITH q AS (
+                UPDATE quick_launches
+                SET name = $3,
+                    description = $4,
+                    app_id = $5,
+                    app_version_id = $6,
+                    is_public = $7
+                WHERE id = $2
+                AND creator = (SELECT id FROM users WHERE username = $1)
+                RETURNING submission_id
+            )
+            UPDATE submissions
+            SET submission = $8::jsonb::json
+            FROM q
+            WHERE submissions.id = q.submission_id
+        "#,
+        username,
+        quick_launch_id,
+        ql.name,
+        ql.description,
+        app_id,
+        ql.app_version_id,
+        ql.is_public,
+        ql.submission,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Deletes one of the user's quick launches along with its submission.
+/// Returns 0 if the user didn't create it. Fails with a foreign key violation
+/// if it's still a default or a favorite.
+pub async fn delete_quick_launch<'a, E>(
+    conn: E,
+    username: &str,
+    quick_launch_id: &Uuid,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            WITH q AS (
+                DELETE FROM quick_launches
+                WHERE id = $2
+                AND creator = (SELECT id FROM users WHERE username = $1)
+                RETURNING submission_id
+            )
+            DELETE FROM submissions
+            WHERE id IN (SELECT submission_id FROM q)
+        "#,
+        username,
+        quick_launch_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -1,5 +1,9 @@
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, types::Uuid};
+use serde_json::Map;
+use sqlx::{
+    query, query_as,
+    types::{JsonValue, Uuid},
+};
 use utoipa::ToSchema;
 
 /// A record containing a user's saved searches.
@@ -133,3 +137,240 @@ where
 
     Ok(result.rows_affected())
 }
+
+/// A saved search that another user has shared with the current user.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct SharedSearch {
+    /// The unique identifier for the share.
+    pub id: Uuid,
+
+    /// The username of the user that owns the saved search.
+    pub owner: String,
+
+    /// The name of the search within the owner's saved searches document.
+    pub name: String,
+
+    /// The JSON contents of the saved search. Will be null if the owner no
+    /// longer has a search with the shared name.
+    #[schema(value_type = Object)]
+    pub search: JsonValue,
+}
+
+/// A list of saved searches shared with a user.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct SharedSearches {
+    pub shared_searches: Vec<SharedSearch>,
+}
+
+/// A user's saved searches along with the searches other users have shared
+/// with them. The fields of the user's own saved searches record are left out
+/// if they haven't saved any searches yet.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct SavedSearchListing {
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub saved: Option<SavedSearches>,
+
+    /// The searches shared with the user, each marked with the username of its
+    /// owner.
+    pub shared_searches: Vec<SharedSearch>,
+}
+
+/// A record of a saved search that a user has shared with someone else.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct SearchShare {
+    /// The unique identifier for the share.
+    pub id: Uuid,
+
+    /// The name of the shared search.
+    pub name: String,
+
+    /// The username of the user the search was shared with.
+    pub recipient: String,
+}
+
+/// A list of the shares created by a user.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct SearchShares {
+    pub shares: Vec<SearchShare>,
+}
+
+/// Pulls a single named search out of a saved searches document. The document
+/// is expected to be a JSON object keyed by search name.
+pub fn find_search(saved_searches: &str, name: &str) -> Option<JsonValue> {
+    serde_json::from_str::<Map<String, JsonValue>>(saved_searches)
+        .ok()?
+        .remove(name)
+}
+
+/// Shares one of the owner's saved searches with a recipient. Sharing the same
+/// search with the same recipient more than once is a no-op.
+pub async fn add_search_share<'a, E>(
+    conn: E,
+    owner: &str,
+    recipient: &str,
+    name: &str,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            INSERT INTO saved_search_shares
+                (owner_id, recipient_id, search_name)
+            VALUES
+                (
+                    (SELECT id FROM users WHERE username = $1),
+                    (SELECT id FROM users WHERE username = $2),
+                    $3
+                )
+            ON CONFLICT (owner_id, recipient_id, search_name)
+                DO UPDATE SET search_name = EXCLUDED.search_name
+            RETURNING id
+        "#,
+        owner,
+        recipient,
+        name,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.id)
+}
+
+/// Lists the shares the owner has created.
+pub async fn list_search_shares<'a, E>(conn: E, owner: &str) -> Result<SearchShares, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let shares = query_as!(
+        SearchShare,
+        r#"
+            SELECT
+                s.id,
+                s.search_name AS name,
+                r.username AS recipient
+            FROM saved_search_shares s
+            JOIN users o ON s.owner_id = o.id
+            JOIN users r ON s.recipient_id = r.id
+            WHERE o.username = $1
+            ORDER BY s.search_name, r.username
+        "#,
+        owner,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(SearchShares { shares })
+}
+
+/// Revokes a share. Copies the recipient already made are left alone.
+pub async fn delete_search_share<'a, E>(
+    conn: E,
+    owner: &str,
+    recipient: &str,
+    name: &str,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM saved_search_shares
+            WHERE owner_id = (SELECT id FROM users WHERE username = $1)
+            AND recipient_id = (SELECT id FROM users WHERE username = $2)
+            AND search_name = $3
+        "#,
+        owner,
+        recipient,
+        name,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Lists the saved searches other users have shared with the recipient, along
+/// with the current contents of each search from the owner's document.
+pub async fn list_shared_searches<'a, E>(
+    conn: E,
+    recipient: &str,
+) -> Result<SharedSearches, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let records = query!(
+        r#"
+            SELECT
+                s.id,
+                o.username AS owner,
+                s.search_name,
+                ss.saved_searches AS "saved_searches?"
+            FROM saved_search_shares s
+            JOIN users o ON s.owner_id = o.id
+            JOIN users r ON s.recipient_id = r.id
+            LEFT JOIN user_saved_searches ss ON ss.user_id = s.owner_id
+            WHERE r.username = $1
+            ORDER BY o.username, s.search_name
+        "#,
+        recipient,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(SharedSearches {
+        shared_searches: records
+            .into_iter()
+            .map(|r| SharedSearch {
+                id: r.id,
+                search: r
+                    .saved_searches
+                    .and_then(|doc| find_search(&doc, &r.search_name))
+                    .unwrap_or_default(),
+                owner: r.owner,
+                name: r.search_name,
+            })
+            .collect(),
+    })
+}
+
+/// Returns a single saved search shared with the recipient.
+pub async fn get_shared_search<'a, E>(
+    conn: E,
+    recipient: &str,
+    share_id: &Uuid,
+) -> Result<SharedSearch, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let r = query!(
+        r#"
+            SELECT
+                s.id,
+                o.username AS owner,
+                s.search_name,
+                ss.saved_searches AS "saved_searches?"
+            FROM saved_search_shares s
+            JOIN users o ON s.owner_id = o.id
+            JOIN users r ON s.recipient_id = r.id
+            LEFT JOIN user_saved_searches ss ON ss.user_id = s.owner_id
+            WHERE r.username = $1
+            AND s.id = $2
+        "#,
+        recipient,
+        share_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(SharedSearch {
+        id: r.id,
+        search: r
+            .saved_searches
+            .and_then(|doc| find_search(&doc, &r.search_name))
+            .unwrap_or_default(),
+        owner: r.owner,
+        name: r.search_name,
+    })
+}
@@ -1,7 +1,12 @@
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, types::Uuid};
+use sqlx::{
+    query, query_as,
+    types::{chrono::NaiveDateTime, Uuid},
+};
 use utoipa::ToSchema;
 
+use crate::crypto::{self, Keyring, Owner};
+
 /// A record containing a user's session.
 #[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
 pub struct Session {
@@ -13,6 +18,46 @@ pub struct Session {
 
     /// The session stored as text.
     pub session: String,
+
+    /// The client-supplied label for the device the session belongs to.
+    pub device_label: Option<String>,
+
+    /// When the session was created.
+    pub created_date: NaiveDateTime,
+
+    /// When the session was last read or written.
+    pub last_access_date: NaiveDateTime,
+
+    /// How long the session may go unused before it expires. Sessions without
+    /// a TTL never expire.
+    pub ttl_seconds: Option<i32>,
+
+    /// When the session will expire if it isn't used again.
+    pub expires_date: Option<NaiveDateTime>,
+
+    /// The ID of the key the stored session is encrypted with. Never sent to
+    /// clients.
+    #[serde(skip)]
+    pub session_key_id: Option<String>,
+}
+
+impl Session {
+    /// Decrypts the session contents if they were stored encrypted.
+    fn open(mut self, keyring: Option<&Keyring>) -> Result<Self, sqlx::Error> {
+        let owner = session_owner(&self.user_id, &self.id);
+        self.session = crypto::open(keyring, self.session_key_id.as_deref(), self.session, owner)?;
+        self.session_key_id = None;
+        Ok(self)
+    }
+}
+
+/// The row an encrypted session is bound to.
+fn session_owner<'a>(user_id: &'a Uuid, session_id: &'a Uuid) -> Owner<'a> {
+    Owner {
+        user_id,
+        table: "user_sessions",
+        row_id: session_id,
+    }
 }
 
 /// A list of sessions
@@ -31,6 +76,10 @@ where
             FROM user_sessions
             JOIN users ON user_sessions.user_id = users.id
             WHERE users.username = $1
+            AND (
+                user_sessions.ttl_seconds IS NULL
+                OR user_sessions.last_access_date + make_interval(secs => user_sessions.ttl_seconds) > now()
+            )
         "#,
         username,
     )
@@ -40,7 +89,41 @@ where
     Ok(result.has_session.unwrap_or(false))
 }
 
-pub async fn get_session<'a, E>(conn: E, username: &str) -> Result<Session, sqlx::Error>
+pub async fn session_exists<'a, E>(
+    conn: E,
+    username: &str,
+    session_id: &Uuid,
+) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            SELECT COUNT(*) > 0 AS session_exists
+            FROM user_sessions
+            JOIN users ON user_sessions.user_id = users.id
+            WHERE users.username = $1
+            AND user_sessions.id = $2
+            AND (
+                user_sessions.ttl_seconds IS NULL
+                OR user_sessions.last_access_date + make_interval(secs => user_sessions.ttl_seconds) > now()
+            )
+        "#,
+        username,
+        session_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.session_exists.unwrap_or(false))
+}
+
+/// Returns all of the user's unexpired sessions, most recently used first.
+pub async fn list_sessions<'a, E>(
+    conn: E,
+    username: &str,
+    keyring: Option<&Keyring>,
+) -> Result<Sessions, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
@@ -50,34 +133,108 @@ where
             SELECT
                 s.id,
                 s.user_id,
-                s.session
+                s.session,
+                s.device_label,
+                s.created_date,
+                s.last_access_date,
+                s.ttl_seconds,
+                s.last_access_date + make_interval(secs => s.ttl_seconds) AS "expires_date?",
+                s.session_key_id
             FROM
                 user_sessions s
             JOIN users u ON s.user_id = u.id
             WHERE u.username = $1
+            AND (
+                s.ttl_seconds IS NULL
+                OR s.last_access_date + make_interval(secs => s.ttl_seconds) > now()
+            )
+            ORDER BY s.last_access_date DESC
         "#,
         username,
     )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(Sessions {
+        sessions: sessions
+            .into_iter()
+            .map(|s| s.open(keyring))
+            .collect::<Result<Vec<Session>, sqlx::Error>>()?,
+    })
+}
+
+/// Returns one of the user's sessions and marks it as accessed.
+pub async fn get_session<'a, E>(
+    conn: E,
+    username: &str,
+    session_id: &Uuid,
+    keyring: Option<&Keyring>,
+) -> Result<Session, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let session = query_as!(
+        Session,
+        r#"
+            UPDATE user_sessions s
+            SET last_access_date = now()
+            FROM users u
+            WHERE s.user_id = u.id
+            AND u.username = $1
+            AND s.id = $2
+            AND (
+                s.ttl_seconds IS NULL
+                OR s.last_access_date + make_interval(secs => s.ttl_seconds) > now()
+            )
+            RETURNING
+                s.id,
+                s.user_id,
+                s.session,
+                s.device_label,
+                s.created_date,
+                s.last_access_date,
+                s.ttl_seconds,
+                s.last_access_date + make_interval(secs => s.ttl_seconds) AS "expires_date?",
+                s.session_key_id
+        "#,
+        username,
+        session_id,
+    )
     .fetch_one(conn)
     .await?;
 
-    Ok(sessions)
+    session.open(keyring)
 }
 
-pub async fn add_session<'a, E>(conn: E, username: &str, session: &str) -> Result<Uuid, sqlx::Error>
+/// Adds a session for the user. The session's ID is generated up front so that
+/// an encrypted session can be bound to the row it's stored in.
+pub async fn add_session<'a, E>(
+    conn: E,
+    user_id: &Uuid,
+    session: &str,
+    device_label: Option<&str>,
+    ttl_seconds: Option<i32>,
+    keyring: Option<&Keyring>,
+) -> Result<Uuid, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
+    let id = Uuid::new_v4();
+    let (session, key_id) = crypto::seal(keyring, session, session_owner(user_id, &id))?;
     let result = query!(
         r#"
             INSERT INTO user_sessions
-                (user_id, session)
+                (id, user_id, session, device_label, ttl_seconds, session_key_id)
             VALUES
-                ((SELECT id FROM users WHERE username = $1), $2)
+                ($1, $2, $3, $4, $5, $6)
             RETURNING id
         "#,
-        username,
+        id,
+        user_id,
         session,
+        device_label,
+        ttl_seconds,
+        key_id,
     )
     .fetch_one(conn)
     .await?;
@@ -85,24 +242,63 @@ where
     Ok(result.id)
 }
 
+/// Replaces the contents of a session. The device label and TTL are only
+/// changed if new values are passed in; passing `Some(None)` for the TTL
+/// removes it so the session never expires.
 pub async fn update_session<'a, E>(
     conn: E,
-    username: &str,
+    user_id: &Uuid,
+    session_id: &Uuid,
     session: &str,
+    device_label: Option<&str>,
+    ttl_seconds: Option<Option<i32>>,
+    keyring: Option<&Keyring>,
 ) -> Result<u64, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
+    let (session, key_id) = crypto::seal(keyring, session, session_owner(user_id, session_id))?;
     let result = query!(
         r#"
             UPDATE ONLY user_sessions
-            SET session = $2
-            FROM users
-            WHERE user_sessions.user_id = users.id
-            AND users.username = $1
+            SET session = $3,
+                session_key_id = $6,
+                device_label = COALESCE($4, user_sessions.device_label),
+                ttl_seconds = CASE WHEN $7 THEN $5 ELSE user_sessions.ttl_seconds END,
+                last_access_date = now()
+            WHERE user_sessions.user_id = $1
+            AND user_sessions.id = $2
         "#,
-        username,
+        user_id,
+        session_id,
         session,
+        device_label,
+        ttl_seconds.flatten(),
+        key_id,
+        ttl_seconds.is_some(),
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_session<'a, E>(
+    conn: E,
+    username: &str,
+    session_id: &Uuid,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM user_sessions
+            WHERE user_sessions.user_id = (SELECT id FROM users WHERE username = $1)
+            AND user_sessions.id = $2
+        "#,
+        username,
+        session_id,
     )
     .execute(conn)
     .await?;
@@ -110,7 +306,7 @@ where
     Ok(result.rows_affected())
 }
 
-pub async fn delete_session<'a, E>(conn: E, username: &str) -> Result<u64, sqlx::Error>
+pub async fn delete_sessions<'a, E>(conn: E, username: &str) -> Result<u64, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
@@ -126,3 +322,88 @@ where
 
     Ok(result.rows_affected())
 }
+
+/// Deletes every session that has gone unused for longer than its TTL.
+pub async fn purge_expired_sessions<'a, E>(conn: E) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM user_sessions
+            WHERE ttl_seconds IS NOT NULL
+            AND last_access_date + make_interval(secs => ttl_seconds) <= now()
+        "#,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// A stored session that isn't encrypted with the current key.
+pub struct StaleSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub session: String,
+    pub session_key_id: Option<String>,
+}
+
+/// Returns up to `limit` sessions that are stored in plaintext or encrypted
+/// with a key other than the current one.
+pub async fn list_stale_sessions<'a, E>(
+    conn: E,
+    current_key_id: &str,
+    limit: i64,
+) -> Result<Vec<StaleSession>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query_as!(
+        StaleSession,
+        r#"
+            SELECT id, user_id, session, session_key_id
+            FROM user_sessions
+            WHERE session_key_id IS DISTINCT FROM $1
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        "#,
+        current_key_id,
+        limit,
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Re-encrypts a single session under the keyring's current key.
+pub async fn reencrypt_session<'a, E>(
+    conn: E,
+    stale: StaleSession,
+    keyring: &Keyring,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let owner = session_owner(&stale.user_id, &stale.id);
+    let plaintext = crypto::open(
+        Some(keyring),
+        stale.session_key_id.as_deref(),
+        stale.session,
+        owner,
+    )?;
+    let (session, key_id) = crypto::seal(Some(keyring), &plaintext, owner)?;
+    let result = query!(
+        r#"
+            UPDATE user_sessions
+            SET session = $2, session_key_id = $3
+            WHERE id = $1
+        "#,
+        stale.id,
+        session,
+        key_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -1,17 +1,130 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use debuff::{apps::IntegrationData, containers::Image, tools::Tool, user::User};
-use sqlx::{self, query};
+use serde::{Deserialize, Serialize};
+use sqlx::{self, query, query_as, types::Uuid};
+use utoipa::{IntoParams, ToSchema};
 
-/// Returns a listing of tools added by the user.
-pub async fn get_user_tools<'a, E>(conn: E, username: &str) -> Result<Vec<Tool>, sqlx::Error>
+use super::analyses::{escape_like, UsageWindow};
+
+/// Filters that can be applied when listing a user's tools.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ToolFilter {
+    /// Only include tools with names containing this string. Case insensitive.
+    pub name: Option<String>,
+
+    /// Only include interactive tools if true, or non-interactive tools if false.
+    pub interactive: Option<bool>,
+
+    /// Only include tools that need a GPU if true, or tools that don't if false.
+    pub gpu_enabled: Option<bool>,
+
+    /// Only include restricted tools if true, or unrestricted tools if false.
+    pub restricted: Option<bool>,
+}
+
+/// A tool along with whether its container image is deprecated, which isn't
+/// part of the Tool message.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct ToolDetail {
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub tool: Tool,
+
+    /// Whether the tool's container image has been deprecated.
+    pub container_image_deprecated: bool,
+}
+
+/// A list of tools.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Tools {
+    pub tools: Vec<ToolDetail>,
+}
+
+/// A tool as it's stored in the database, joined with its container image and
+/// integration data.
+struct ToolRecord {
+    id: Uuid,
+    name: String,
+    description: Option<String>,
+    version: String,
+    attribution: Option<String>,
+    time_limit_seconds: i32,
+    restricted: bool,
+    interactive: bool,
+    gpu_enabled: bool,
+    user_id: Uuid,
+    username: String,
+    integration_data_id: Uuid,
+    integrator_name: String,
+    integrator_email: String,
+    container_image_id: Uuid,
+    container_image_name: String,
+    container_image_tag: String,
+    container_image_url: Option<String>,
+    container_image_osg_image_path: Option<String>,
+    container_image_deprecated: bool,
+}
+
+impl ToolRecord {
+    fn into_detail(self) -> ToolDetail {
+        let tool = Tool {
+            uuid: self.id.to_string(),
+            name: self.name,
+            version: self.version,
+            attribution: self.attribution.unwrap_or_default(),
+            description: self.description.unwrap_or_default(),
+            time_limit_seconds: self.time_limit_seconds,
+            restricted: self.restricted,
+            interactive: self.interactive,
+            gpu_enabled: self.gpu_enabled,
+            container_image: Some(Image {
+                id: self.container_image_id.to_string(),
+                name: self.container_image_name,
+                tag: self.container_image_tag,
+                url: self.container_image_url.unwrap_or_default(),
+                osg_image_path: self.container_image_osg_image_path.unwrap_or_default(),
+            }),
+            integration_data: Some(IntegrationData {
+                id: self.integration_data_id.to_string(),
+                integrator_name: self.integrator_name,
+                integrator_email: self.integrator_email,
+                user: Some(User {
+                    uuid: self.user_id.to_string(),
+                    username: self.username,
+                }),
+            }),
+        };
+
+        ToolDetail {
+            tool,
+            container_image_deprecated: self.container_image_deprecated,
+        }
+    }
+}
+
+/// Returns a listing of tools added by the user that match the filter, sorted
+/// by name and version.
+pub async fn get_user_tools<'a, E>(
+    conn: E,
+    username: &str,
+    filter: &ToolFilter,
+) -> Result<Vec<ToolDetail>, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
-    let records = query!(
+    let name = filter
+        .name
+        .as_deref()
+        .map(|n| format!("%{}%", escape_like(n)));
+
+    let records = query_as!(
+        ToolRecord,
         r#"
             SELECT
                 t.id,
                 t.name,
-                t.location,
                 t.description,
                 t.version,
                 t.attribution,
@@ -28,68 +141,74 @@ where
                 c.name AS container_image_name,
                 c.tag AS container_image_tag,
                 c.url as container_image_url,
-                c.deprecated as container_image_deprecated,
-                c.osg_image_path as container_image_osg_image_path                
+                c.osg_image_path as container_image_osg_image_path,
+                c.deprecated as container_image_deprecated
             FROM tools t
             JOIN container_images c ON t.container_images_id = c.id
             JOIN integration_data i ON t.integration_data_id = i.id
             JOIN users u ON i.user_id = u.id
             WHERE
                 u.username = $1
+            AND ($2::text IS NULL OR t.name ILIKE $2)
+            AND ($3::boolean IS NULL OR t.interactive = $3)
+            AND ($4::boolean IS NULL OR t.gpu_enabled = $4)
+            AND ($5::boolean IS NULL OR t.restricted = $5)
+            ORDER BY t.name, t.version, t.id
         "#,
-        username
+        username,
+        name,
+        filter.interactive,
+        filter.gpu_enabled,
+        filter.restricted,
     )
     .fetch_all(conn)
     .await?;
 
-    Ok(records
-        .into_iter()
-        .map(|r| Tool {
-            uuid: r.id.to_string(),
-            name: r.name,
-            version: r.version,
-            attribution: r.attribution.unwrap_or_default(),
-            description: r.description.unwrap_or_default(),
-            time_limit_seconds: r.time_limit_seconds,
-            restricted: r.restricted,
-            interactive: r.interactive,
-            gpu_enabled: r.gpu_enabled,
-            container_image: Some(Image {
-                id: r.container_image_id.to_string(),
-                name: r.container_image_name,
-                tag: r.container_image_tag,
-                url: r.container_image_url.unwrap_or_default(),
-                osg_image_path: r.container_image_osg_image_path.unwrap_or_default(),
-            }),
-            integration_data: Some(IntegrationData {
-                id: r.integration_data_id.to_string(),
-                integrator_name: r.integrator_name,
-                integrator_email: r.integrator_email,
-                user: Some(User {
-                    uuid: r.user_id.to_string(),
-                    username: r.username,
-                }),
-            }),
-        })
-        .collect())
+    Ok(records.into_iter().map(|r| r.into_detail()).collect())
+}
+
+/// Returns whether the user added the tool.
+pub async fn user_tool_exists<'a, E>(
+    conn: E,
+    username: &str,
+    tool_id: &Uuid,
+) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            SELECT COUNT(*) > 0 AS tool_exists
+            FROM tools t
+            JOIN integration_data i ON t.integration_data_id = i.id
+            JOIN users u ON i.user_id = u.id
+            WHERE u.username = $1
+            AND t.id = $2
+        "#,
+        username,
+        tool_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.tool_exists.unwrap_or(false))
 }
 
 /// Returns a single tool owned by a user.
 pub async fn get_user_tool<'a, E>(
     conn: E,
     username: &str,
-    tool_id: &uuid::Uuid,
-) -> Result<Tool, sqlx::Error>
+    tool_id: &Uuid,
+) -> Result<ToolDetail, sqlx::Error>
 where
     E: sqlx::Executor<'a, Database = sqlx::Postgres>,
 {
-    let record = query!(
+    let record = query_as!(
+        ToolRecord,
         r#"
-            
             SELECT
                 t.id,
                 t.name,
-                t.location,
                 t.description,
                 t.version,
                 t.attribution,
@@ -106,8 +225,8 @@ where
                 c.name AS container_image_name,
                 c.tag AS container_image_tag,
                 c.url as container_image_url,
-                c.deprecated as container_image_deprecated,
-                c.osg_image_path as container_image_osg_image_path                
+                c.osg_image_path as container_image_osg_image_path,
+                c.deprecated as container_image_deprecated
             FROM tools t
             JOIN container_images c ON t.container_images_id = c.id
             JOIN integration_data i ON t.integration_data_id = i.id
@@ -121,31 +240,451 @@ where
     .fetch_one(conn)
     .await?;
 
-    Ok(Tool {
-        uuid: record.id.to_string(),
-        name: record.name,
-        version: record.version,
-        attribution: record.attribution.unwrap_or_default(),
-        description: record.description.unwrap_or_default(),
-        time_limit_seconds: record.time_limit_seconds,
-        restricted: record.restricted,
-        interactive: record.interactive,
-        gpu_enabled: record.gpu_enabled,
-        integration_data: Some(IntegrationData {
-            id: record.integration_data_id.to_string(),
-            integrator_name: record.integrator_name,
-            integrator_email: record.integrator_email,
-            user: Some(User {
-                uuid: record.user_id.to_string(),
-                username: record.username,
-            }),
-        }),
-        container_image: Some(Image {
-            id: record.container_image_id.to_string(),
-            name: record.container_image_name,
-            tag: record.container_image_tag,
-            url: record.container_image_url.unwrap_or_default(),
-            osg_image_path: record.container_image_osg_image_path.unwrap_or_default(),
-        }),
-    })
+    Ok(record.into_detail())
+}
+
+/// Returns whether a tool other than the excluded one already has the name and
+/// version.
+pub async fn tool_name_version_exists<'a, E>(
+    conn: E,
+    name: &str,
+    version: &str,
+    exclude_id: Option<&Uuid>,
+) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            SELECT COUNT(*) > 0 AS tool_exists
+            FROM tools
+            WHERE name = $1
+            AND version = $2
+            AND ($3::uuid IS NULL OR id != $3)
+        "#,
+        name,
+        version,
+        exclude_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.tool_exists.unwrap_or(false))
+}
+
+/// Makes other transactions adding a container image with the same name and tag
+/// wait until this one ends. The unique constraint on container images treats
+/// null OSG image paths as distinct, so it doesn't stop two requests from both
+/// adding the same image.
+pub async fn lock_container_image<'a, E>(conn: E, image: &Image) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query!(
+        r#"
+            SELECT pg_advisory_xact_lock(hashtext('container_images:' || $1 || ':' || $2))
+        "#,
+        image.name,
+        image.tag,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the ID of the container image with the name, tag and OSG image
+/// path, adding it if it doesn't exist yet. Empty strings are stored as nulls.
+/// Call `lock_container_image` in the same transaction first.
+pub async fn find_or_add_container_image<'a, E>(
+    conn: E,
+    image: &Image,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let url = Some(image.url.as_str()).filter(|u| !u.is_empty());
+    let osg_image_path = Some(image.osg_image_path.as_str()).filter(|p| !p.is_empty());
+
+    let result = query!(
+        r#"
+            WITH existing AS (
+                SELECT id
+                FROM container_images
+                WHERE name = $1
+                AND tag = $2
+                AND osg_image_path IS NOT DISTINCT FROM $4
+                LIMIT 1
+            ), inserted AS (
+                INSERT INTO container_images (name, tag, url, osg_image_path)
+                SELECT $1, $2, $3, $4
+                WHERE NOT EXISTS (SELECT 1 FROM existing)
+                RETURNING id
+            )
+            SELECT id AS "id!" FROM existing
+            UNION ALL
+            SELECT id AS "id!" FROM inserted
+        "#,
+        image.name,
+        image.tag,
+        url,
+        osg_image_path,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.id)
+}
+
+/// Returns the ID of the user's integration data record with the name and email,
+/// adding one if the user doesn't have a record with both of them yet.
+pub async fn find_or_add_integration_data<'a, E>(
+    conn: E,
+    username: &str,
+    integrator_name: &str,
+    integrator_email: &str,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            WITH u AS (
+                SELECT id FROM users WHERE username = $1
+            ), existing AS (
+                SELECT i.id
+                FROM integration_data i
+                JOIN u ON i.user_id = u.id
+                WHERE i.integrator_name = $2
+                AND i.integrator_email = $3
+                LIMIT 1
+            ), inserted AS (
+                INSERT INTO integration_data (integrator_name, integrator_email, user_id)
+                SELECT $2, $3, u.id FROM u
+                WHERE NOT EXISTS (SELECT 1 FROM existing)
+                RETURNING id
+            )
+            SELECT id AS "id!" FROM existing
+            UNION ALL
+            SELECT id AS "id!" FROM inserted
+        "#,
+        username,
+        integrator_name,
+        integrator_email,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.id)
+}
+
+/// Adds a tool. The tool's UUID, container image and integration data in the
+/// Tool are ignored in favor of the IDs passed in.
+pub async fn add_tool<'a, E>(
+    conn: E,
+    tool: &Tool,
+    container_image_id: &Uuid,
+    integration_data_id: &Uuid,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            INSERT INTO tools (
+                name,
+                description,
+                version,
+                attribution,
+                time_limit_seconds,
+                restricted,
+                interactive,
+                gpu_enabled,
+                tool_type_id,
+                container_images_id,
+                integration_data_id
+            )
+            VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8,
+                (SELECT id FROM tool_types WHERE name = CASE WHEN $7 THEN 'interactive' ELSE 'executable' END),
+                $9,
+                $10
+            )
+            RETURNING id
+        "#,
+        tool.name,
+        tool.description,
+        tool.version,
+        tool.attribution,
+        tool.time_limit_seconds,
+        tool.restricted,
+        tool.interactive,
+        tool.gpu_enabled,
+        container_image_id,
+        integration_data_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.id)
+}
+
+/// Replaces the settings and container image of a tool. The integration data
+/// isn't changed.
+pub async fn update_tool<'a, E>(
+    conn: E,
+    tool_id: &Uuid,
+    tool: &Tool,
+    container_image_id: &Uuid,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            UPDATE tools
+            SET name = $2,
+                description = $3,
+                version = $4,
+                attribution = $5,
+                time_limit_seconds = $6,
+                restricted = $7,
+                interactive = $8,
+                gpu_enabled = $9,
+                tool_type_id = (SELECT id FROM tool_types WHERE name = CASE WHEN $8 THEN 'interactive' ELSE 'executable' END),
+                container_images_id = $10
+            WHERE id = $1
+        "#,
+        tool_id,
+        tool.name,
+        tool.description,
+        tool.version,
+        tool.attribution,
+        tool.time_limit_seconds,
+        tool.restricted,
+        tool.interactive,
+        tool.gpu_enabled,
+        container_image_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Returns whether anything refers to the tool: a task, whether or not an app
+/// version has a step that runs it, a tool request or container settings.
+pub async fn tool_in_use<'a, E>(conn: E, tool_id: &Uuid) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            SELECT
+                EXISTS (SELECT 1 FROM tasks WHERE tool_id = $1)
+                OR EXISTS (SELECT 1 FROM tool_requests WHERE tool_id = $1)
+                OR EXISTS (SELECT 1 FROM container_settings WHERE tools_id = $1)
+                AS "in_use!"
+        "#,
+        tool_id,
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(result.in_use)
+}
+
+/// Deletes the tool. Fails with a foreign key violation if anything still
+/// refers to it.
+pub async fn delete_tool<'a, E>(conn: E, tool_id: &Uuid) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            DELETE FROM tools
+            WHERE id = $1
+        "#,
+        tool_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// The number of a tool's analyses with one status.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ToolStatusCount {
+    pub status: String,
+    pub analyses: i64,
+
+    /// The fraction of the tool's analyses in the window with the status.
+    pub rate: f64,
+}
+
+/// How much a tool was used during a window of time.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ToolUsage {
+    pub tool_id: Uuid,
+
+    /// The start of the window, if one was requested.
+    pub from: Option<DateTime<Utc>>,
+
+    /// The end of the window, if one was requested.
+    pub to: Option<DateTime<Utc>>,
+
+    /// The number of analyses that used the tool and started during the window.
+    pub total_analyses: i64,
+
+    /// The number of distinct users who ran those analyses.
+    pub distinct_users: i64,
+
+    /// The fraction of the analyses that completed.
+    pub success_rate: f64,
+
+    /// The fraction of the analyses that failed.
+    pub failure_rate: f64,
+
+    /// The analyses grouped by status, most common first.
+    pub by_status: Vec<ToolStatusCount>,
+}
+
+/// Returns the number of analyses that used the tool and started during the
+/// window by status, most common first, along with the number of distinct
+/// users who ran them. The window defaults to the 30 days before its end,
+/// which defaults to now. The rates are left at zero.
+pub async fn count_tool_usage<'a, E>(
+    conn: E,
+    tool_id: &Uuid,
+    window: &UsageWindow,
+    tz: &Tz,
+) -> Result<(Vec<ToolStatusCount>, i64), sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let (from, to) = window.bounds(tz);
+    let results = query!(
+        r#"
+            WITH tool_app_versions AS (
+                SELECT DISTINCT tk.tool_id, s.app_version_id
+                FROM tasks tk
+                JOIN app_steps s ON s.task_id = tk.id
+            ), tool_jobs AS (
+                SELECT j.status, j.user_id
+                FROM jobs j
+                JOIN tool_app_versions tav ON j.app_version_id = tav.app_version_id
+                WHERE tav.tool_id = $1
+                AND j.start_date >= COALESCE(
+                    $2,
+                    CASE WHEN $4 THEN '-infinity'::timestamp
+                    ELSE COALESCE($3, now()::timestamp) - interval '30 days' END
+                )
+                AND j.start_date < COALESCE($3, now()::timestamp)
+            )
+            SELECT
+                status,
+                COUNT(*) AS "analyses!",
+                (SELECT COUNT(DISTINCT user_id) FROM tool_jobs) AS "users!"
+            FROM tool_jobs
+            GROUP BY status
+            ORDER BY 2 DESC, status
+        "#,
+        tool_id,
+        from,
+        to,
+        window.all_time,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    let distinct_users = results.first().map(|r| r.users).unwrap_or_default();
+    let by_status = results
+        .into_iter()
+        .map(|r| ToolStatusCount {
+            status: r.status,
+            analyses: r.analyses,
+            rate: 0.0,
+        })
+        .collect();
+
+    Ok((by_status, distinct_users))
+}
+
+impl ToolUsage {
+    /// Builds the usage summary from the status counts, filling in the totals
+    /// and rates.
+    pub fn new(
+        tool_id: Uuid,
+        window: &UsageWindow,
+        mut by_status: Vec<ToolStatusCount>,
+        distinct_users: i64,
+    ) -> Self {
+        let total_analyses: i64 = by_status.iter().map(|s| s.analyses).sum();
+        let rate = |n: i64| {
+            if total_analyses == 0 {
+                0.0
+            } else {
+                n as f64 / total_analyses as f64
+            }
+        };
+
+        for s in by_status.iter_mut() {
+            s.rate = rate(s.analyses);
+        }
+        let status_rate = |status: &str| {
+            by_status
+                .iter()
+                .find(|s| s.status == status)
+                .map(|s| s.rate)
+                .unwrap_or_default()
+        };
+
+        ToolUsage {
+            tool_id,
+            from: window.from,
+            to: window.to,
+            total_analyses,
+            distinct_users,
+            success_rate: status_rate("Completed"),
+            failure_rate: status_rate("Failed"),
+            by_status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn count(status: &str, analyses: i64) -> ToolStatusCount {
+        ToolStatusCount {
+            status: status.to_owned(),
+            analyses,
+            rate: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_tool_usage_rates() {
+        let usage = ToolUsage::new(
+            Uuid::nil(),
+            &UsageWindow::default(),
+            vec![count("Completed", 6), count("Failed", 3), count("Canceled", 1)],
+            4,
+        );
+        assert_eq!(usage.total_analyses, 10);
+        assert_eq!(usage.distinct_users, 4);
+        assert_eq!(usage.success_rate, 0.6);
+        assert_eq!(usage.failure_rate, 0.3);
+        assert_eq!(usage.by_status[2].rate, 0.1);
+    }
+
+    #[test]
+    fn test_tool_usage_empty() {
+        let usage = ToolUsage::new(Uuid::nil(), &UsageWindow::default(), vec![], 0);
+        assert_eq!(usage.total_analyses, 0);
+        assert_eq!(usage.success_rate, 0.0);
+        assert_eq!(usage.failure_rate, 0.0);
+    }
 }
@@ -1,6 +1,13 @@
-use sqlx::{query, types::Uuid, PgPool};
+use sqlx::{query, types::Uuid};
 
-pub async fn user_id(conn: &PgPool, username: &str) -> Result<Uuid, sqlx::Error> {
+/// How long a user's last login date is left alone before it's updated again,
+/// so that active users don't cause a write on every request.
+const LOGIN_UPDATE_INTERVAL_MINUTES: i32 = 15;
+
+pub async fn user_id<'a, E>(conn: E, username: &str) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
     let result = query!(r#"select id from users where username = $1"#, username,)
         .fetch_one(conn)
         .await?;
@@ -35,3 +42,76 @@ where
 
     Ok(result.has_user.unwrap_or(false))
 }
+
+/// Adds the user if they're not in the database yet. Safe to call from
+/// concurrent requests for the same user. Returns whether the user was added.
+pub async fn provision_user<'a, E>(conn: E, username: &str) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let result = query!(
+        r#"
+            INSERT INTO users (username)
+            VALUES ($1)
+            ON CONFLICT (username) DO NOTHING
+        "#,
+        username
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Records that the user logged in, setting their first login date if it
+/// hasn't been set yet. The last login date is only updated if it's more than
+/// LOGIN_UPDATE_INTERVAL_MINUTES old.
+pub async fn record_login<'a, E>(conn: E, username: &str) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    query!(
+        r#"
+            UPDATE users
+            SET first_login_date = COALESCE(first_login_date, now()),
+                last_login_date = now()
+            WHERE username = $1
+            AND (
+                last_login_date IS NULL
+                OR last_login_date < now() - make_interval(mins => $2)
+            )
+        "#,
+        username,
+        LOGIN_UPDATE_INTERVAL_MINUTES,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the IDs and usernames of the users with any of the IDs or
+/// usernames, in one query.
+pub async fn lookup_users<'a, E>(
+    conn: E,
+    ids: &[Uuid],
+    usernames: &[String],
+) -> Result<Vec<(Uuid, String)>, sqlx::Error>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+{
+    let results = query!(
+        r#"
+            SELECT id, username
+            FROM users
+            WHERE id = ANY($1)
+            OR username = ANY($2)
+        "#,
+        ids,
+        usernames,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results.into_iter().map(|r| (r.id, r.username)).collect())
+}
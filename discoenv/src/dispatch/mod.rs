@@ -0,0 +1,194 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::types::{JsonValue, Uuid};
+use std::fmt;
+#[cfg(test)]
+use std::sync::Mutex;
+
+use crate::errors::DiscoError;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DispatchError {
+    #[error("error encoding the request: {0}")]
+    Encode(String),
+
+    #[error("error publishing the request: {0}")]
+    Publish(String),
+
+    #[error("invalid submission: {0}")]
+    InvalidSubmission(String),
+}
+
+impl From<DispatchError> for DiscoError {
+    fn from(e: DispatchError) -> Self {
+        match e {
+            DispatchError::InvalidSubmission(m) => DiscoError::BadRequest(m),
+            _ => DiscoError::Internal(e.to_string()),
+        }
+    }
+}
+
+/// Asks the services that run analyses to stop one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CancelRequest {
+    pub analysis_id: Uuid,
+
+    /// The user that requested the cancellation.
+    pub username: String,
+
+    /// The IDs the execution system uses for the analysis's steps.
+    pub external_ids: Vec<String>,
+}
+
+/// Asks the services that run analyses to launch a new one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubmitRequest {
+    /// The user the analysis is launched for.
+    pub username: String,
+
+    /// The analysis this one is a relaunch of, if any.
+    pub relaunch_of: Option<Uuid>,
+
+    /// The submission to launch, in the same format the apps service accepts.
+    pub submission: JsonValue,
+}
+
+/// Sends analysis requests on to the services that run them.
+#[async_trait]
+pub trait JobDispatcher: fmt::Debug + Send + Sync {
+    async fn cancel(&self, request: &CancelRequest) -> Result<(), DispatchError>;
+
+    async fn submit(&self, request: &SubmitRequest) -> Result<(), DispatchError>;
+}
+
+/// Returns a copy of a stored submission renamed for relaunching, along with
+/// the app version it will launch.
+pub fn relaunch_submission(
+    submission: &JsonValue,
+    name: &str,
+    app_version_id: &Uuid,
+) -> Result<JsonValue, DispatchError> {
+    let mut submission = submission.clone();
+    let fields = submission.as_object_mut().ok_or_else(|| {
+        DispatchError::InvalidSubmission("the stored submission isn't an object".to_owned())
+    })?;
+
+    fields.insert("name".to_owned(), JsonValue::String(name.to_owned()));
+    fields.insert(
+        "app_version_id".to_owned(),
+        JsonValue::String(app_version_id.to_string()),
+    );
+
+    Ok(submission)
+}
+
+/// Publishes analysis requests as JSON messages on NATS subjects.
+#[derive(Debug)]
+pub struct NatsDispatcher {
+    client: async_nats::Client,
+    cancel_subject: String,
+    submit_subject: String,
+}
+
+impl NatsDispatcher {
+    pub fn new(client: async_nats::Client, cancel_subject: &str, submit_subject: &str) -> Self {
+        NatsDispatcher {
+            client,
+            cancel_subject: cancel_subject.to_owned(),
+            submit_subject: submit_subject.to_owned(),
+        }
+    }
+
+    async fn publish<T: Serialize + Sync>(
+        &self,
+        subject: &str,
+        msg: &T,
+    ) -> Result<(), DispatchError> {
+        let payload = serde_json::to_vec(msg).map_err(|e| DispatchError::Encode(e.to_string()))?;
+
+        self.client
+            .publish(subject.to_owned(), payload.into())
+            .await
+            .map_err(|e| DispatchError::Publish(e.to_string()))?;
+
+        // Make sure the message made it to the server before reporting success.
+        self.client
+            .flush()
+            .await
+            .map_err(|e| DispatchError::Publish(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl JobDispatcher for NatsDispatcher {
+    async fn cancel(&self, request: &CancelRequest) -> Result<(), DispatchError> {
+        self.publish(&self.cancel_subject, request).await
+    }
+
+    async fn submit(&self, request: &SubmitRequest) -> Result<(), DispatchError> {
+        self.publish(&self.submit_subject, request).await
+    }
+}
+
+/// Keeps the requests it's sent in memory instead of sending them anywhere.
+/// Only built for tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakeDispatcher {
+    cancels: Mutex<Vec<CancelRequest>>,
+    submissions: Mutex<Vec<SubmitRequest>>,
+}
+
+#[cfg(test)]
+impl FakeDispatcher {
+    /// The cancellation requests sent so far.
+    pub fn cancels(&self) -> Vec<CancelRequest> {
+        self.cancels.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    /// The submissions sent so far.
+    pub fn submissions(&self) -> Vec<SubmitRequest> {
+        self.submissions.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl JobDispatcher for FakeDispatcher {
+    async fn cancel(&self, request: &CancelRequest) -> Result<(), DispatchError> {
+        if let Ok(mut c) = self.cancels.lock() {
+            c.push(request.clone());
+        }
+        Ok(())
+    }
+
+    async fn submit(&self, request: &SubmitRequest) -> Result<(), DispatchError> {
+        if let Ok(mut s) = self.submissions.lock() {
+            s.push(request.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_relaunch_submission() {
+        let app_version_id = Uuid::new_v4();
+        let stored = json!({
+            "name": "word count",
+            "app_version_id": "old",
+            "config": {"step_1_input": "/iplant/home/ipcdev/in.txt"},
+        });
+
+        let relaunched = relaunch_submission(&stored, "word count again", &app_version_id).unwrap();
+        assert_eq!(relaunched["name"], "word count again");
+        assert_eq!(relaunched["app_version_id"], app_version_id.to_string());
+        assert_eq!(relaunched["config"], stored["config"]);
+
+        assert!(relaunch_submission(&json!([]), "x", &app_version_id).is_err());
+    }
+}
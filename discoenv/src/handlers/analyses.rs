@@ -1,29 +1,66 @@
 use axum::{
-    extract::{Extension, Json, State},
-    response,
+    body::{Bytes, StreamBody},
+    extract::{Extension, Json, Path, Query, State},
+    http::{header, HeaderMap},
+    response::{
+        self,
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
 };
+use cached::proc_macro::cached;
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::{JsonValue, Uuid},
+    PgPool,
+};
+use std::collections::VecDeque;
 use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use utoipa::ToSchema;
 
-use crate::db::analyses;
+use crate::db::analyses::{
+    self, AdminAnalysisSummary, AnalysisCursor, AnalysisDetail, AnalysisFilter, AnalysisListing,
+    AnalysisPage, AnalysisStatusEvent, AnalysisSummary, ListedAnalysis, RelaunchSource,
+    SubdomainAnalysis, TimeLimitExtensionCount, TopUsersParams, UsageGroup, UsageWindow,
+};
+use crate::db::{annotations, users};
 
 use crate::app_state::DiscoenvState;
+use crate::auth::middleware::is_admin;
+use debuff::analysis::Analysis;
+use pbjson_types::Timestamp;
+
+use crate::dispatch::{self, CancelRequest, JobDispatcher, SubmitRequest};
 use crate::errors::DiscoError;
-use debuff::analysis;
 
 use super::common;
 use crate::auth::UserInfo;
 
 /// List the analyses for the logged-in user
 ///
-/// Returns a list of analyses for the user.
+/// Returns a page of the user's analyses matching the filters, along with the total number of
+/// matching analyses and a cursor for the next page. Deleted analyses are left out unless
+/// `deleted=true` is passed in. Pass `top_level=true` to leave out the analyses launched by
+/// batch analyses; batch analyses include the number of their undeleted children with each
+/// status.
+/// Each analysis includes the user's tags on it, and `tags` filters on them.
+///
+/// Timestamps are RFC 3339 strings in UTC. The `start_date`, `end_date` and `planned_end_date`
+/// of an analysis, and the `integration_date` and `edited_date` of its app version, are left
+/// out when they aren't set, e.g. a running analysis has no `end_date`.
 #[utoipa::path(
     get,
     path = "/analyses",
+    params(AnalysisFilter, AnalysisPage),
     security(
       ("api_key" = []),  
     ),
     responses(
-        (status = 200, description = "Lists all of a user's analyses", body = Bags),
+        (status = 200, description = "Lists a page of the user's analyses", body = AnalysisListing),
         (status = 400, description = "Bad request.", 
             body = DiscoError,
             example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
@@ -39,13 +76,1318 @@ use crate::auth::UserInfo;
 pub async fn get_user_analyses(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
-) -> response::Result<Json<Vec<analysis::Analysis>>, DiscoError> {
+    Query(filter): Query<AnalysisFilter>,
+    Query(page): Query<AnalysisPage>,
+) -> response::Result<Json<AnalysisListing>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let retval = list_analyses(&mut tx, &user, &filter, &page, &state.db_timezone).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Returns a page of the user's analyses along with the status counts of the
+/// children of any batch analyses on the page.
+async fn list_analyses(
+    conn: &mut sqlx::PgConnection,
+    user: &str,
+    filter: &AnalysisFilter,
+    page: &AnalysisPage,
+    tz: &Tz,
+) -> Result<AnalysisListing, DiscoError> {
+    let cursor = match &page.cursor {
+        Some(c) => Some(AnalysisCursor::decode(c).ok_or_else(|| {
+            DiscoError::ParameterInvalid("cursor is not valid".to_owned())
+        })?),
+        None => None,
+    };
+
+    if let Some(c) = &cursor {
+        if !c.matches(page) {
+            return Err(DiscoError::ParameterInvalid(
+                "cursor was created with a different sort_field or sort_order".to_owned(),
+            ));
+        }
+    }
+
+    let total = analyses::count_user_analyses(&mut *conn, user, filter, tz).await?;
+    let (list, next) =
+        analyses::get_user_analyses(&mut *conn, user, filter, page, cursor.as_ref(), tz).await?;
+
+    let ids: Vec<Uuid> = list.iter().filter_map(|a| a.id.parse().ok()).collect();
+    let mut counts = analyses::count_children(&mut *conn, &ids).await?;
+    let mut tags = annotations::get_analyses_tags(&mut *conn, user, &ids).await?;
+
+    Ok(AnalysisListing {
+        analyses: list
+            .into_iter()
+            .map(|a| {
+                let id = a.id.parse::<Uuid>().ok();
+                ListedAnalysis {
+                    child_counts: id.and_then(|id| counts.remove(&id)),
+                    tags: id.and_then(|id| tags.remove(&id)).unwrap_or_default(),
+                    analysis: a,
+                }
+            })
+            .collect(),
+        total,
+        next_cursor: next.map(|c| c.encode()),
+    })
+}
+
+/// Get a single analysis owned by the logged-in user
+///
+/// Returns the analysis along with its steps, the submission it was launched with, the
+/// user's tags and notes, and its child analyses and the number of undeleted ones with each
+/// status if it's a batch analysis. Timestamps that aren't set are left out, as in
+/// the listing.
+#[utoipa::path(
+    get,
+    path = "/analyses/{analysis_id}",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+    ),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The analysis", body = AnalysisDetail),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_user_analysis(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+) -> response::Result<Json<AnalysisDetail>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !analyses::analysis_exists(&mut tx, &user, &analysis_id).await? {
+        return Err(DiscoError::NotFound(format!("analysis {} was not found", analysis_id)));
+    }
+
+    let tz = &state.db_timezone;
+    let analysis = analyses::get_user_analysis(&mut tx, &user, &analysis_id, tz).await?;
+    let steps = analyses::get_analysis_steps(&mut tx, &analysis_id, tz).await?;
+    let submission = analyses::get_analysis_submission(&mut tx, &analysis_id).await?;
+    let children = analyses::get_child_analyses(&mut tx, &user, &analysis_id, tz).await?;
+    let child_counts = analyses::count_children(&mut tx, &[analysis_id])
+        .await?
+        .remove(&analysis_id);
+    let tags = annotations::get_analyses_tags(&mut tx, &user, &[analysis_id])
+        .await?
+        .remove(&analysis_id)
+        .unwrap_or_default();
+    let notes = annotations::list_notes(&mut tx, &user, &analysis_id, tz).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(AnalysisDetail {
+        analysis,
+        steps,
+        submission,
+        children,
+        child_counts,
+        tags,
+        notes,
+    }))
+}
+
+/// List the analyses launched as part of one of the logged-in user's batch analyses
+///
+/// Takes the same filters, sorting and paging parameters as the analyses listing.
+#[utoipa::path(
+    get,
+    path = "/analyses/{analysis_id}/children",
+    params(
+        ("analysis_id" = String, Path, description = "The UUID of a batch analysis"),
+        AnalysisFilter,
+        AnalysisPage,
+    ),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "A page of the batch analysis's children", body = AnalysisListing),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_analysis_children(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+    Query(mut filter): Query<AnalysisFilter>,
+    Query(page): Query<AnalysisPage>,
+) -> response::Result<Json<AnalysisListing>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !analyses::analysis_exists(&mut tx, &user, &analysis_id).await? {
+        return Err(DiscoError::NotFound(format!("analysis {} was not found", analysis_id)));
+    }
+
+    filter.parent_id = Some(analysis_id);
+    filter.top_level = None;
+
+    let retval = list_analyses(&mut tx, &user, &filter, &page, &state.db_timezone).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// How many stored events are loaded at a time when a client resumes a stream.
+const EVENT_REPLAY_BATCH_SIZE: i64 = 500;
+
+/// Where a single client's event stream is at.
+struct EventStream {
+    pool: PgPool,
+    tz: Tz,
+    user_id: Uuid,
+    receiver: broadcast::Receiver<AnalysisStatusEvent>,
+    pending: VecDeque<AnalysisStatusEvent>,
+    last_id: i64,
+    replaying: bool,
+}
+
+impl EventStream {
+    /// Returns the next event for the client. Stored events are replayed first,
+    /// then live events are passed along. Returns None when the stream should
+    /// end, which tells the client to reconnect and resume from its last event.
+    async fn next(&mut self) -> Option<AnalysisStatusEvent> {
+        loop {
+            if let Some(ev) = self.pending.pop_front() {
+                self.last_id = ev.id;
+                return Some(ev);
+            }
+
+            if self.replaying {
+                match analyses::list_status_events_since(
+                    &self.pool,
+                    &self.user_id,
+                    self.last_id,
+                    EVENT_REPLAY_BATCH_SIZE,
+                    &self.tz,
+                )
+                .await
+                {
+                    Ok(events) if events.is_empty() => self.replaying = false,
+                    Ok(events) => self.pending.extend(events),
+                    Err(e) => {
+                        tracing::error!("error replaying analysis status events: {e}");
+                        return None;
+                    }
+                }
+                continue;
+            }
+
+            match self.receiver.recv().await {
+                // Events that were already replayed from the table are skipped. Event IDs are
+                // handed out in commit order, so nothing with a lower ID can show up later.
+                Ok(ev) if ev.user_id == self.user_id && ev.id > self.last_id => {
+                    self.pending.push_back(ev)
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("analysis event stream fell behind by {n} events, closing it");
+                    return None;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Stream status changes for the logged-in user's analyses
+///
+/// Sends a server-sent event named `status` each time one of the user's analyses changes
+/// status. Each event's ID is a sequence number; clients that reconnect with the
+/// `Last-Event-ID` header are sent the events they missed before the live ones.
+#[utoipa::path(
+    get,
+    path = "/analyses/events",
+    params(
+        ("Last-Event-ID" = Option<i64>, Header, description = "The ID of the last event the client received"),
+    ),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "A stream of status events", body = AnalysisStatusEvent, content_type = "text/event-stream"),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_analysis_events(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    headers: HeaderMap,
+) -> response::Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, DiscoError> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|s| s.trim().parse::<i64>().ok())
+                .ok_or_else(|| {
+                    DiscoError::ParameterInvalid("Last-Event-ID must be an integer".to_owned())
+                })
+        })
+        .transpose()?;
+
     let mut tx = state.pool.begin().await?;
-    let user = common::validate_username(
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+    tx.commit().await?;
+
+    let user_id = users::user_id(&state.pool, &user).await?;
+
+    // Subscribe before replaying so nothing is missed in between; anything seen
+    // twice is filtered out by ID.
+    let events = EventStream {
+        pool: state.pool.clone(),
+        tz: state.db_timezone,
+        user_id,
+        receiver: state.status_events.subscribe(),
+        pending: VecDeque::new(),
+        last_id: last_event_id.unwrap_or_default(),
+        replaying: last_event_id.is_some(),
+    };
+
+    let stream = stream::unfold(events, |mut events| async move {
+        let ev = events.next().await?;
+        let sse = Event::default()
+            .id(ev.id.to_string())
+            .event("status")
+            .json_data(&ev)
+            .map_err(axum::Error::new);
+        Some((sse, events))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Builds a usage summary for a single user, or for everyone if no username
+/// is passed in.
+pub(crate) async fn summarize_usage(
+    conn: &mut sqlx::PgConnection,
+    username: Option<&str>,
+    window: &UsageWindow,
+    tz: &Tz,
+) -> Result<AnalysisSummary, DiscoError> {
+    let by_status =
+        analyses::summarize_usage(&mut *conn, username, window, UsageGroup::Status, None, tz)
+            .await?;
+    let by_app =
+        analyses::summarize_usage(&mut *conn, username, window, UsageGroup::App, None, tz).await?;
+    let by_job_type =
+        analyses::summarize_usage(&mut *conn, username, window, UsageGroup::JobType, None, tz)
+            .await?;
+
+    Ok(AnalysisSummary {
+        from: window.from,
+        to: window.to,
+        total_analyses: by_status.iter().map(|b| b.analyses).sum(),
+        total_core_hours: by_status.iter().map(|b| b.core_hours).sum(),
+        by_status,
+        by_app,
+        by_job_type,
+    })
+}
+
+/// Summarize the logged-in user's analyses and resource usage
+///
+/// Counts the analyses that were running at some point during the window by status, app and
+/// job type, along with the CPU-core-hours reserved for them: the millicores reserved times
+/// how long each analysis ran within the window. The window covers the last 30 days unless
+/// `from` or `to` are passed in. Deleted analyses are included. Analyses that haven't started
+/// yet, such as Submitted or Queued ones, have no start date and are left out.
+#[utoipa::path(
+    get,
+    path = "/analyses/summary",
+    params(UsageWindow),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The user's usage summary", body = AnalysisSummary),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_analyses_summary(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Query(window): Query<UsageWindow>,
+) -> response::Result<Json<AnalysisSummary>, DiscoError> {
+    window.validate()?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let retval = summarize_usage(&mut tx, Some(&user), &window, &state.db_timezone).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Summarize analyses and resource usage across all users
+///
+/// The same summary as `/analyses/summary`, covering every user's analyses, along with the
+/// users that reserved the most CPU time during the window. Requires an admin entitlement.
+#[utoipa::path(
+    get,
+    path = "/admin/analyses/summary",
+    params(UsageWindow, TopUsersParams),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The usage summary for all users", body = AdminAnalysisSummary),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The user isn't an admin."),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "admin"
+)]
+pub async fn get_all_analyses_summary(
+    State(state): State<Arc<DiscoenvState>>,
+    Query(window): Query<UsageWindow>,
+    Query(top): Query<TopUsersParams>,
+) -> response::Result<Json<AdminAnalysisSummary>, DiscoError> {
+    window.validate()?;
+
+    let mut tx = state.pool.begin().await?;
+    let tz = &state.db_timezone;
+    let summary = summarize_usage(&mut tx, None, &window, tz).await?;
+    let top_users = analyses::summarize_usage(
         &mut tx,
-        &user_info.preferred_username.unwrap_or_default(),
+        None,
+        &window,
+        UsageGroup::User,
+        Some(top.top()),
+        tz,
+    )
+    .await?;
+
+    Ok(Json(AdminAnalysisSummary { summary, top_users }))
+}
+
+/// Looks up the analysis at a subdomain. Lookups are cached for a few seconds
+/// since the VICE proxy checks on every request. Misses aren't cached, so a
+/// newly launched analysis can be found right away.
+#[cached(
+    time = 10,
+    result = true,
+    key = "String",
+    convert = r#"{ subdomain.to_owned() }"#
+)]
+async fn lookup_subdomain(
+    pool: &PgPool,
+    tz: &Tz,
+    subdomain: &str,
+) -> Result<SubdomainAnalysis, DiscoError> {
+    analyses::get_analysis_by_subdomain(pool, subdomain, tz)
+        .await?
+        .ok_or_else(|| {
+            DiscoError::NotFound(format!("no analysis was found for subdomain {}", subdomain))
+        })
+}
+
+/// Whether a user may open the interactive analysis at a subdomain.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SubdomainAccess {
+    #[serde(flatten)]
+    pub analysis: SubdomainAnalysis,
+
+    /// Whether the calling user may open the analysis.
+    pub allowed: bool,
+}
+
+/// Look up the analysis at a VICE subdomain
+///
+/// Returns the owner, ID, status and planned end date of the interactive analysis using the
+/// subdomain. Only service accounts and admins may call this.
+#[utoipa::path(
+    get,
+    path = "/analyses/by-subdomain/{subdomain}",
+    params(
+        ("subdomain" = String, Path, description = "The subdomain of a VICE analysis"),
+    ),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The analysis at the subdomain", body = SubdomainAnalysis),
+        (status = 403, description = "The caller isn't a service account or admin."),
+        (status = 404, description = "No analysis uses the subdomain.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_analysis_by_subdomain(
+    State(state): State<Arc<DiscoenvState>>,
+    Path(subdomain): Path<String>,
+) -> response::Result<Json<SubdomainAnalysis>, DiscoError> {
+    Ok(Json(
+        lookup_subdomain(&state.pool, &state.db_timezone, &subdomain).await?,
+    ))
+}
+
+/// Check whether the logged-in user may open the analysis at a VICE subdomain
+///
+/// Returns the same information as `/analyses/by-subdomain/{subdomain}` along with whether
+/// the calling user may open the analysis. Owners and admins may open it.
+#[utoipa::path(
+    get,
+    path = "/analyses/by-subdomain/{subdomain}/access",
+    params(
+        ("subdomain" = String, Path, description = "The subdomain of a VICE analysis"),
+    ),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The analysis and whether the user may open it", body = SubdomainAccess),
+        (status = 404, description = "No analysis uses the subdomain.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_subdomain_access(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(subdomain): Path<String>,
+) -> response::Result<Json<SubdomainAccess>, DiscoError> {
+    let user = common::fix_username(
+        &user_info.preferred_username.clone().unwrap_or_default(),
         &state.handler_config,
+    );
+
+    let analysis = lookup_subdomain(&state.pool, &state.db_timezone, &subdomain).await?;
+    let allowed = analysis.owner == user || is_admin(&user_info, &state.admin_entitlements);
+
+    Ok(Json(SubdomainAccess { analysis, allowed }))
+}
+
+/// A request to extend an interactive analysis's time limit.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct TimeLimitRequest {
+    /// How many hours to extend the time limit by. Defaults to the most that's allowed.
+    pub extension_hours: Option<i32>,
+}
+
+/// An analysis's time limit after it was extended.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TimeLimit {
+    pub analysis_id: Uuid,
+
+    /// The planned end date before the extension. Absent if it wasn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_end_date: Option<DateTime<Utc>>,
+
+    /// The new planned end date.
+    pub planned_end_date: DateTime<Utc>,
+}
+
+/// Extend the time limit of a running interactive analysis
+///
+/// Pushes the analysis's planned end date out by the requested number of hours, counting from
+/// now if the planned end date has already passed. Extensions are limited to a configured
+/// number of hours each, and the planned end date can't be pushed past a configured number of
+/// hours after the analysis started. Every extension is recorded.
+#[utoipa::path(
+    post,
+    path = "/analyses/{analysis_id}/time-limit",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+    ),
+    request_body = TimeLimitRequest,
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The extended time limit", body = TimeLimit),
+        (status = 400, description = "The analysis isn't a running interactive analysis, the extension is out of bounds, or the time limit is already at its maximum.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn extend_time_limit(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+    Json(req): Json<TimeLimitRequest>,
+) -> response::Result<Json<TimeLimit>, DiscoError> {
+    let max_extension = state.handler_config.max_extension_hours;
+    let extension = req.extension_hours.unwrap_or(max_extension);
+    if extension <= 0 || extension > max_extension {
+        return Err(DiscoError::ParameterInvalid(format!(
+            "extension_hours must be between 1 and {}",
+            max_extension
+        )));
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !analyses::analysis_exists(&mut tx, &user, &analysis_id).await? {
+        return Err(DiscoError::NotFound(format!("analysis {} was not found", analysis_id)));
+    }
+
+    let current = analyses::lock_time_limit_state(&mut tx, &analysis_id).await?;
+    if current.job_type != analyses::INTERACTIVE_JOB_TYPE || current.status != "Running" {
+        return Err(DiscoError::BadRequest(format!(
+            "analysis {} is not a running interactive analysis",
+            analysis_id
+        )));
+    }
+
+    let new_end_date = analyses::extend_time_limit(
+        &mut tx,
+        &analysis_id,
+        extension,
+        state.handler_config.max_time_limit_hours,
+    )
+    .await?
+    .filter(|d| Some(*d) > current.planned_end_date)
+    .ok_or_else(|| {
+        DiscoError::BadRequest(format!(
+            "analysis {} has reached its maximum time limit of {} hours",
+            analysis_id, state.handler_config.max_time_limit_hours
+        ))
+    })?;
+
+    analyses::record_time_limit_extension(
+        &mut tx,
+        &analysis_id,
+        &user,
+        current.planned_end_date,
+        new_end_date,
     )
     .await?;
-    Ok(Json(analyses::get_user_analyses(&mut tx, &user).await?))
+
+    tx.commit().await?;
+
+    let tz = &state.db_timezone;
+    Ok(Json(TimeLimit {
+        analysis_id,
+        previous_end_date: current.planned_end_date.map(|d| analyses::localize(d, tz)),
+        planned_end_date: analyses::localize(new_end_date, tz),
+    }))
+}
+
+/// List the users that extend analysis time limits the most
+///
+/// Returns the number of time limit extensions each user made during the window, along with
+/// the number of analyses they extended. The window covers the last 30 days unless `from` or
+/// `to` are passed in. Requires an admin entitlement.
+#[utoipa::path(
+    get,
+    path = "/admin/analyses/time-limit-extensions",
+    params(UsageWindow, TopUsersParams),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "Extension counts by user", body = [TimeLimitExtensionCount]),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The user isn't an admin."),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "admin"
+)]
+pub async fn get_time_limit_extensions(
+    State(state): State<Arc<DiscoenvState>>,
+    Query(window): Query<UsageWindow>,
+    Query(top): Query<TopUsersParams>,
+) -> response::Result<Json<Vec<TimeLimitExtensionCount>>, DiscoError> {
+    window.validate()?;
+
+    Ok(Json(
+        analyses::count_time_limit_extensions(&state.pool, &window, top.top(), &state.db_timezone)
+            .await?,
+    ))
+}
+
+/// Returns the configured job dispatcher.
+fn dispatcher(state: &DiscoenvState) -> Result<&dyn JobDispatcher, DiscoError> {
+    state
+        .dispatcher
+        .as_deref()
+        .ok_or_else(|| DiscoError::Unimplemented("job dispatch isn't configured".to_owned()))
+}
+
+/// Cancel one of the logged-in user's analyses
+///
+/// Sets the analysis's status to `Canceling` and asks the services that run analyses to stop
+/// it. The status changes again once the analysis has stopped.
+///
+/// The cancellation is sent before the status change is committed, so that a request that
+/// can't be sent doesn't leave the analysis stuck in `Canceling`. If the commit fails after
+/// that, the analysis is stopped anyway and its status is updated when it stops.
+#[utoipa::path(
+    post,
+    path = "/analyses/{analysis_id}/cancel",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+    ),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "Cancellation was requested"),
+        (status = 400, description = "The analysis has already finished.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn cancel_analysis(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+) -> response::Result<(), DiscoError> {
+    let dispatcher = dispatcher(&state)?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !analyses::analysis_exists(&mut tx, &user, &analysis_id).await? {
+        return Err(DiscoError::NotFound(format!("analysis {} was not found", analysis_id)));
+    }
+
+    if analyses::mark_canceling(&mut tx, &analysis_id).await? == 0 {
+        return Err(DiscoError::BadRequest(format!(
+            "analysis {} has already finished or is being canceled",
+            analysis_id
+        )));
+    }
+
+    let external_ids = analyses::get_external_ids(&mut tx, &analysis_id).await?;
+
+    // Publish before committing so the status isn't left as Canceling if the
+    // request can't be sent. Cancellation requests are safe to repeat.
+    send_cancel(dispatcher, analysis_id, user, external_ids).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Asks the services that run analyses to stop an analysis that's been marked
+/// as canceling.
+async fn send_cancel(
+    dispatcher: &dyn JobDispatcher,
+    analysis_id: Uuid,
+    username: String,
+    external_ids: Vec<String>,
+) -> Result<(), DiscoError> {
+    dispatcher
+        .cancel(&CancelRequest {
+            analysis_id,
+            username,
+            external_ids,
+        })
+        .await?;
+    Ok(())
+}
+
+/// A request to launch an analysis again.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RelaunchRequest {
+    /// The name of the new analysis. Defaults to the old name with "(relaunch)" added.
+    pub name: Option<String>,
+}
+
+/// The submission sent to relaunch an analysis.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Relaunch {
+    /// The name of the new analysis.
+    pub name: String,
+
+    /// The submission that was sent.
+    #[schema(value_type = Object)]
+    pub submission: JsonValue,
+}
+
+/// Launch one of the logged-in user's analyses again
+///
+/// Submits the app version the analysis ran with the same parameters, under a new name. The
+/// new analysis shows up in the listing once it has been launched.
+#[utoipa::path(
+    post,
+    path = "/analyses/{analysis_id}/relaunch",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+    ),
+    request_body = RelaunchRequest,
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The analysis was submitted", body = Relaunch),
+        (status = 400, description = "The analysis can't be relaunched.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn relaunch_analysis(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+    Json(req): Json<RelaunchRequest>,
+) -> response::Result<Json<Relaunch>, DiscoError> {
+    let dispatcher = dispatcher(&state)?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !analyses::analysis_exists(&mut tx, &user, &analysis_id).await? {
+        return Err(DiscoError::NotFound(format!("analysis {} was not found", analysis_id)));
+    }
+
+    let source = analyses::get_relaunch_source(&mut tx, &analysis_id).await?;
+    tx.commit().await?;
+
+    Ok(Json(
+        submit_relaunch(dispatcher, user, analysis_id, source, &req).await?,
+    ))
+}
+
+/// Builds the submission for relaunching an analysis and sends it to the
+/// services that run analyses.
+async fn submit_relaunch(
+    dispatcher: &dyn JobDispatcher,
+    username: String,
+    analysis_id: Uuid,
+    source: RelaunchSource,
+    req: &RelaunchRequest,
+) -> Result<Relaunch, DiscoError> {
+    let stored = source.submission.ok_or_else(|| {
+        DiscoError::BadRequest(format!("analysis {} has no stored submission", analysis_id))
+    })?;
+
+    let name = match req.name.as_deref().map(str::trim) {
+        Some("") => {
+            return Err(DiscoError::ParameterInvalid(
+                "name must not be empty".to_owned(),
+            ))
+        }
+        Some(n) => n.to_owned(),
+        None => format!("{} (relaunch)", source.job_name),
+    };
+
+    let submission = dispatch::relaunch_submission(&stored, &name, &source.app_version_id)?;
+
+    dispatcher
+        .submit(&SubmitRequest {
+            username,
+            relaunch_of: Some(analysis_id),
+            submission: submission.clone(),
+        })
+        .await?;
+
+    Ok(Relaunch { name, submission })
+}
+
+/// The formats analyses can be exported in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    NdJson,
+}
+
+impl ExportFormat {
+    /// Picks the format to use for an Accept header. CSV is used if any format
+    /// is acceptable.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        common::negotiate(
+            accept,
+            &[
+                ("text/csv", ExportFormat::Csv),
+                ("application/x-ndjson", ExportFormat::NdJson),
+                ("application/jsonl", ExportFormat::NdJson),
+                ("application/x-jsonlines", ExportFormat::NdJson),
+            ],
+        )
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::NdJson => "application/x-ndjson",
+        }
+    }
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "analyses.csv",
+            ExportFormat::NdJson => "analyses.ndjson",
+        }
+    }
+}
+
+/// An analysis flattened into a single row for exporting.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct AnalysisExportRow {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub status: String,
+    pub start_date: Option<DateTime<Utc>>,
+    pub end_date: Option<DateTime<Utc>>,
+    pub planned_end_date: Option<DateTime<Utc>>,
+    pub result_folder_path: String,
+    pub deleted: bool,
+    pub notify: bool,
+    pub subdomain: String,
+    pub parent_id: String,
+    pub millicores_reserved: i64,
+    pub username: String,
+    pub app_id: String,
+    pub app_name: String,
+    pub app_description: String,
+    pub app_version_id: String,
+    pub app_version: String,
+    pub app_version_deleted: bool,
+    pub app_version_disabled: bool,
+    pub app_version_integration_date: Option<DateTime<Utc>>,
+    pub app_version_edited_date: Option<DateTime<Utc>>,
+    pub integrator_name: String,
+    pub integrator_email: String,
+    pub job_type_id: String,
+    pub job_type: String,
+    pub job_type_system_id: String,
+}
+
+fn from_ts(ts: &Option<Timestamp>) -> Option<DateTime<Utc>> {
+    ts.as_ref()
+        .and_then(|t| Utc.timestamp_opt(t.seconds, t.nanos as u32).single())
+}
+
+impl From<Analysis> for AnalysisExportRow {
+    fn from(a: Analysis) -> Self {
+        let mut row = AnalysisExportRow {
+            start_date: from_ts(&a.start_date),
+            end_date: from_ts(&a.end_date),
+            planned_end_date: from_ts(&a.planned_end_date),
+            id: a.id,
+            name: a.name,
+            description: a.description,
+            status: a.status,
+            result_folder_path: a.result_folder_path,
+            deleted: a.deleted,
+            notify: a.notify,
+            subdomain: a.subdomain,
+            parent_id: a.parent_id,
+            millicores_reserved: a.millicores_reserved,
+            ..Default::default()
+        };
+
+        if let Some(user) = a.user {
+            row.username = user.username;
+        }
+
+        if let Some(app) = a.app {
+            row.app_id = app.id;
+            row.app_name = app.name;
+            row.app_description = app.description;
+        }
+
+        if let Some(av) = a.app_version {
+            row.app_version_integration_date = from_ts(&av.integration_date);
+            row.app_version_edited_date = from_ts(&av.edited_date);
+            row.app_version_id = av.id;
+            row.app_version = av.version;
+            row.app_version_deleted = av.deleted;
+            row.app_version_disabled = av.disabled;
+            if let Some(intd) = av.integration {
+                row.integrator_name = intd.integrator_name;
+                row.integrator_email = intd.integrator_email;
+            }
+        }
+
+        if let Some(kind) = a.kind {
+            row.job_type_id = kind.id;
+            row.job_type = kind.name;
+            row.job_type_system_id = kind.system_id;
+        }
+
+        row
+    }
+}
+
+impl AnalysisExportRow {
+    /// Returns the line that goes before any rows, if the format has one. CSV
+    /// exports start with the column names even if there are no rows.
+    pub fn header(format: ExportFormat) -> Result<Option<Vec<u8>>, DiscoError> {
+        match format {
+            ExportFormat::Csv => {
+                // The csv crate only writes the column names along with a row,
+                // so write an empty one and keep the first line.
+                let mut w = csv::Writer::from_writer(vec![]);
+                w.serialize(AnalysisExportRow::default())
+                    .map_err(|e| DiscoError::MarshalFailure(e.to_string()))?;
+                let mut header = w
+                    .into_inner()
+                    .map_err(|e| DiscoError::MarshalFailure(e.to_string()))?;
+                if let Some(end) = header.iter().position(|b| *b == b'\n') {
+                    header.truncate(end + 1);
+                }
+                Ok(Some(header))
+            }
+            ExportFormat::NdJson => Ok(None),
+        }
+    }
+
+    /// Encodes the row as a line of output.
+    pub fn encode(&self, format: ExportFormat) -> Result<Vec<u8>, DiscoError> {
+        match format {
+            ExportFormat::Csv => {
+                let mut w = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(vec![]);
+                w.serialize(self)
+                    .map_err(|e| DiscoError::MarshalFailure(e.to_string()))?;
+                w.into_inner()
+                    .map_err(|e| DiscoError::MarshalFailure(e.to_string()))
+            }
+            ExportFormat::NdJson => {
+                let mut line = serde_json::to_vec(self)
+                    .map_err(|e| DiscoError::MarshalFailure(e.to_string()))?;
+                line.push(b'\n');
+                Ok(line)
+            }
+        }
+    }
+}
+
+/// Export the logged-in user's analyses
+///
+/// Streams every analysis matching the filters, oldest first, as CSV (`text/csv`) or
+/// newline-delimited JSON (`application/x-ndjson`) depending on the `Accept` header. CSV is
+/// used if the header is missing. Each row flattens the analysis along with its app, app
+/// version and job type. Timestamps are RFC 3339 strings in UTC and are empty when unset.
+#[utoipa::path(
+    get,
+    path = "/analyses/export",
+    params(
+        AnalysisFilter,
+        ("Accept" = Option<String>, Header, description = "text/csv or application/x-ndjson"),
+    ),
+    security(
+      ("api_key" = []),  
+    ),
+    responses(
+        (status = 200, description = "The exported analyses", content_type = ["text/csv", "application/x-ndjson"]),
+        (status = 400, description = "Bad request, or the Accept header doesn't list a supported format.", 
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.", 
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn export_user_analyses(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Query(filter): Query<AnalysisFilter>,
+    headers: HeaderMap,
+) -> response::Result<impl IntoResponse, DiscoError> {
+    let format = match headers.get(header::ACCEPT) {
+        None => ExportFormat::Csv,
+        Some(v) => v
+            .to_str()
+            .ok()
+            .and_then(ExportFormat::from_accept)
+            .ok_or_else(|| {
+                DiscoError::BadRequest(
+                    "analyses can only be exported as text/csv or application/x-ndjson".to_owned(),
+                )
+            })?,
+    };
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+    tx.commit().await?;
+
+    // The analyses are read in a separate task and handed over a small channel,
+    // so reading stops while the client catches up.
+    let (sender, receiver) = mpsc::channel(64);
+    let pool = state.pool.clone();
+    let tz = state.db_timezone;
+    tokio::spawn(async move {
+        analyses::stream_user_analyses(&pool, &user, &filter, &tz, sender).await;
+    });
+
+    let header = AnalysisExportRow::header(format)
+        .map(|h| h.map(Bytes::from))
+        .transpose();
+    let rows = stream::unfold(receiver, move |mut receiver| async move {
+        let chunk = match receiver.recv().await? {
+            Ok(a) => AnalysisExportRow::from(a).encode(format),
+            Err(e) => Err(DiscoError::from(e)),
+        }
+        .map(Bytes::from);
+        Some((chunk, receiver))
+    });
+    let body = stream::iter(header).chain(rows);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", format.file_name()),
+            ),
+        ],
+        StreamBody::new(body),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dispatch::FakeDispatcher;
+
+    #[test]
+    fn test_export_format_from_accept() {
+        assert_eq!(ExportFormat::from_accept("text/csv"), Some(ExportFormat::Csv));
+        assert_eq!(ExportFormat::from_accept("*/*"), Some(ExportFormat::Csv));
+        assert_eq!(
+            ExportFormat::from_accept("application/x-ndjson"),
+            Some(ExportFormat::NdJson)
+        );
+        assert_eq!(
+            ExportFormat::from_accept("application/xml, application/jsonl;q=0.9, text/csv;q=0.5"),
+            Some(ExportFormat::NdJson)
+        );
+        assert_eq!(ExportFormat::from_accept("application/json"), None);
+        assert_eq!(
+            ExportFormat::from_accept("text/csv;q=0.5, application/x-ndjson"),
+            Some(ExportFormat::NdJson)
+        );
+        assert_eq!(
+            ExportFormat::from_accept("text/csv;q=0, */*;q=0.1"),
+            Some(ExportFormat::NdJson)
+        );
+        assert_eq!(ExportFormat::from_accept("text/csv;q=0"), None);
+    }
+
+    #[test]
+    fn test_export_row_encoding() {
+        let row = AnalysisExportRow {
+            id: "1".to_owned(),
+            name: "word, count".to_owned(),
+            start_date: Utc.timestamp_opt(0, 0).single(),
+            ..Default::default()
+        };
+
+        let header = AnalysisExportRow::header(ExportFormat::Csv).unwrap().unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert_eq!(header.lines().count(), 1);
+        assert!(header.starts_with("id,name,description,status,start_date,"));
+        assert!(header.ends_with("job_type_system_id\n"));
+
+        let line = String::from_utf8(row.encode(ExportFormat::Csv).unwrap()).unwrap();
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.starts_with("1,\"word, count\",,,1970-01-01T00:00:00Z,,,"));
+
+        assert!(AnalysisExportRow::header(ExportFormat::NdJson)
+            .unwrap()
+            .is_none());
+        let line = row.encode(ExportFormat::NdJson).unwrap();
+        assert_eq!(line.last(), Some(&b'\n'));
+        let v: JsonValue = serde_json::from_slice(&line).unwrap();
+        assert_eq!(v["name"], "word, count");
+        assert_eq!(v["end_date"], JsonValue::Null);
+    }
+
+    fn relaunch_source(submission: Option<JsonValue>) -> RelaunchSource {
+        RelaunchSource {
+            job_name: "word count".to_owned(),
+            app_version_id: Uuid::new_v4(),
+            submission,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_cancel() {
+        let dispatcher = FakeDispatcher::default();
+        let analysis_id = Uuid::new_v4();
+
+        send_cancel(
+            &dispatcher,
+            analysis_id,
+            "ipcdev".to_owned(),
+            vec!["abc".to_owned()],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            dispatcher.cancels(),
+            vec![CancelRequest {
+                analysis_id,
+                username: "ipcdev".to_owned(),
+                external_ids: vec!["abc".to_owned()],
+            }]
+        );
+        assert!(dispatcher.submissions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_submit_relaunch() {
+        let dispatcher = FakeDispatcher::default();
+        let analysis_id = Uuid::new_v4();
+        let source = relaunch_source(Some(serde_json::json!({
+            "name": "word count",
+            "config": {"step_1_input": "/iplant/home/ipcdev/in.txt"},
+        })));
+        let app_version_id = source.app_version_id;
+
+        let relaunch = submit_relaunch(
+            &dispatcher,
+            "ipcdev".to_owned(),
+            analysis_id,
+            source,
+            &RelaunchRequest::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(relaunch.name, "word count (relaunch)");
+        assert_eq!(relaunch.submission["name"], "word count (relaunch)");
+        assert_eq!(
+            relaunch.submission["app_version_id"],
+            app_version_id.to_string()
+        );
+
+        let submissions = dispatcher.submissions();
+        assert_eq!(submissions.len(), 1);
+        assert_eq!(submissions[0].username, "ipcdev");
+        assert_eq!(submissions[0].relaunch_of, Some(analysis_id));
+        assert_eq!(submissions[0].submission, relaunch.submission);
+
+        let renamed = submit_relaunch(
+            &dispatcher,
+            "ipcdev".to_owned(),
+            analysis_id,
+            relaunch_source(Some(serde_json::json!({}))),
+            &RelaunchRequest {
+                name: Some(" again ".to_owned()),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(renamed.name, "again");
+        assert_eq!(dispatcher.submissions().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_submit_relaunch_rejected() {
+        let dispatcher = FakeDispatcher::default();
+
+        let blank_name = submit_relaunch(
+            &dispatcher,
+            "ipcdev".to_owned(),
+            Uuid::new_v4(),
+            relaunch_source(Some(serde_json::json!({}))),
+            &RelaunchRequest {
+                name: Some(" ".to_owned()),
+            },
+        )
+        .await;
+        assert!(matches!(blank_name, Err(DiscoError::ParameterInvalid(_))));
+
+        let not_stored = submit_relaunch(
+            &dispatcher,
+            "ipcdev".to_owned(),
+            Uuid::new_v4(),
+            relaunch_source(None),
+            &RelaunchRequest::default(),
+        )
+        .await;
+        assert!(matches!(not_stored, Err(DiscoError::BadRequest(_))));
+
+        assert!(dispatcher.submissions().is_empty());
+    }
 }
@@ -0,0 +1,483 @@
+use axum::{
+    extract::{Extension, Json, Path, Query, State},
+    response,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::app_state::DiscoenvState;
+use crate::auth::UserInfo;
+use crate::db::analyses;
+use crate::db::annotations::{self, Note, TagCount};
+use crate::errors::DiscoError;
+
+use super::common;
+
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_NOTE_LENGTH: usize = 10000;
+const DEFAULT_TAG_LIMIT: i64 = 20;
+const MAX_TAG_LIMIT: i64 = 100;
+
+/// Query parameters for tag autocompletion.
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TagParams {
+    /// Only return tags starting with this. Case sensitive, like tags themselves.
+    pub prefix: Option<String>,
+
+    /// The maximum number of tags to return. Defaults to 20, and can't be more than 100.
+    pub limit: Option<i64>,
+}
+
+/// The tags a user has used.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct UserTags {
+    pub tags: Vec<TagCount>,
+}
+
+/// The tags a user has put on an analysis.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct AnalysisTags {
+    pub tags: Vec<String>,
+}
+
+/// A user's notes about an analysis.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct Notes {
+    pub notes: Vec<Note>,
+}
+
+/// The body of a request to add or change a note.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct NoteRequest {
+    pub note: String,
+}
+
+/// Trims the tag and makes sure it can be stored. Commas aren't allowed since
+/// the tags filter on the analysis listing is comma-separated.
+fn validate_tag(tag: &str) -> Result<String, DiscoError> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err(DiscoError::ParameterInvalid("tag can't be empty".to_owned()));
+    }
+    if tag.chars().count() > MAX_TAG_LENGTH {
+        return Err(DiscoError::ParameterInvalid(format!(
+            "tag can't be longer than {} characters",
+            MAX_TAG_LENGTH
+        )));
+    }
+    if tag.contains(',') {
+        return Err(DiscoError::ParameterInvalid("tag can't contain commas".to_owned()));
+    }
+    Ok(tag.to_owned())
+}
+
+fn validate_note(note: &str) -> Result<(), DiscoError> {
+    if note.trim().is_empty() {
+        return Err(DiscoError::BadRequest("note can't be empty".to_owned()));
+    }
+    if note.chars().count() > MAX_NOTE_LENGTH {
+        return Err(DiscoError::BadRequest(format!(
+            "note can't be longer than {} characters",
+            MAX_NOTE_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Validates the user and makes sure the analysis belongs to them. Returns the
+/// fixed-up username.
+async fn check_analysis(
+    conn: &mut sqlx::PgConnection,
+    state: &DiscoenvState,
+    user_info: UserInfo,
+    analysis_id: &Uuid,
+) -> Result<String, DiscoError> {
+    let user = common::validate_user(&mut *conn, &user_info, state).await?;
+
+    if !analyses::analysis_exists(&mut *conn, &user, analysis_id).await? {
+        return Err(DiscoError::NotFound(format!("analysis {} was not found", analysis_id)));
+    }
+
+    Ok(user)
+}
+
+/// List the tags the logged-in user has used
+///
+/// Meant for autocompletion. Returns the tags starting with the prefix along with the number of
+/// analyses each is on, most used first.
+#[utoipa::path(
+    get,
+    path = "/analyses/tags",
+    params(TagParams),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The user's tags", body = UserTags),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_user_tags(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Query(params): Query<TagParams>,
+) -> response::Result<Json<UserTags>, DiscoError> {
+    let limit = params.limit.unwrap_or(DEFAULT_TAG_LIMIT);
+    if !(1..=MAX_TAG_LIMIT).contains(&limit) {
+        return Err(DiscoError::ParameterInvalid(format!(
+            "limit must be between 1 and {}",
+            MAX_TAG_LIMIT
+        )));
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let prefix = params.prefix.as_deref().unwrap_or_default().trim();
+    let tags = annotations::list_user_tags(&mut tx, &user, prefix, limit).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(UserTags { tags }))
+}
+
+/// List the logged-in user's tags on an analysis
+#[utoipa::path(
+    get,
+    path = "/analyses/{analysis_id}/tags",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The analysis's tags", body = AnalysisTags),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_analysis_tags(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+) -> response::Result<Json<AnalysisTags>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = check_analysis(&mut tx, &state, user_info, &analysis_id).await?;
+
+    let tags = annotations::get_analyses_tags(&mut tx, &user, &[analysis_id])
+        .await?
+        .remove(&analysis_id)
+        .unwrap_or_default();
+
+    Ok(Json(AnalysisTags { tags }))
+}
+
+/// Tag one of the logged-in user's analyses
+///
+/// Tags are trimmed, can't be empty or contain commas, and can be up to 64 characters long.
+/// Adding a tag the analysis already has does nothing. Returns all of the analysis's tags.
+#[utoipa::path(
+    put,
+    path = "/analyses/{analysis_id}/tags/{tag}",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+        ("tag" = String, Path, description = "The tag to add"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The analysis's tags", body = AnalysisTags),
+        (status = 400, description = "The tag is invalid.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn add_analysis_tag(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path((analysis_id, tag)): Path<(Uuid, String)>,
+) -> response::Result<Json<AnalysisTags>, DiscoError> {
+    let tag = validate_tag(&tag)?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = check_analysis(&mut tx, &state, user_info, &analysis_id).await?;
+
+    annotations::add_analysis_tag(&mut tx, &user, &analysis_id, &tag).await?;
+    let tags = annotations::get_analyses_tags(&mut tx, &user, &[analysis_id])
+        .await?
+        .remove(&analysis_id)
+        .unwrap_or_default();
+
+    tx.commit().await?;
+
+    Ok(Json(AnalysisTags { tags }))
+}
+
+/// Remove a tag from one of the logged-in user's analyses
+#[utoipa::path(
+    delete,
+    path = "/analyses/{analysis_id}/tags/{tag}",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+        ("tag" = String, Path, description = "The tag to remove"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The tag was removed"),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user, analysis, or tag wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("tag wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn delete_analysis_tag(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path((analysis_id, tag)): Path<(Uuid, String)>,
+) -> response::Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = check_analysis(&mut tx, &state, user_info, &analysis_id).await?;
+
+    let tag = tag.trim();
+    if annotations::delete_analysis_tag(&mut tx, &user, &analysis_id, tag).await? == 0 {
+        return Err(DiscoError::NotFound(format!(
+            "tag {} was not found on analysis {}",
+            tag, analysis_id
+        )));
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// List the logged-in user's notes about an analysis
+///
+/// Notes are returned oldest first.
+#[utoipa::path(
+    get,
+    path = "/analyses/{analysis_id}/notes",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The analysis's notes", body = Notes),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn get_analysis_notes(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+) -> response::Result<Json<Notes>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = check_analysis(&mut tx, &state, user_info, &analysis_id).await?;
+
+    let notes = annotations::list_notes(&mut tx, &user, &analysis_id, &state.db_timezone).await?;
+
+    Ok(Json(Notes { notes }))
+}
+
+/// Add a note about one of the logged-in user's analyses
+///
+/// Notes can't be empty and can be up to 10000 characters long.
+#[utoipa::path(
+    post,
+    path = "/analyses/{analysis_id}/notes",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+    ),
+    request_body = NoteRequest,
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The new note", body = Note),
+        (status = 400, description = "The note is invalid.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or analysis wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("analysis wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn add_analysis_note(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(analysis_id): Path<Uuid>,
+    Json(req): Json<NoteRequest>,
+) -> response::Result<Json<Note>, DiscoError> {
+    validate_note(&req.note)?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = check_analysis(&mut tx, &state, user_info, &analysis_id).await?;
+
+    let note =
+        annotations::add_note(&mut tx, &user, &analysis_id, &req.note, &state.db_timezone).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(note))
+}
+
+/// Change one of the logged-in user's notes about an analysis
+#[utoipa::path(
+    post,
+    path = "/analyses/{analysis_id}/notes/{note_id}",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+        ("note_id" = String, Path, description = "A note's UUID"),
+    ),
+    request_body = NoteRequest,
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The updated note", body = Note),
+        (status = 400, description = "The note is invalid.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user, analysis, or note wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("note wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn update_analysis_note(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path((analysis_id, note_id)): Path<(Uuid, Uuid)>,
+    Json(req): Json<NoteRequest>,
+) -> response::Result<Json<Note>, DiscoError> {
+    validate_note(&req.note)?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = check_analysis(&mut tx, &state, user_info, &analysis_id).await?;
+
+    let note = annotations::update_note(
+        &mut tx,
+        &user,
+        &analysis_id,
+        &note_id,
+        &req.note,
+        &state.db_timezone,
+    )
+    .await?
+    .ok_or_else(|| DiscoError::NotFound(format!("note {} was not found", note_id)))?;
+
+    tx.commit().await?;
+
+    Ok(Json(note))
+}
+
+/// Delete one of the logged-in user's notes about an analysis
+#[utoipa::path(
+    delete,
+    path = "/analyses/{analysis_id}/notes/{note_id}",
+    params(
+        ("analysis_id" = String, Path, description = "An analysis's UUID"),
+        ("note_id" = String, Path, description = "A note's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The note was deleted"),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user, analysis, or note wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("note wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "analyses"
+)]
+pub async fn delete_analysis_note(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path((analysis_id, note_id)): Path<(Uuid, Uuid)>,
+) -> response::Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = check_analysis(&mut tx, &state, user_info, &analysis_id).await?;
+
+    if annotations::delete_note(&mut tx, &user, &analysis_id, &note_id).await? == 0 {
+        return Err(DiscoError::NotFound(format!("note {} was not found", note_id)));
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_tag() {
+        assert_eq!(validate_tag("  rna-seq ").unwrap(), "rna-seq");
+        assert!(validate_tag("   ").is_err());
+        assert!(validate_tag("a,b").is_err());
+        assert!(validate_tag(&"x".repeat(MAX_TAG_LENGTH)).is_ok());
+        assert!(validate_tag(&"x".repeat(MAX_TAG_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use axum::{
+    extract::{Extension, Json, Path, Query, State},
+    response,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use std::sync::Arc;
+use utoipa::IntoParams;
+
+use crate::app_state::DiscoenvState;
+use crate::auth::UserInfo;
+use crate::db::apps::{self, AppDetail, AppFilter, AppListing, FavoriteApps, RecentApps};
+use crate::errors::DiscoError;
+
+use super::common;
+
+/// The number of recent apps returned if no limit is requested.
+const DEFAULT_RECENT_APPS: i64 = 10;
+
+/// The largest number of recent apps that can be requested.
+const MAX_RECENT_APPS: i64 = 100;
+
+/// Query parameters for the recently used apps listing.
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RecentAppsParams {
+    /// The maximum number of apps to return. Defaults to 10, and can't be more than 100.
+    pub limit: Option<i64>,
+}
+
+/// List apps
+///
+/// Returns a page of the apps matching the filters, sorted by name, along with the total number
+/// of matching apps. An app counts as deleted when all of its versions are deleted, and as
+/// disabled when all of them are disabled. Deleted apps are left out unless `deleted=true` is
+/// passed in.
+#[utoipa::path(
+    get,
+    path = "/apps",
+    params(AppFilter),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "A page of apps", body = AppListing),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "apps"
+)]
+pub async fn get_apps(
+    State(state): State<Arc<DiscoenvState>>,
+    Query(mut filter): Query<AppFilter>,
+) -> response::Result<Json<AppListing>, DiscoError> {
+    filter.integrator_username = filter
+        .integrator
+        .as_deref()
+        .map(|i| common::fix_username(i, &state.handler_config));
+
+    let mut tx = state.pool.begin().await?;
+    let total = apps::count_apps(&mut tx, &filter).await?;
+    let apps = apps::list_apps(&mut tx, &filter).await?;
+
+    Ok(Json(AppListing { apps, total }))
+}
+
+/// Get an app along with all of its versions
+///
+/// The versions are ordered by `version_order` and include deleted and disabled versions.
+#[utoipa::path(
+    get,
+    path = "/apps/{app_id}",
+    params(
+        ("app_id" = String, Path, description = "An app's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The app", body = AppDetail),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The app wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("app wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "apps"
+)]
+pub async fn get_app(
+    State(state): State<Arc<DiscoenvState>>,
+    Path(app_id): Path<Uuid>,
+) -> response::Result<Json<AppDetail>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+
+    let app = apps::get_app(&mut tx, &app_id)
+        .await?
+        .ok_or_else(|| DiscoError::NotFound(format!("app {} was not found", app_id)))?;
+    let versions = apps::get_app_versions(&mut tx, &app_id, &state.db_timezone).await?;
+
+    Ok(Json(AppDetail { app, versions }))
+}
+
+/// List the logged-in user's favorite apps
+///
+/// Favorites are listed most recently added first. Apps that have since been deleted or
+/// disabled stay in the list and are flagged.
+#[utoipa::path(
+    get,
+    path = "/apps/favorites",
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The user's favorite apps", body = FavoriteApps),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "apps"
+)]
+pub async fn get_favorite_apps(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+) -> response::Result<Json<FavoriteApps>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let apps = apps::list_favorite_apps(&mut tx, &user, &state.db_timezone).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(FavoriteApps { apps }))
+}
+
+/// Add an app to the logged-in user's favorites
+///
+/// Adding an app that's already a favorite does nothing.
+#[utoipa::path(
+    put,
+    path = "/apps/favorites/{app_id}",
+    params(
+        ("app_id" = String, Path, description = "An app's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The app was added to the user's favorites"),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or app wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("app wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "apps"
+)]
+pub async fn add_favorite_app(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(app_id): Path<Uuid>,
+) -> response::Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !apps::app_exists(&mut tx, &app_id).await? {
+        return Err(DiscoError::NotFound(format!("app {} was not found", app_id)));
+    }
+
+    apps::add_favorite_app(&mut tx, &user, &app_id).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Remove an app from the logged-in user's favorites
+#[utoipa::path(
+    delete,
+    path = "/apps/favorites/{app_id}",
+    params(
+        ("app_id" = String, Path, description = "An app's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The app was removed from the user's favorites"),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user wasn't found or the app isn't a favorite.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("favorite wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "apps"
+)]
+pub async fn delete_favorite_app(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(app_id): Path<Uuid>,
+) -> response::Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if apps::delete_favorite_app(&mut tx, &user, &app_id).await? == 0 {
+        return Err(DiscoError::NotFound(format!(
+            "app {} is not one of the user's favorites",
+            app_id
+        )));
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// List the apps the logged-in user has run most recently
+///
+/// Apps are ordered by when the user last started an analysis of each. Apps that have since
+/// been deleted or disabled are included and flagged.
+#[utoipa::path(
+    get,
+    path = "/apps/recent",
+    params(RecentAppsParams),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The user's recently used apps", body = RecentApps),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "apps"
+)]
+pub async fn get_recent_apps(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Query(params): Query<RecentAppsParams>,
+) -> response::Result<Json<RecentApps>, DiscoError> {
+    let limit = params.limit.unwrap_or(DEFAULT_RECENT_APPS);
+    if !(1..=MAX_RECENT_APPS).contains(&limit) {
+        return Err(DiscoError::ParameterInvalid(format!(
+            "limit must be between 1 and {}",
+            MAX_RECENT_APPS
+        )));
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let apps = apps::list_recent_apps(&mut tx, &user, limit, &state.db_timezone).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(RecentApps { apps }))
+}
@@ -1,11 +1,13 @@
 use axum::{
-    extract::{Json, Path, State},
+    extract::{Json, Path, Query, State},
     http::StatusCode,
     response, Extension,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::Map;
 use sqlx::types::{JsonValue, Uuid};
 use std::sync::Arc;
+use utoipa::IntoParams;
 
 
 use crate::{db::{bags::{self, Bag}, users}, auth::UserInfo};
@@ -14,6 +16,32 @@ use crate::errors::DiscoError;
 use crate::app_state::DiscoenvState;
 use super::common;
 
+/// Optional settings for a bag passed in as query parameters.
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BagParams {
+    /// Whether the bag's contents should be encrypted at rest. Requires encryption to be
+    /// configured. Existing bags keep their current setting if this is omitted.
+    pub sensitive: Option<bool>,
+}
+
+impl BagParams {
+    fn validate(&self, state: &DiscoenvState) -> Result<(), DiscoError> {
+        check_encryption(self.sensitive.unwrap_or(false), state)
+    }
+}
+
+/// Sensitive bags can only be written when encryption is configured. This is also checked for
+/// bags that are already sensitive, since their setting is kept when it isn't passed in.
+fn check_encryption(sensitive: bool, state: &DiscoenvState) -> Result<(), DiscoError> {
+    if sensitive && state.keyring.is_none() {
+        return Err(DiscoError::BadRequest(
+            "sensitive bags require encryption to be configured".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
 /// Get all of a user's bags.
 /// 
 /// Returns a complete listing of all of the user's bags. Mostly useful for administrative purposes.
@@ -38,14 +66,13 @@ pub async fn get_user_bags(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
 ) -> response::Result<Json<Bags>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let retval = list_user_bags(&mut tx, &user, state.keyring.as_ref()).await?;
+    tx.commit().await?;
 
-    Ok(Json(list_user_bags(&mut tx, &user).await?))
+    Ok(Json(retval))
 }
 
 /// Deletes all of a user's bags.
@@ -72,12 +99,8 @@ pub async fn delete_user_bags(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
 ) -> response::Result<(), DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     bags::delete_user_bags(&mut tx, &user).await?;
 
@@ -92,6 +115,7 @@ pub async fn delete_user_bags(
 #[utoipa::path(
     put,
     path = "/bags",
+    params(BagParams),
     request_body = JsonValue::Object,
     responses(
         (status = 200, description = "Adds a bag for a user", body = ID),
@@ -110,16 +134,23 @@ pub async fn delete_user_bags(
 pub  async fn add_user_bag(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
+    Query(params): Query<BagParams>,
     Json(bag): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<common::ID>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
-    let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    params.validate(&state)?;
 
-    let u = bags::add_user_bag(&mut tx, &user, bag).await?;
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let user_id = users::user_id(&mut tx, &user).await?;
+    let u = bags::add_user_bag(
+        &mut tx,
+        &user_id,
+        bag,
+        params.sensitive.unwrap_or(false),
+        state.keyring.as_ref(),
+    )
+    .await?;
 
     tx.commit().await?;
 
@@ -184,18 +215,17 @@ pub async fn get_bag(
     Extension(user_info): Extension<UserInfo>,
     Path(bag_id): Path<Uuid>,
 ) -> response::Result<Json<Bag>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     if !bags::bag_exists(&mut tx, &user, &bag_id).await? {
         return Err(DiscoError::NotFound(format!("bag {} was not found", bag_id)));
     }
 
-    Ok(Json(bags::get_bag(&mut tx, &user, &bag_id).await?))
+    let retval = bags::get_bag(&mut tx, &user, &bag_id, state.keyring.as_ref()).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
 }
 
 /// Updates a particular bag for a user.
@@ -204,10 +234,14 @@ pub async fn get_bag(
     path = "/bags/{bag_id}",
     params(
         ("bag_id" = String, Path, description = "A bag's UUID"),
+        BagParams,
     ),
     request_body = JsonValue::Object,
     responses(
         (status = 200, description = "The user's default bag.", body = Bag),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError, 
+            example = json!(DiscoError::BadRequest("sensitive bags require encryption to be configured".to_owned()).create_service_error()) ),
         (status = 404, description = "The user was not found.", 
             body = DiscoError, 
             example = json!(DiscoError::NotFound("user doesn't exist".to_owned()).create_service_error())),
@@ -221,22 +255,28 @@ pub async fn update_bag(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
     Path(bag_id): Path<Uuid>,
+    Query(params): Query<BagParams>,
     Json(bag): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<Bag>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
-    let mut tx = state.pool.begin().await?;
+    params.validate(&state)?;
 
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     if !bags::bag_exists(&mut tx, &user, &bag_id).await? {
         return Err(DiscoError::NotFound(format!("bag {} was not found", bag_id)));
     }
 
-    bags::update_bag(&mut tx, &user, &bag_id, bag).await?;
+    let sensitive = match params.sensitive {
+        Some(sensitive) => sensitive,
+        None => bags::bag_is_sensitive(&mut tx, &user, &bag_id).await?,
+    };
+    check_encryption(sensitive, &state)?;
 
-    let retval = bags::get_bag(&mut tx, &user, &bag_id).await?;
+    let user_id = users::user_id(&mut tx, &user).await?;
+    bags::update_bag(&mut tx, &user_id, &bag_id, bag, sensitive, state.keyring.as_ref()).await?;
+
+    let retval = bags::get_bag(&mut tx, &user, &bag_id, state.keyring.as_ref()).await?;
     
     tx.commit().await?;
     
@@ -266,12 +306,8 @@ pub async fn delete_bag(
     Extension(user_info): Extension<UserInfo>,
     Path(bag_id): Path<Uuid>,
 ) -> response::Result<(), DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     bags::delete_bag(&mut tx, &user, &bag_id).await?;
 
@@ -301,20 +337,20 @@ pub async fn get_default_bag(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
 ) -> response::Result<Json<Bag>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     if !bags::has_default_bag(&mut tx, &user).await? {
+        let user_id = users::user_id(&mut tx, &user).await?;
         let new_bag: Map<String, JsonValue> = Map::new();
-        let new_bag_uuid = bags::add_user_bag(&mut tx, &user, new_bag).await?;
+        let new_bag_uuid = bags::add_user_bag(&mut tx, &user_id, new_bag, false, None).await?;
         bags::set_default_bag(&mut tx, &user, &new_bag_uuid).await?;
     }
 
-    Ok(Json(bags::get_default_bag(&mut tx, &user).await?))
+    let retval = bags::get_default_bag(&mut tx, &user, state.keyring.as_ref()).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
 }
 
 
@@ -325,9 +361,13 @@ pub async fn get_default_bag(
 #[utoipa::path(
     post,
     path = "/bags/default",
+    params(BagParams),
     request_body = JsonValue::Object,
     responses(
         (status = 200, description = "The user's default bag.", body = Bag),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError, 
+            example = json!(DiscoError::BadRequest("sensitive bags require encryption to be configured".to_owned()).create_service_error()) ),
         (status = 404, description = "The user was not found.", 
             body = DiscoError, 
             example = json!(DiscoError::NotFound("user doesn't exist".to_owned()).create_service_error())),
@@ -340,23 +380,33 @@ pub async fn get_default_bag(
 pub async fn update_default_bag(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
+    Query(params): Query<BagParams>,
     Json(bag): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<Bag>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
+    params.validate(&state)?;
+
     let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user_id = users::user_id(&mut tx, &user).await?;
 
     if !bags::has_default_bag(&mut tx, &user).await? {
-        let new_bag_uuid = bags::add_user_bag(&mut tx, &user, bag).await?;
+        let sensitive = params.sensitive.unwrap_or(false);
+        let new_bag_uuid =
+            bags::add_user_bag(&mut tx, &user_id, bag, sensitive, state.keyring.as_ref()).await?;
         bags::set_default_bag(&mut tx, &user, &new_bag_uuid).await?;
     } else {
-        bags::update_default_bag(&mut tx, &user, bag).await?;
+        let bag_id = bags::default_bag_id(&mut tx, &user).await?;
+        let sensitive = match params.sensitive {
+            Some(sensitive) => sensitive,
+            None => bags::bag_is_sensitive(&mut tx, &user, &bag_id).await?,
+        };
+        check_encryption(sensitive, &state)?;
+
+        bags::update_bag(&mut tx, &user_id, &bag_id, bag, sensitive, state.keyring.as_ref()).await?;
     }
 
-    let retval = bags::get_default_bag(&mut tx, &user).await?;
+    let retval = bags::get_default_bag(&mut tx, &user, state.keyring.as_ref()).await?;
 
     tx.commit().await?;
 
@@ -382,12 +432,8 @@ pub async fn delete_default_bag(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
 ) -> response::Result<(), DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     bags::delete_default_bag(&mut tx, &user).await?;
 
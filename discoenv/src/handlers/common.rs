@@ -1,4 +1,6 @@
 use super::config;
+use crate::app_state::DiscoenvState;
+use crate::auth::{middleware::is_service_account, UserInfo};
 use crate::db::users;
 use crate::errors::DiscoError;
 use serde::{Deserialize, Serialize};
@@ -40,3 +42,101 @@ where
 
     Ok(user)
 }
+
+/// Returns the logged-in user's fixed-up username, making sure they're in the
+/// database. If users are provisioned, a user who isn't in it yet is added using
+/// the caller's transaction, so the user is only kept if the request's changes
+/// are. Logins are recorded the same way if they're tracked. Service accounts
+/// are only checked.
+pub async fn validate_user(
+    conn: &mut sqlx::PgConnection,
+    user_info: &UserInfo,
+    state: &DiscoenvState,
+) -> Result<String, DiscoError> {
+    let cfg = &state.handler_config;
+    let user = fix_username(
+        user_info.preferred_username.as_deref().unwrap_or_default(),
+        cfg,
+    );
+
+    if user.is_empty() || is_service_account(user_info, &state.service_accounts) {
+        return validate_username(&mut *conn, &user, cfg).await;
+    }
+
+    if !users::username_exists(&mut *conn, &user).await? {
+        if !cfg.provision_users {
+            return Err(DiscoError::NotFound(format!("user {} was not found", user)));
+        }
+        if users::provision_user(&mut *conn, &user).await? {
+            tracing::info!("provisioned user {user}");
+        }
+    }
+
+    if cfg.track_user_logins {
+        users::record_login(&mut *conn, &user).await?;
+    }
+
+    Ok(user)
+}
+
+/// Returns how specifically a media range from an Accept header matches a media
+/// type: 2 for the type itself, 1 for `type/*` and 0 for `*/*`. Returns None if
+/// the range doesn't match.
+fn range_specificity(range: &str, media_type: &str) -> Option<u8> {
+    if range.eq_ignore_ascii_case(media_type) {
+        return Some(2);
+    }
+    if range == "*/*" {
+        return Some(0);
+    }
+
+    let main_type = media_type.split('/').next().unwrap_or_default();
+    match range.strip_suffix("/*") {
+        Some(t) if t.eq_ignore_ascii_case(main_type) => Some(1),
+        _ => None,
+    }
+}
+
+/// Picks the response format for an Accept header from the media types a
+/// handler offers, each paired with the format it maps to. Every offered type
+/// gets the q-value of the most specific range in the header that matches it,
+/// so `text/csv;q=0, */*` rules CSV out. The type with the highest q-value
+/// wins, then the one whose range comes first in the header, then the one
+/// offered first. Returns None if nothing offered is acceptable.
+pub fn negotiate<T: Copy>(accept: &str, offered: &[(&str, T)]) -> Option<T> {
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|r| {
+            let mut parts = r.split(';').map(str::trim);
+            let range = parts.next().filter(|t| !t.is_empty())?;
+            let q = match parts.find_map(|p| p.strip_prefix("q=")) {
+                Some(v) => v.parse::<f32>().ok()?,
+                None => 1.0,
+            };
+            Some((range, q))
+        })
+        .collect();
+
+    let mut best: Option<(f32, usize, T)> = None;
+    for (media_type, format) in offered {
+        let matched = ranges
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, (range, q))| {
+                range_specificity(range, media_type).map(|s| (s, pos, *q))
+            })
+            .max_by_key(|(s, pos, _)| (*s, std::cmp::Reverse(*pos)));
+
+        if let Some((_, pos, q)) = matched {
+            let better = match best {
+                None => true,
+                Some((best_q, best_pos, _)) => q > best_q || (q == best_q && pos < best_pos),
+            };
+            if q > 0.0 && better {
+                best = Some((q, pos, *format));
+            }
+        }
+    }
+
+    best.map(|(_, _, format)| format)
+}
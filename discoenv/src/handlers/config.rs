@@ -2,5 +2,9 @@
 pub struct HandlerConfiguration {
     pub append_user_domain: bool,
     pub user_domain: String,
+    pub provision_users: bool,
+    pub track_user_logins: bool,
     pub do_auth: bool,
+    pub max_extension_hours: i32,
+    pub max_time_limit_hours: i32,
 }
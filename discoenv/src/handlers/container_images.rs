@@ -0,0 +1,129 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    response,
+};
+use sqlx::types::Uuid;
+use std::sync::Arc;
+
+use crate::app_state::DiscoenvState;
+use crate::db::container_images::{self, ContainerImageFilter, ContainerImages, ImageDependents};
+use crate::errors::DiscoError;
+
+fn validate_recent_days(filter: &ContainerImageFilter) -> Result<(), DiscoError> {
+    if filter.recent_days() <= 0 {
+        return Err(DiscoError::ParameterInvalid(
+            "recent_days must be greater than 0".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// List container images along with how much they're used
+///
+/// Returns the container images matching the filters, sorted by name and tag. Each image
+/// includes the number of tools that run in it, the number of apps using those tools, and the
+/// number of analyses of those apps started in the last `recent_days` days. Only available to
+/// administrators.
+#[utoipa::path(
+    get,
+    path = "/container-images",
+    params(ContainerImageFilter),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The container images", body = ContainerImages),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The user isn't an administrator.",
+            body = DiscoError,
+            example = json!(DiscoError::Forbidden("forbidden".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "container-images"
+)]
+pub async fn get_container_images(
+    State(state): State<Arc<DiscoenvState>>,
+    Query(filter): Query<ContainerImageFilter>,
+) -> response::Result<Json<ContainerImages>, DiscoError> {
+    validate_recent_days(&filter)?;
+
+    let container_images =
+        container_images::list_container_images(&state.pool, &filter, None).await?;
+
+    Ok(Json(ContainerImages { container_images }))
+}
+
+/// List the tools and users that depend on a container image
+///
+/// Meant to be checked before deprecating an image. Returns the image with its usage counts,
+/// the tools that run in it, and the users who ran analyses that used it in the last
+/// `recent_days` days. Only the `recent_days` filter is used. Only available to
+/// administrators.
+#[utoipa::path(
+    get,
+    path = "/container-images/{image_id}/dependents",
+    params(
+        ("image_id" = String, Path, description = "A container image's UUID"),
+        ContainerImageFilter,
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The image's dependents", body = ImageDependents),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The user isn't an administrator.",
+            body = DiscoError,
+            example = json!(DiscoError::Forbidden("forbidden".to_owned()).create_service_error())),
+        (status = 404, description = "The container image wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("container image wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "container-images"
+)]
+pub async fn get_image_dependents(
+    State(state): State<Arc<DiscoenvState>>,
+    Path(image_id): Path<Uuid>,
+    Query(filter): Query<ContainerImageFilter>,
+) -> response::Result<Json<ImageDependents>, DiscoError> {
+    validate_recent_days(&filter)?;
+
+    // Only the window applies here; the image is picked by its ID.
+    let filter = ContainerImageFilter {
+        recent_days: filter.recent_days,
+        ..Default::default()
+    };
+
+    let mut tx = state.pool.begin().await?;
+
+    let container_image =
+        container_images::list_container_images(&mut tx, &filter, Some(&image_id))
+            .await?
+            .pop()
+            .ok_or_else(|| {
+                DiscoError::NotFound(format!("container image {} was not found", image_id))
+            })?;
+    let tools = container_images::list_dependent_tools(&mut tx, &image_id).await?;
+    let users = container_images::list_affected_users(
+        &mut tx,
+        &image_id,
+        filter.recent_days(),
+        &state.db_timezone,
+    )
+    .await?;
+
+    Ok(Json(ImageDependents {
+        container_image,
+        tools,
+        users,
+    }))
+}
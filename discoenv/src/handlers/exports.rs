@@ -0,0 +1,541 @@
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Extension, Path, State},
+    http::{header, HeaderMap},
+    response::{self, IntoResponse},
+};
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use futures::stream;
+use serde::Serialize;
+use sqlx::types::Uuid;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::app_state::DiscoenvState;
+use crate::archive::ZipStream;
+use crate::auth::{middleware::is_admin, UserInfo};
+use crate::db::analyses::UsageWindow;
+use crate::db::{bags, preferences, searches, sessions, tools, users};
+use crate::errors::DiscoError;
+
+use super::{analyses, common};
+
+/// The version of the export layout as a whole: which files there are and
+/// where they go.
+const EXPORT_FORMAT_VERSION: u32 = 1;
+
+/// The archive formats a user's data can be exported in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Picks the format to use for an Accept header. Zip is used if any format
+    /// is acceptable.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        common::negotiate(
+            accept,
+            &[
+                ("application/zip", ArchiveFormat::Zip),
+                ("application/gzip", ArchiveFormat::TarGz),
+                ("application/x-gzip", ArchiveFormat::TarGz),
+                ("application/x-gtar", ArchiveFormat::TarGz),
+            ],
+        )
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+/// The kinds of files in an export. Each kind's schema version gets bumped
+/// when the fields written for it change.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportSchema {
+    Bag,
+    Preferences,
+    SavedSearches,
+    Sessions,
+    Tools,
+    AnalysesSummary,
+}
+
+impl ExportSchema {
+    fn version(&self) -> u32 {
+        match self {
+            ExportSchema::Bag => 1,
+            ExportSchema::Preferences => 1,
+            ExportSchema::SavedSearches => 1,
+            ExportSchema::Sessions => 1,
+            ExportSchema::Tools => 1,
+            ExportSchema::AnalysesSummary => 1,
+        }
+    }
+}
+
+/// A JSON file to include in an export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportFile {
+    pub path: String,
+    pub schema: ExportSchema,
+    pub contents: Vec<u8>,
+}
+
+impl ExportFile {
+    fn new<T: Serialize>(
+        path: impl Into<String>,
+        schema: ExportSchema,
+        value: &T,
+    ) -> Result<Self, DiscoError> {
+        Ok(ExportFile {
+            path: path.into(),
+            schema,
+            contents: serde_json::to_vec_pretty(value)
+                .map_err(|e| DiscoError::Internal(e.to_string()))?,
+        })
+    }
+}
+
+/// An entry in the export's manifest.
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    path: String,
+    schema: ExportSchema,
+    schema_version: u32,
+}
+
+/// Describes what's in an export. Written to manifest.json at the end of the
+/// archive, once every other file is in it.
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    format_version: u32,
+    user_id: Uuid,
+    username: &'a str,
+    exported_at: DateTime<Utc>,
+    files: &'a [ManifestEntry],
+}
+
+/// Writes an archive into a buffer that's emptied each time part of the
+/// archive is sent.
+enum ArchiveWriter {
+    Zip(ZipStream),
+    TarGz(tar::Builder<GzEncoder<Vec<u8>>>, DateTime<Utc>),
+}
+
+impl ArchiveWriter {
+    fn new(format: ArchiveFormat, mtime: DateTime<Utc>) -> Self {
+        match format {
+            ArchiveFormat::Zip => ArchiveWriter::Zip(ZipStream::new(mtime)),
+            ArchiveFormat::TarGz => ArchiveWriter::TarGz(
+                tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default())),
+                mtime,
+            ),
+        }
+    }
+
+    fn add(&mut self, path: &str, contents: &[u8]) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Zip(zip) => zip.add(path, contents),
+            ArchiveWriter::TarGz(tar, mtime) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(mtime.timestamp().max(0) as u64);
+                tar.append_data(&mut header, path, contents)
+            }
+        }
+    }
+
+    /// Returns the part of the archive written since the last call.
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            ArchiveWriter::Zip(zip) => zip.take_output(),
+            ArchiveWriter::TarGz(tar, _) => std::mem::take(tar.get_mut().get_mut()),
+        }
+    }
+
+    /// Finishes the archive and returns the rest of it.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            ArchiveWriter::Zip(zip) => zip.finish(),
+            ArchiveWriter::TarGz(tar, _) => tar.into_inner()?.finish(),
+        }
+    }
+}
+
+/// The parts of an archive on their way to the client.
+type ArchiveSender = mpsc::Sender<Result<Bytes, DiscoError>>;
+
+/// An export archive that's sent to the client as files are added to it.
+struct ExportArchive {
+    writer: ArchiveWriter,
+    files: Vec<ManifestEntry>,
+    sender: ArchiveSender,
+}
+
+fn archive_error(e: io::Error) -> DiscoError {
+    DiscoError::Internal(format!("error writing the export archive: {e}"))
+}
+
+impl ExportArchive {
+    fn new(format: ArchiveFormat, exported_at: DateTime<Utc>, sender: ArchiveSender) -> Self {
+        ExportArchive {
+            writer: ArchiveWriter::new(format, exported_at),
+            files: Vec::new(),
+            sender,
+        }
+    }
+
+    async fn send(&self, chunk: Vec<u8>) -> Result<(), DiscoError> {
+        send_chunk(&self.sender, chunk).await
+    }
+
+    /// Adds the file to the archive and sends whatever parts of the archive are
+    /// ready.
+    async fn add(&mut self, file: ExportFile) -> Result<(), DiscoError> {
+        self.writer
+            .add(&file.path, &file.contents)
+            .map_err(archive_error)?;
+        self.files.push(ManifestEntry {
+            path: file.path,
+            schema: file.schema,
+            schema_version: file.schema.version(),
+        });
+
+        let chunk = self.writer.take_output();
+        self.send(chunk).await
+    }
+
+    /// Adds the manifest listing the files and sends the rest of the archive.
+    async fn finish(
+        mut self,
+        user_id: Uuid,
+        username: &str,
+        exported_at: DateTime<Utc>,
+    ) -> Result<(), DiscoError> {
+        let manifest = serde_json::to_vec_pretty(&Manifest {
+            format_version: EXPORT_FORMAT_VERSION,
+            user_id,
+            username,
+            exported_at,
+            files: &self.files,
+        })
+        .map_err(|e| DiscoError::Internal(e.to_string()))?;
+        self.writer
+            .add("manifest.json", &manifest)
+            .map_err(archive_error)?;
+
+        let chunk = self.writer.finish().map_err(archive_error)?;
+        send_chunk(&self.sender, chunk).await
+    }
+}
+
+/// Sends part of the archive to the response body, skipping empty parts.
+async fn send_chunk(sender: &ArchiveSender, chunk: Vec<u8>) -> Result<(), DiscoError> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    sender
+        .send(Ok(Bytes::from(chunk)))
+        .await
+        .map_err(|_| DiscoError::Internal("the export was abandoned".to_owned()))
+}
+
+/// Reads everything the user has stored into export files, adding each one to
+/// the archive as soon as it's read.
+async fn collect_export_files(
+    conn: &mut sqlx::PgConnection,
+    state: &DiscoenvState,
+    username: &str,
+    archive: &mut ExportArchive,
+) -> Result<(), DiscoError> {
+    for bag in bags::list_user_bags(&mut *conn, username, state.keyring.as_ref())
+        .await?
+        .bags
+    {
+        let file = ExportFile::new(format!("bags/{}.json", bag.id), ExportSchema::Bag, &bag)?;
+        archive.add(file).await?;
+    }
+
+    if preferences::has_preferences(&mut *conn, username).await? {
+        let prefs = preferences::user_preferences(&mut *conn, username).await?;
+        let file = ExportFile::new("preferences.json", ExportSchema::Preferences, &prefs)?;
+        archive.add(file).await?;
+    }
+
+    if searches::has_saved_searches(&mut *conn, username).await? {
+        let saved = searches::get_saved_searches(&mut *conn, username).await?;
+        let file = ExportFile::new("saved-searches.json", ExportSchema::SavedSearches, &saved)?;
+        archive.add(file).await?;
+    }
+
+    let sessions = sessions::list_sessions(&mut *conn, username, state.keyring.as_ref()).await?;
+    let file = ExportFile::new("sessions.json", ExportSchema::Sessions, &sessions)?;
+    archive.add(file).await?;
+
+    let tools = tools::Tools {
+        tools: tools::get_user_tools(&mut *conn, username, &tools::ToolFilter::default()).await?,
+    };
+    archive
+        .add(ExportFile::new("tools.json", ExportSchema::Tools, &tools)?)
+        .await?;
+
+    // Covers every analysis the user has run rather than the default 30 days.
+    let window = UsageWindow::all_time();
+    let summary =
+        analyses::summarize_usage(&mut *conn, Some(username), &window, &state.db_timezone).await?;
+    let file = ExportFile::new(
+        "analyses-summary.json",
+        ExportSchema::AnalysesSummary,
+        &summary,
+    )?;
+    archive.add(file).await
+}
+
+/// Reads the user's data and sends it to the client as an archive.
+async fn write_export(
+    state: &DiscoenvState,
+    user_id: Uuid,
+    username: &str,
+    exported_at: DateTime<Utc>,
+    mut archive: ExportArchive,
+) -> Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    collect_export_files(&mut tx, state, username, &mut archive).await?;
+    tx.commit().await?;
+
+    archive.finish(user_id, username, exported_at).await
+}
+
+/// Export everything a user has stored
+///
+/// Returns an archive with each of the user's bags, their preferences document, saved searches,
+/// sessions, tools and an all-time analyses summary as separate JSON files, along with a
+/// `manifest.json` listing the files and the schema version of each. The archive is a zip file
+/// (`application/zip`) or a gzipped tarball (`application/gzip`) depending on the `Accept`
+/// header, and is a zip file if the header is missing. The archive is sent while it's being
+/// written, so the manifest comes last, and an error partway through cuts the response short.
+/// Users can export their own data, and administrators can export anyone's.
+#[utoipa::path(
+    get,
+    path = "/users/{username}/export",
+    params(
+        ("username" = String, Path, description = "A username"),
+        ("Accept" = Option<String>, Header, description = "application/zip or application/gzip"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The exported data", content_type = ["application/zip", "application/gzip"]),
+        (status = 400, description = "Bad request, or the Accept header doesn't list a supported format.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The user isn't the logged-in user or an administrator.",
+            body = DiscoError,
+            example = json!(DiscoError::Forbidden("forbidden".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "users"
+)]
+pub async fn export_user_data(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+) -> response::Result<impl IntoResponse, DiscoError> {
+    let format = match headers.get(header::ACCEPT) {
+        None => ArchiveFormat::Zip,
+        Some(v) => v
+            .to_str()
+            .ok()
+            .and_then(ArchiveFormat::from_accept)
+            .ok_or_else(|| {
+                DiscoError::BadRequest(
+                    "exports are only available as application/zip or application/gzip".to_owned(),
+                )
+            })?,
+    };
+
+    let caller = common::fix_username(
+        user_info.preferred_username.as_deref().unwrap_or_default(),
+        &state.handler_config,
+    );
+    let username = common::fix_username(&username, &state.handler_config);
+    if username != caller && !is_admin(&user_info, &state.admin_entitlements) {
+        return Err(DiscoError::Forbidden(format!(
+            "{} can't export the data of {}",
+            caller, username
+        )));
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let username = if username == caller {
+        common::validate_user(&mut tx, &user_info, &state).await?
+    } else {
+        common::validate_username(&mut tx, &username, &state.handler_config).await?
+    };
+    let user_id = users::user_id(&mut tx, &username).await?;
+    tx.commit().await?;
+
+    let exported_at = Utc::now();
+    let file_name = format!(
+        "{}-export-{}.{}",
+        username,
+        exported_at.format("%Y%m%d"),
+        format.extension()
+    );
+
+    // The data is read and archived in a separate task and handed over a small
+    // channel, so the archive never has to be held in memory. Errors end the
+    // response early.
+    let (sender, receiver) = mpsc::channel(16);
+    let archive = ExportArchive::new(format, exported_at, sender.clone());
+    tokio::spawn(async move {
+        if let Err(e) = write_export(&state, user_id, &username, exported_at, archive).await {
+            tracing::error!("error exporting the data of {username}: {e}");
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((chunk, receiver))
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        StreamBody::new(body),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::{Cursor, Read};
+
+    fn files() -> Vec<ExportFile> {
+        vec![
+            ExportFile::new(
+                "preferences.json",
+                ExportSchema::Preferences,
+                &serde_json::json!({"theme": "dark"}),
+            )
+            .unwrap(),
+            ExportFile::new("tools.json", ExportSchema::Tools, &serde_json::json!([])).unwrap(),
+        ]
+    }
+
+    /// Writes the files into an archive and puts the parts that were sent back
+    /// together.
+    async fn write_archive(format: ArchiveFormat) -> Vec<u8> {
+        let (sender, mut receiver) = mpsc::channel(64);
+        let mut archive = ExportArchive::new(format, Utc::now(), sender);
+        for file in files() {
+            archive.add(file).await.unwrap();
+        }
+        archive
+            .finish(Uuid::nil(), "u@example.org", Utc::now())
+            .await
+            .unwrap();
+
+        let mut written = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            written.extend_from_slice(&chunk.unwrap());
+        }
+        written
+    }
+
+    #[test]
+    fn test_archive_format_from_accept() {
+        assert_eq!(
+            ArchiveFormat::from_accept("application/zip"),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(ArchiveFormat::from_accept("*/*"), Some(ArchiveFormat::Zip));
+        assert_eq!(
+            ArchiveFormat::from_accept("text/html, application/gzip;q=0.9"),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(ArchiveFormat::from_accept("application/json"), None);
+        assert_eq!(
+            ArchiveFormat::from_accept("application/zip;q=0.2, application/*;q=0.8"),
+            Some(ArchiveFormat::TarGz)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_zip_archive() {
+        let archive = write_archive(ArchiveFormat::Zip).await;
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+
+        assert_eq!(
+            zip.len(),
+            3,
+            "the manifest and both files should be in the archive"
+        );
+
+        let mut manifest = String::new();
+        zip.by_name("manifest.json")
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["format_version"], EXPORT_FORMAT_VERSION);
+        assert_eq!(manifest["username"], "u@example.org");
+        assert_eq!(manifest["files"][0]["path"], "preferences.json");
+        assert_eq!(manifest["files"][0]["schema"], "preferences");
+        assert_eq!(manifest["files"][0]["schema_version"], 1);
+
+        let mut prefs = String::new();
+        zip.by_name("preferences.json")
+            .unwrap()
+            .read_to_string(&mut prefs)
+            .unwrap();
+        assert!(prefs.contains("dark"));
+    }
+
+    #[tokio::test]
+    async fn test_write_tar_gz_archive() {
+        let archive = write_archive(ArchiveFormat::TarGz).await;
+        let mut tar = tar::Archive::new(GzDecoder::new(archive.as_slice()));
+
+        let paths: Vec<String> = tar
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["preferences.json", "tools.json", "manifest.json"]
+        );
+    }
+}
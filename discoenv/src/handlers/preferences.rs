@@ -7,7 +7,6 @@ use sqlx::types::JsonValue;
 use std::sync::Arc;
 
 use crate::{db::preferences::{self, Preferences}, app_state::DiscoenvState};
-use crate::db::users;
 use crate::errors::DiscoError;
 use crate::auth::UserInfo;
 
@@ -36,12 +35,12 @@ pub async fn get_user_preferences(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
 ) -> response::Result<Json<Preferences>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
-    Ok(Json(preferences::user_preferences(&mut tx, &user).await?))
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+    let retval = preferences::user_preferences(&mut tx, &user).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
 }
 
 
@@ -73,12 +72,8 @@ pub async fn add_user_preferences(
     Extension(user_info): Extension<UserInfo>,
     Json(preferences): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<common::ID>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     let pref_str =
         serde_json::to_string(&preferences).map_err(|e| DiscoError::BadRequest(e.to_string()))?;
@@ -118,12 +113,8 @@ pub async fn update_user_preferences(
     Extension(user_info): Extension<UserInfo>,
     Json(preferences): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<Preferences>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     let pref_str =
         serde_json::to_string(&preferences).map_err(|e| DiscoError::BadRequest(e.to_string()))?;
@@ -162,12 +153,8 @@ pub async fn delete_user_preferences(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
 ) -> response::Result<(), DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
     preferences::delete_user_preferences(&mut tx, &user).await?;
 
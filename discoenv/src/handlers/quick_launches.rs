@@ -0,0 +1,449 @@
+use axum::{
+    extract::{Extension, Json, Path, State},
+    response,
+};
+use sqlx::types::{JsonValue, Uuid};
+use std::sync::Arc;
+
+use crate::app_state::DiscoenvState;
+use crate::auth::UserInfo;
+use crate::db::apps;
+use crate::db::quick_launches::{
+    self, AppParameter, QuickLaunch, QuickLaunchRequest, QuickLaunches,
+};
+use crate::errors::DiscoError;
+
+use super::common;
+
+/// Makes sure the submission only sets parameters the app version has, and
+/// sets every required parameter that doesn't have a default value.
+fn validate_submission(submission: &JsonValue, params: &[AppParameter]) -> Result<(), DiscoError> {
+    let submission = submission
+        .as_object()
+        .ok_or_else(|| DiscoError::BadRequest("submission must be an object".to_owned()))?;
+
+    let empty = serde_json::Map::new();
+    let config = match submission.get("config") {
+        None | Some(JsonValue::Null) => &empty,
+        Some(JsonValue::Object(config)) => config,
+        Some(_) => {
+            return Err(DiscoError::BadRequest(
+                "submission config must be an object".to_owned(),
+            ))
+        }
+    };
+
+    if let Some(key) = config
+        .keys()
+        .find(|key| !params.iter().any(|p| &p.key == *key))
+    {
+        return Err(DiscoError::BadRequest(format!(
+            "{} is not a parameter of the app version",
+            key
+        )));
+    }
+
+    for param in params.iter().filter(|p| p.required && !p.has_default) {
+        match config.get(&param.key) {
+            None | Some(JsonValue::Null) => {}
+            Some(JsonValue::String(s)) if s.is_empty() => {}
+            Some(_) => continue,
+        }
+        return Err(DiscoError::BadRequest(format!(
+            "required parameter {} has no value",
+            param.key
+        )));
+    }
+
+    Ok(())
+}
+
+/// Validates the quick launch against the app version it references and
+/// returns the ID of the app the version belongs to.
+async fn validate_quick_launch(
+    conn: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ql: &QuickLaunchRequest,
+) -> Result<Uuid, DiscoError> {
+    if ql.name.trim().is_empty() {
+        return Err(DiscoError::BadRequest("name must not be empty".to_owned()));
+    }
+
+    let app_id = quick_launches::get_version_app_id(&mut *conn, &ql.app_version_id)
+        .await?
+        .ok_or_else(|| {
+            DiscoError::NotFound(format!("app version {} was not found", ql.app_version_id))
+        })?;
+
+    let params =
+        quick_launches::list_app_version_parameters(&mut *conn, &ql.app_version_id).await?;
+    validate_submission(&ql.submission, &params)?;
+
+    Ok(app_id)
+}
+
+/// Returns the error for a quick launch the user can't change: Forbidden if
+/// they can see it, NotFound otherwise.
+async fn not_owned_error(
+    conn: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    username: &str,
+    quick_launch_id: &Uuid,
+) -> Result<DiscoError, DiscoError> {
+    Ok(
+        match quick_launches::get_quick_launch(&mut *conn, username, quick_launch_id).await? {
+            Some(_) => DiscoError::Forbidden(format!(
+                "quick launch {} belongs to another user",
+                quick_launch_id
+            )),
+            None => DiscoError::NotFound(format!("quick launch {} was not found", quick_launch_id)),
+        },
+    )
+}
+
+/// List the logged-in user's quick launches
+///
+/// Only the quick launches the user created are listed, sorted by name.
+#[utoipa::path(
+    get,
+    path = "/quick-launches",
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The user's quick launches", body = QuickLaunches),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "quick-launches"
+)]
+pub async fn get_quick_launches(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+) -> response::Result<Json<QuickLaunches>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let quick_launches = quick_launches::list_user_quick_launches(&mut tx, &user).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(QuickLaunches { quick_launches }))
+}
+
+/// Add a quick launch for the logged-in user
+///
+/// The submission's config is checked against the app version's parameters: it can only set
+/// parameters the version has, and has to set every required parameter without a default value.
+#[utoipa::path(
+    post,
+    path = "/quick-launches",
+    request_body = QuickLaunchRequest,
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The new quick launch", body = QuickLaunch),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or app version wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("app version wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "quick-launches"
+)]
+pub async fn add_quick_launch(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Json(ql): Json<QuickLaunchRequest>,
+) -> response::Result<Json<QuickLaunch>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let app_id = validate_quick_launch(&mut tx, &ql).await?;
+    let id = quick_launches::add_quick_launch(&mut tx, &user, &app_id, &ql).await?;
+    let quick_launch = quick_launches::get_quick_launch(&mut tx, &user, &id)
+        .await?
+        .ok_or_else(|| DiscoError::Internal(format!("quick launch {} was not added", id)))?;
+
+    tx.commit().await?;
+
+    Ok(Json(quick_launch))
+}
+
+/// Get a quick launch
+///
+/// Returns the quick launch if the logged-in user created it or it's public.
+#[utoipa::path(
+    get,
+    path = "/quick-launches/{quick_launch_id}",
+    params(
+        ("quick_launch_id" = String, Path, description = "A quick launch's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The quick launch", body = QuickLaunch),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or quick launch wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("quick launch wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "quick-launches"
+)]
+pub async fn get_quick_launch(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(quick_launch_id): Path<Uuid>,
+) -> response::Result<Json<QuickLaunch>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let quick_launch = quick_launches::get_quick_launch(&mut tx, &user, &quick_launch_id)
+        .await?
+        .ok_or_else(|| {
+            DiscoError::NotFound(format!("quick launch {} was not found", quick_launch_id))
+        })?;
+
+    tx.commit().await?;
+
+    Ok(Json(quick_launch))
+}
+
+/// Update one of the logged-in user's quick launches
+///
+/// Replaces the whole quick launch. The submission is validated the same way as when a quick
+/// launch is added. Only the user who created the quick launch can update it.
+#[utoipa::path(
+    put,
+    path = "/quick-launches/{quick_launch_id}",
+    params(
+        ("quick_launch_id" = String, Path, description = "A quick launch's UUID"),
+    ),
+    request_body = QuickLaunchRequest,
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The updated quick launch", body = QuickLaunch),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The quick launch belongs to another user.",
+            body = DiscoError,
+            example = json!(DiscoError::Forbidden("forbidden".to_owned()).create_service_error())),
+        (status = 404, description = "The user, quick launch, or app version wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("quick launch wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "quick-launches"
+)]
+pub async fn update_quick_launch(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(quick_launch_id): Path<Uuid>,
+    Json(ql): Json<QuickLaunchRequest>,
+) -> response::Result<Json<QuickLaunch>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let app_id = validate_quick_launch(&mut tx, &ql).await?;
+    if quick_launches::update_quick_launch(&mut tx, &user, &quick_launch_id, &app_id, &ql).await?
+        == 0
+    {
+        return Err(not_owned_error(&mut tx, &user, &quick_launch_id).await?);
+    }
+
+    let quick_launch = quick_launches::get_quick_launch(&mut tx, &user, &quick_launch_id)
+        .await?
+        .ok_or_else(|| {
+            DiscoError::NotFound(format!("quick launch {} was not found", quick_launch_id))
+        })?;
+
+    tx.commit().await?;
+
+    Ok(Json(quick_launch))
+}
+
+/// Delete one of the logged-in user's quick launches
+///
+/// Only the user who created the quick launch can delete it. Quick launches that are still
+/// someone's default for an app version or in someone's favorites can't be deleted.
+#[utoipa::path(
+    delete,
+    path = "/quick-launches/{quick_launch_id}",
+    params(
+        ("quick_launch_id" = String, Path, description = "A quick launch's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The quick launch was deleted"),
+        (status = 400, description = "Bad request, or the quick launch is still in use.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The quick launch belongs to another user.",
+            body = DiscoError,
+            example = json!(DiscoError::Forbidden("forbidden".to_owned()).create_service_error())),
+        (status = 404, description = "The user or quick launch wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("quick launch wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "quick-launches"
+)]
+pub async fn delete_quick_launch(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(quick_launch_id): Path<Uuid>,
+) -> response::Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    // Defaults and favorites that refer to the quick launch show up as a
+    // foreign key violation.
+    let deleted = match quick_launches::delete_quick_launch(&mut tx, &user, &quick_launch_id).await
+    {
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => {
+            return Err(DiscoError::BadRequest(format!(
+                "quick launch {} is still in use and can't be deleted",
+                quick_launch_id
+            )))
+        }
+        result => result?,
+    };
+
+    if deleted == 0 {
+        return Err(not_owned_error(&mut tx, &user, &quick_launch_id).await?);
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// List the quick launches for an app
+///
+/// Returns the logged-in user's own quick launches for the app along with the public ones,
+/// sorted by name.
+#[utoipa::path(
+    get,
+    path = "/apps/{app_id}/quick-launches",
+    params(
+        ("app_id" = String, Path, description = "An app's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The app's quick launches", body = QuickLaunches),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or app wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("app wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "quick-launches"
+)]
+pub async fn get_app_quick_launches(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(app_id): Path<Uuid>,
+) -> response::Result<Json<QuickLaunches>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !apps::app_exists(&mut tx, &app_id).await? {
+        return Err(DiscoError::NotFound(format!(
+            "app {} was not found",
+            app_id
+        )));
+    }
+
+    let quick_launches = quick_launches::list_app_quick_launches(&mut tx, &user, &app_id).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(QuickLaunches { quick_launches }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn params() -> Vec<AppParameter> {
+        vec![
+            AppParameter {
+                key: "s1_required".to_owned(),
+                required: true,
+                has_default: false,
+            },
+            AppParameter {
+                key: "s1_defaulted".to_owned(),
+                required: true,
+                has_default: true,
+            },
+            AppParameter {
+                key: "s1_optional".to_owned(),
+                required: false,
+                has_default: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_validate_submission() {
+        for submission in [
+            json!({"config": {"s1_required": "a"}}),
+            json!({"config": {"s1_required": 1, "s1_optional": ""}, "name": "x"}),
+            json!({"config": {"s1_required": false, "s1_defaulted": null}}),
+        ] {
+            assert!(
+                validate_submission(&submission, &params()).is_ok(),
+                "{} should be valid",
+                submission
+            );
+        }
+
+        for submission in [
+            json!([]),
+            json!({}),
+            json!({"config": []}),
+            json!({"config": {"s1_optional": "a"}}),
+            json!({"config": {"s1_required": ""}}),
+            json!({"config": {"s1_required": null}}),
+            json!({"config": {"s1_required": "a", "s2_unknown": "b"}}),
+        ] {
+            assert!(
+                validate_submission(&submission, &params()).is_err(),
+                "{} should be invalid",
+                submission
+            );
+        }
+
+        assert!(validate_submission(&json!({}), &[]).is_ok());
+    }
+}
@@ -1,13 +1,17 @@
 use axum::{
-    extract::{Json, Extension, State},
+    extract::{Json, Extension, Path, State},
     http::StatusCode,
     response,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::Map;
-use sqlx::types::JsonValue;
+use sqlx::types::{JsonValue, Uuid};
 use std::sync::Arc;
+use utoipa::ToSchema;
 
-use crate::db::searches::{self, SavedSearches};
+use crate::db::searches::{
+    self, SavedSearchListing, SavedSearches, SearchShares, SharedSearches,
+};
 use crate::db::users;
 use crate::errors::DiscoError;
 use crate::app_state::DiscoenvState;
@@ -17,16 +21,17 @@ use super::common;
 
 /// Get the saved searches for a user.
 ///
-/// Returns the JSON document containing the saved searches for a user.
+/// Returns the JSON document containing the saved searches for a user, along with the searches
+/// other users have shared with them. Each shared search includes the username of its owner.
 #[utoipa::path(
     get,
     path = "/searches",
     responses(
-        (status = 200, description = "Returned the user's saved searches", body = SavedSearches),
+        (status = 200, description = "Returned the user's saved searches", body = SavedSearchListing),
         (status = 400, description = "Bad request.", 
             body = DiscoError, 
             example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error()) ),
-        (status = 404, description = "User didn't exist.", 
+        (status = 404, description = "User didn't exist or had no saved or shared searches.", 
             body = DiscoError, 
             example = json!(DiscoError::NotFound("user doesn't exist".to_owned()).create_service_error())),
         (status = 500, description = "Internal error.", 
@@ -38,14 +43,28 @@ use super::common;
 pub async fn get_saved_searches(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
-) -> response::Result<Json<SavedSearches>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
+) -> response::Result<Json<SavedSearchListing>, DiscoError> {
     let mut tx = state.pool.begin().await?;
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
-    let retval = searches::get_saved_searches(&mut tx, &user).await?;
-    Ok(Json(retval))
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let shared_searches = searches::list_shared_searches(&mut tx, &user).await?.shared_searches;
+    let saved = if searches::has_saved_searches(&mut tx, &user).await? {
+        Some(searches::get_saved_searches(&mut tx, &user).await?)
+    } else if shared_searches.is_empty() {
+        return Err(DiscoError::NotFound(format!(
+            "user {} has no saved searches",
+            user
+        )));
+    } else {
+        None
+    };
+
+    tx.commit().await?;
+
+    Ok(Json(SavedSearchListing {
+        saved,
+        shared_searches,
+    }))
 }
 
 /// Whether the user has saved searches.
@@ -107,11 +126,8 @@ pub async fn add_saved_searches(
     Extension(user_info): Extension<UserInfo>,
     Json(saved_searches): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<common::ID>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
     let searches_str = serde_json::to_string(&saved_searches)
         .map_err(|e| DiscoError::BadRequest(e.to_string()))?;
     let id = searches::add_saved_searches(&mut tx, &user, &searches_str).await?;
@@ -145,11 +161,8 @@ pub async fn update_saved_searches(
     Extension(user_info): Extension<UserInfo>,
     Json(saved_searches): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<SavedSearches>, DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
     let searches_str = serde_json::to_string(&saved_searches)
         .map_err(|e| DiscoError::BadRequest(e.to_string()))?;
     searches::update_saved_searches(&mut tx, &user, &searches_str).await?;
@@ -182,12 +195,242 @@ pub async fn delete_saved_searches(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
 ) -> Result<(), DiscoError> {
-    let user = common::fix_username(&user_info.preferred_username.unwrap_or_default(), &state.handler_config);
     let mut tx = state.pool.begin().await?;
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
     searches::delete_saved_searches(&mut tx, &user).await?;
     tx.commit().await?;
     Ok(())
 }
+
+/// The users a saved search should be shared with.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct ShareRequest {
+    pub usernames: Vec<String>,
+}
+
+/// Optional settings used when copying a shared search.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct CopyRequest {
+    /// The name to store the copy under. Defaults to the shared search's name.
+    pub name: Option<String>,
+}
+
+/// Lists the saved searches the user has shared with others.
+///
+/// Returns each share along with the username of the recipient.
+#[utoipa::path(
+    get,
+    path = "/searches/shares",
+    responses(
+        (status = 200, description = "Returned the user's search shares", body = SearchShares),
+        (status = 404, description = "User didn't exist.", 
+            body = DiscoError, 
+            example = json!(DiscoError::NotFound("user doesn't exist".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError, 
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error()))
+    ),
+    tag = "searches"
+)]
+pub async fn list_search_shares(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+) -> response::Result<Json<SearchShares>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+    let retval = searches::list_search_shares(&mut tx, &user).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Shares one of the user's saved searches with other users.
+///
+/// The recipients get read-only access to the search and can copy it into their own saved searches.
+#[utoipa::path(
+    put,
+    path = "/searches/shares/{name}",
+    params(
+        ("name" = String, Path, description = "The name of the saved search"),
+    ),
+    request_body = ShareRequest,
+    responses(
+        (status = 200, description = "The search was shared", body = SearchShares),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError, 
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error()) ),
+        (status = 404, description = "User or search didn't exist.", 
+            body = DiscoError, 
+            example = json!(DiscoError::NotFound("user doesn't exist".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError, 
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error()))
+    ),
+    tag = "searches"
+)]
+pub async fn share_saved_search(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(name): Path<String>,
+    Json(share): Json<ShareRequest>,
+) -> response::Result<Json<SearchShares>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let saved = searches::get_saved_searches(&mut tx, &user).await?;
+    if searches::find_search(&saved.saved_searches, &name).is_none() {
+        return Err(DiscoError::NotFound(format!("saved search {} was not found", name)));
+    }
+
+    for recipient in share.usernames.iter() {
+        let recipient = common::fix_username(recipient, &state.handler_config);
+        if recipient == user {
+            return Err(DiscoError::BadRequest("a search can't be shared with its owner".to_owned()));
+        }
+        if !users::username_exists(&mut tx, &recipient).await? {
+            return Err(DiscoError::NotFound(format!("user {} was not found", recipient)));
+        }
+        searches::add_search_share(&mut tx, &user, &recipient, &name).await?;
+    }
+
+    let retval = searches::list_search_shares(&mut tx, &user).await?;
+    tx.commit().await?;
+    Ok(Json(retval))
+}
+
+/// Revokes a user's access to a shared search.
+///
+/// Any copies the recipient already made are left in place.
+#[utoipa::path(
+    delete,
+    path = "/searches/shares/{name}/{recipient}",
+    params(
+        ("name" = String, Path, description = "The name of the saved search"),
+        ("recipient" = String, Path, description = "The username the search was shared with"),
+    ),
+    responses(
+        (status = 200, description = "The share was revoked"),
+        (status = 404, description = "User or share didn't exist.", 
+            body = DiscoError, 
+            example = json!(DiscoError::NotFound("share doesn't exist".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError, 
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error()))
+    ),
+    tag = "searches"
+)]
+pub async fn revoke_search_share(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path((name, recipient)): Path<(String, String)>,
+) -> Result<(), DiscoError> {
+    let recipient = common::fix_username(&recipient, &state.handler_config);
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+    if searches::delete_search_share(&mut tx, &user, &recipient, &name).await? == 0 {
+        return Err(DiscoError::NotFound(format!(
+            "saved search {} is not shared with {}",
+            name, recipient
+        )));
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Lists the saved searches other users have shared with the user.
+///
+/// Each entry includes the username of the search's owner.
+#[utoipa::path(
+    get,
+    path = "/searches/shared",
+    responses(
+        (status = 200, description = "Returned the searches shared with the user", body = SharedSearches),
+        (status = 404, description = "User didn't exist.", 
+            body = DiscoError, 
+            example = json!(DiscoError::NotFound("user doesn't exist".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError, 
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error()))
+    ),
+    tag = "searches"
+)]
+pub async fn get_shared_searches(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+) -> response::Result<Json<SharedSearches>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+    let retval = searches::list_shared_searches(&mut tx, &user).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Copies a shared search into the user's own saved searches.
+///
+/// Returns the user's updated saved searches document.
+#[utoipa::path(
+    post,
+    path = "/searches/shared/{share_id}/copy",
+    params(
+        ("share_id" = String, Path, description = "The UUID of the share"),
+    ),
+    request_body = CopyRequest,
+    responses(
+        (status = 200, description = "The search was copied", body = SavedSearches),
+        (status = 400, description = "Bad request.", 
+            body = DiscoError, 
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error()) ),
+        (status = 404, description = "User or share didn't exist.", 
+            body = DiscoError, 
+            example = json!(DiscoError::NotFound("share doesn't exist".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.", 
+            body = DiscoError, 
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error()))
+    ),
+    tag = "searches"
+)]
+pub async fn copy_shared_search(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(share_id): Path<Uuid>,
+    Json(copy): Json<CopyRequest>,
+) -> response::Result<Json<SavedSearches>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let shared = searches::get_shared_search(&mut tx, &user, &share_id).await?;
+    if shared.search.is_null() {
+        return Err(DiscoError::NotFound(format!(
+            "saved search {} no longer exists",
+            shared.name
+        )));
+    }
+    let name = copy.name.unwrap_or(shared.name);
+
+    if searches::has_saved_searches(&mut tx, &user).await? {
+        let saved = searches::get_saved_searches(&mut tx, &user).await?;
+        let mut doc: Map<String, JsonValue> = serde_json::from_str(&saved.saved_searches)
+            .map_err(|e| DiscoError::UnmarshalFailure(e.to_string()))?;
+        if doc.contains_key(&name) {
+            return Err(DiscoError::BadRequest(format!(
+                "a saved search named {} already exists",
+                name
+            )));
+        }
+        doc.insert(name, shared.search);
+        let searches_str = serde_json::to_string(&doc)
+            .map_err(|e| DiscoError::MarshalFailure(e.to_string()))?;
+        searches::update_saved_searches(&mut tx, &user, &searches_str).await?;
+    } else {
+        let mut doc = Map::new();
+        doc.insert(name, shared.search);
+        let searches_str = serde_json::to_string(&doc)
+            .map_err(|e| DiscoError::MarshalFailure(e.to_string()))?;
+        searches::add_saved_searches(&mut tx, &user, &searches_str).await?;
+    }
+
+    let retval = searches::get_saved_searches(&mut tx, &user).await?;
+    tx.commit().await?;
+    Ok(Json(retval))
+}
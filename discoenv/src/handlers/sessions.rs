@@ -1,55 +1,165 @@
 use axum::{
-    extract::{Extension, Json, State},
+    extract::{Extension, Json, Path, Query, State},
     response,
 };
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Map;
-use sqlx::types::JsonValue;
+use sqlx::types::{JsonValue, Uuid};
 use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
 
 use crate::app_state::DiscoenvState;
 use crate::auth::UserInfo;
-use crate::db::sessions::{self, Session};
+use crate::db::sessions::{self, Session, Sessions};
 use crate::db::users;
 use crate::errors::DiscoError;
 
 use super::common;
 
+/// Optional settings for a session passed in as query parameters.
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SessionParams {
+    /// A label for the device the session belongs to.
+    pub device_label: Option<String>,
+
+    /// How long the session can go unused before it expires.
+    pub ttl_seconds: Option<i32>,
+}
+
+/// Checks a session TTL the same way whether it came from the query or the body.
+fn validate_ttl(ttl: Option<i32>) -> Result<(), DiscoError> {
+    if let Some(ttl) = ttl {
+        if ttl <= 0 {
+            return Err(DiscoError::ParameterInvalid(
+                "ttl_seconds must be greater than 0".to_owned(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl SessionParams {
+    fn validate(&self) -> Result<(), DiscoError> {
+        validate_ttl(self.ttl_seconds)
+    }
+}
+
+/// The new contents of a session, along with any settings to change.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct SessionUpdate {
+    /// The session, which replaces the stored one.
+    #[schema(value_type = Object)]
+    pub session: Map<String, JsonValue>,
+
+    /// A new label for the device the session belongs to. Leave it out to keep the current one.
+    pub device_label: Option<String>,
+
+    /// A new TTL for the session. Leave it out to keep the current one, or pass `null` to
+    /// remove it so that the session never expires.
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<i32>)]
+    pub ttl_seconds: Option<Option<i32>>,
+}
+
+impl SessionUpdate {
+    fn validate(&self) -> Result<(), DiscoError> {
+        validate_ttl(self.ttl_seconds.flatten())
+    }
+}
+
+/// Tells a field that was set to `null` apart from one that was left out, which `serde`
+/// otherwise treats the same way.
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Lists the user's sessions.
+///
+/// Returns all of the user's unexpired sessions, most recently used first.
+#[utoipa::path(
+    get,
+    path = "/sessions/{username}",
+    params(
+        ("username" = String, Path, description = "A username"),
+    ),
+    responses(
+        (status = 200, description = "The user's sessions", body = Sessions),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "sessions"
+)]
 pub async fn get_user_sessions(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
-) -> response::Result<Json<Session>, DiscoError> {
-    let user = common::fix_username(
-        &user_info.preferred_username.unwrap_or_default(),
-        &state.handler_config,
-    );
+) -> response::Result<Json<Sessions>, DiscoError> {
     let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let retval = sessions::list_sessions(&mut tx, &user, state.keyring.as_ref()).await?;
+    tx.commit().await?;
 
-    Ok(Json(sessions::get_session(&mut tx, &user).await?))
+    Ok(Json(retval))
 }
 
+/// Adds a new session for the user.
+///
+/// The request body is stored as the session. Returns the UUID of the new session.
+#[utoipa::path(
+    put,
+    path = "/sessions/{username}",
+    params(
+        ("username" = String, Path, description = "A username"),
+        SessionParams,
+    ),
+    request_body = JsonValue::Object,
+    responses(
+        (status = 200, description = "The session was added", body = ID),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "sessions"
+)]
 pub async fn add_user_sessions(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
+    Query(params): Query<SessionParams>,
     Json(sessions): Json<Map<String, JsonValue>>,
 ) -> response::Result<Json<common::ID>, DiscoError> {
-    let user = common::fix_username(
-        &user_info.preferred_username.unwrap_or_default(),
-        &state.handler_config,
-    );
-    let mut tx = state.pool.begin().await?;
+    params.validate()?;
 
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
-    }
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
+    let user_id = users::user_id(&mut tx, &user).await?;
     let sessions_str =
         serde_json::to_string(&sessions).map_err(|e| DiscoError::BadRequest(e.to_string()))?;
 
-    let id = sessions::add_session(&mut tx, &user, &sessions_str).await?;
+    let id = sessions::add_session(
+        &mut tx,
+        &user_id,
+        &sessions_str,
+        params.device_label.as_deref(),
+        params.ttl_seconds,
+        state.keyring.as_ref(),
+    )
+    .await?;
 
     tx.commit().await?;
 
@@ -58,50 +168,197 @@ pub async fn add_user_sessions(
     Ok(Json(p))
 }
 
-pub async fn update_user_sessions(
+/// Deletes all of the user's sessions.
+#[utoipa::path(
+    delete,
+    path = "/sessions/{username}",
+    params(
+        ("username" = String, Path, description = "A username"),
+    ),
+    responses(
+        (status = 200, description = "The sessions were deleted"),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "sessions"
+)]
+pub async fn delete_user_sessions(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
-    Json(sessions): Json<Map<String, JsonValue>>,
+) -> response::Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    sessions::delete_sessions(&mut tx, &user).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Gets one of the user's sessions.
+///
+/// Reading a session counts as using it, so its expiration is pushed back.
+#[utoipa::path(
+    get,
+    path = "/sessions/{username}/{session_id}",
+    params(
+        ("username" = String, Path, description = "A username"),
+        ("session_id" = String, Path, description = "A session's UUID"),
+    ),
+    responses(
+        (status = 200, description = "The session", body = Session),
+        (status = 404, description = "The user or session was not found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("session wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "sessions"
+)]
+pub async fn get_user_session(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path((_username, session_id)): Path<(String, Uuid)>,
 ) -> response::Result<Json<Session>, DiscoError> {
-    let user = common::fix_username(
-        &user_info.preferred_username.unwrap_or_default(),
-        &state.handler_config,
-    );
     let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
+    if !sessions::session_exists(&mut tx, &user, &session_id).await? {
+        return Err(DiscoError::NotFound(format!("session {} was not found", session_id)));
     }
 
-    let sessions_str =
-        serde_json::to_string(&sessions).map_err(|e| DiscoError::BadRequest(e.to_string()))?;
+    let retval = sessions::get_session(&mut tx, &user, &session_id, state.keyring.as_ref()).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Updates one of the user's sessions.
+///
+/// The session in the request body replaces the stored one. The device label and TTL are only
+/// changed if they're included in the request body; a `null` TTL removes it.
+#[utoipa::path(
+    post,
+    path = "/sessions/{username}/{session_id}",
+    params(
+        ("username" = String, Path, description = "A username"),
+        ("session_id" = String, Path, description = "A session's UUID"),
+    ),
+    request_body = SessionUpdate,
+    responses(
+        (status = 200, description = "The updated session", body = Session),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or session was not found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("session wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "sessions"
+)]
+pub async fn update_user_session(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path((_username, session_id)): Path<(String, Uuid)>,
+    Json(update): Json<SessionUpdate>,
+) -> response::Result<Json<Session>, DiscoError> {
+    update.validate()?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
-    sessions::update_session(&mut tx, &user, &sessions_str).await?;
+    if !sessions::session_exists(&mut tx, &user, &session_id).await? {
+        return Err(DiscoError::NotFound(format!("session {} was not found", session_id)));
+    }
+
+    let user_id = users::user_id(&mut tx, &user).await?;
+    let sessions_str = serde_json::to_string(&update.session)
+        .map_err(|e| DiscoError::BadRequest(e.to_string()))?;
 
-    let retval = sessions::get_session(&mut tx, &user).await?;
+    sessions::update_session(
+        &mut tx,
+        &user_id,
+        &session_id,
+        &sessions_str,
+        update.device_label.as_deref(),
+        update.ttl_seconds,
+        state.keyring.as_ref(),
+    )
+    .await?;
+
+    let retval = sessions::get_session(&mut tx, &user, &session_id, state.keyring.as_ref()).await?;
 
     tx.commit().await?;
 
     Ok(Json(retval))
 }
 
-pub async fn delete_user_sessions(
+/// Deletes one of the user's sessions.
+#[utoipa::path(
+    delete,
+    path = "/sessions/{username}/{session_id}",
+    params(
+        ("username" = String, Path, description = "A username"),
+        ("session_id" = String, Path, description = "A session's UUID"),
+    ),
+    responses(
+        (status = 200, description = "The session was deleted"),
+        (status = 404, description = "The user or session was not found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("session wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "sessions"
+)]
+pub async fn delete_user_session(
     State(state): State<Arc<DiscoenvState>>,
     Extension(user_info): Extension<UserInfo>,
+    Path((_username, session_id)): Path<(String, Uuid)>,
 ) -> response::Result<(), DiscoError> {
-    let user = common::fix_username(
-        &user_info.preferred_username.unwrap_or_default(),
-        &state.handler_config,
-    );
     let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
 
-    if !users::username_exists(&mut tx, &user).await? {
-        return Err(DiscoError::NotFound(format!("user {} was not found", user)));
+    if sessions::delete_session(&mut tx, &user, &session_id).await? == 0 {
+        return Err(DiscoError::NotFound(format!("session {} was not found", session_id)));
     }
 
-    sessions::delete_session(&mut tx, &user).await?;
-
     tx.commit().await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_update_ttl() {
+        let update: SessionUpdate = serde_json::from_str(r#"{"session": {}}"#).unwrap();
+        assert_eq!(update.ttl_seconds, None);
+
+        let update: SessionUpdate =
+            serde_json::from_str(r#"{"session": {}, "ttl_seconds": null}"#).unwrap();
+        assert_eq!(update.ttl_seconds, Some(None));
+
+        let update: SessionUpdate =
+            serde_json::from_str(r#"{"session": {}, "ttl_seconds": 3600}"#).unwrap();
+        assert_eq!(update.ttl_seconds, Some(Some(3600)));
+        assert!(update.validate().is_ok());
+
+        let update: SessionUpdate =
+            serde_json::from_str(r#"{"session": {}, "ttl_seconds": 0}"#).unwrap();
+        assert!(update.validate().is_err());
+    }
+}
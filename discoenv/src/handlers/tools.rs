@@ -0,0 +1,549 @@
+use axum::{
+    extract::{Extension, Json, Path, Query, State},
+    response,
+};
+use debuff::{containers::Image, tools::Tool};
+use sqlx::types::Uuid;
+use std::sync::Arc;
+
+use crate::app_state::DiscoenvState;
+use crate::auth::UserInfo;
+use crate::db::analyses::UsageWindow;
+use crate::db::tools::{self, ToolDetail, ToolFilter, ToolUsage, Tools};
+use crate::errors::DiscoError;
+
+use super::common;
+
+/// The longest a container image name can be, including the registry host.
+const MAX_IMAGE_NAME_LENGTH: usize = 255;
+
+/// The longest a container image tag can be.
+const MAX_IMAGE_TAG_LENGTH: usize = 128;
+
+/// Returns whether the path component of an image name is valid: lowercase letters and
+/// digits, separated by a period, one or two underscores, or any number of dashes.
+fn valid_path_component(component: &str) -> bool {
+    let mut chars = component.chars().peekable();
+    let mut expect_alnum = true;
+    while let Some(c) = chars.next() {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            expect_alnum = false;
+            continue;
+        }
+        if expect_alnum {
+            return false;
+        }
+        let valid_separator = match c {
+            '.' => true,
+            '_' => {
+                if chars.peek() == Some(&'_') {
+                    chars.next();
+                }
+                true
+            }
+            '-' => {
+                while chars.peek() == Some(&'-') {
+                    chars.next();
+                }
+                true
+            }
+            _ => false,
+        };
+        if !valid_separator {
+            return false;
+        }
+        expect_alnum = true;
+    }
+    !expect_alnum
+}
+
+/// Returns whether the registry host of an image name is valid, e.g. "harbor.cyverse.org"
+/// or "localhost:5000".
+fn valid_registry_host(host: &str) -> bool {
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) => {
+            if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+                return false;
+            }
+            hostname
+        }
+        None => host,
+    };
+    hostname.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Makes sure the image name is a valid repository name without a tag or digest, e.g.
+/// "discoenv/jupyter-lab" or "harbor.cyverse.org/de/jupyter-lab".
+fn validate_image_name(name: &str) -> Result<(), DiscoError> {
+    let invalid = || DiscoError::BadRequest(format!("invalid container image name: {}", name));
+
+    if name.is_empty() || name.len() > MAX_IMAGE_NAME_LENGTH {
+        return Err(invalid());
+    }
+
+    let mut components: Vec<&str> = name.split('/').collect();
+
+    // The first component is a registry host if it looks like one.
+    let first = components[0];
+    let has_host = components.len() > 1
+        && (first.contains('.') || first.contains(':') || first == "localhost");
+    if has_host && !valid_registry_host(components.remove(0)) {
+        return Err(invalid());
+    }
+
+    if !components.into_iter().all(valid_path_component) {
+        return Err(invalid());
+    }
+
+    Ok(())
+}
+
+/// Makes sure the image tag is valid: up to 128 letters, digits, underscores, periods and
+/// dashes, not starting with a period or dash.
+fn validate_image_tag(tag: &str) -> Result<(), DiscoError> {
+    let valid = !tag.is_empty()
+        && tag.len() <= MAX_IMAGE_TAG_LENGTH
+        && !tag.starts_with(['.', '-'])
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+    if !valid {
+        return Err(DiscoError::BadRequest(format!(
+            "invalid container image tag: {}",
+            tag
+        )));
+    }
+    Ok(())
+}
+
+/// Checks the parts of a tool that can't be left to the database. Time limits are in
+/// seconds, with 0 meaning no limit, and can't be longer than the maximum analysis time
+/// limit. GPU-enabled tools need a time limit so that they can't hold onto a GPU forever.
+/// Returns the tool's container image.
+fn validate_tool(tool: &Tool, max_time_limit_hours: i32) -> Result<&Image, DiscoError> {
+    if tool.name.trim().is_empty() {
+        return Err(DiscoError::BadRequest("name can't be empty".to_owned()));
+    }
+    if tool.version.trim().is_empty() {
+        return Err(DiscoError::BadRequest("version can't be empty".to_owned()));
+    }
+
+    let image = tool
+        .container_image
+        .as_ref()
+        .ok_or_else(|| DiscoError::BadRequest("container_image is required".to_owned()))?;
+    validate_image_name(&image.name)?;
+    validate_image_tag(&image.tag)?;
+
+    let max_time_limit = i64::from(max_time_limit_hours) * 3600;
+    if tool.time_limit_seconds < 0 || i64::from(tool.time_limit_seconds) > max_time_limit {
+        return Err(DiscoError::BadRequest(format!(
+            "time_limit_seconds must be between 0 and {}",
+            max_time_limit
+        )));
+    }
+
+    if tool.gpu_enabled && tool.time_limit_seconds == 0 {
+        return Err(DiscoError::BadRequest(
+            "GPU-enabled tools must have a time limit".to_owned(),
+        ));
+    }
+
+    Ok(image)
+}
+
+/// List the tools added by the logged-in user
+///
+/// Returns the user's tools matching the filters, sorted by name and version. Each tool
+/// includes its container image and integration data, and whether the image is deprecated.
+#[utoipa::path(
+    get,
+    path = "/tools",
+    params(ToolFilter),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The user's tools", body = Tools),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "tools"
+)]
+pub async fn get_user_tools(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Query(filter): Query<ToolFilter>,
+) -> response::Result<Json<Tools>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let tools = tools::get_user_tools(&mut tx, &user, &filter).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(Tools { tools }))
+}
+
+/// Get one of the tools added by the logged-in user
+#[utoipa::path(
+    get,
+    path = "/tools/{tool_id}",
+    params(
+        ("tool_id" = String, Path, description = "A tool's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The tool", body = ToolDetail),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or tool wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("tool wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "tools"
+)]
+pub async fn get_user_tool(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(tool_id): Path<Uuid>,
+) -> response::Result<Json<ToolDetail>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !tools::user_tool_exists(&mut tx, &user, &tool_id).await? {
+        return Err(DiscoError::NotFound(format!("tool {} was not found", tool_id)));
+    }
+
+    let retval = tools::get_user_tool(&mut tx, &user, &tool_id).await?;
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Add a tool for the logged-in user
+///
+/// The request body is a tool. Its UUID and integration data are ignored: the tool is
+/// attributed to the logged-in user, whose integration data is added if they don't have any
+/// yet. The container image is looked up by name, tag and OSG image path, and added if it
+/// doesn't exist. Image names and tags must follow the Docker reference syntax, time limits
+/// are in seconds with 0 meaning no limit, and GPU-enabled tools must have a time limit.
+#[utoipa::path(
+    post,
+    path = "/tools",
+    request_body = Object,
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The new tool", body = ToolDetail),
+        (status = 400, description = "The tool is invalid or its name and version are taken.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "tools"
+)]
+pub async fn add_tool(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Json(tool): Json<Tool>,
+) -> response::Result<Json<ToolDetail>, DiscoError> {
+    let image = validate_tool(&tool, state.handler_config.max_time_limit_hours)?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if tools::tool_name_version_exists(&mut tx, &tool.name, &tool.version, None).await? {
+        return Err(DiscoError::BadRequest(format!(
+            "a tool named {} with version {} already exists",
+            tool.name, tool.version
+        )));
+    }
+
+    let integrator_name = user_info.name.unwrap_or_else(|| user.clone());
+    let integrator_email = user_info.email.unwrap_or_default();
+    let integration_data_id =
+        tools::find_or_add_integration_data(&mut tx, &user, &integrator_name, &integrator_email)
+            .await?;
+
+    tools::lock_container_image(&mut tx, image).await?;
+    let image_id = tools::find_or_add_container_image(&mut tx, image).await?;
+    let tool_id = tools::add_tool(&mut tx, &tool, &image_id, &integration_data_id).await?;
+    let retval = tools::get_user_tool(&mut tx, &user, &tool_id).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Update one of the tools added by the logged-in user
+///
+/// The request body replaces the tool's settings and container image, and is validated the
+/// same way as a new tool. The tool's integration data isn't changed.
+#[utoipa::path(
+    put,
+    path = "/tools/{tool_id}",
+    params(
+        ("tool_id" = String, Path, description = "A tool's UUID"),
+    ),
+    request_body = Object,
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The updated tool", body = ToolDetail),
+        (status = 400, description = "The tool is invalid or its name and version are taken.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or tool wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("tool wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "tools"
+)]
+pub async fn update_tool(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(tool_id): Path<Uuid>,
+    Json(tool): Json<Tool>,
+) -> response::Result<Json<ToolDetail>, DiscoError> {
+    let image = validate_tool(&tool, state.handler_config.max_time_limit_hours)?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !tools::user_tool_exists(&mut tx, &user, &tool_id).await? {
+        return Err(DiscoError::NotFound(format!("tool {} was not found", tool_id)));
+    }
+
+    if tools::tool_name_version_exists(&mut tx, &tool.name, &tool.version, Some(&tool_id)).await? {
+        return Err(DiscoError::BadRequest(format!(
+            "a tool named {} with version {} already exists",
+            tool.name, tool.version
+        )));
+    }
+
+    tools::lock_container_image(&mut tx, image).await?;
+    let image_id = tools::find_or_add_container_image(&mut tx, image).await?;
+    tools::update_tool(&mut tx, &tool_id, &tool, &image_id).await?;
+    let retval = tools::get_user_tool(&mut tx, &user, &tool_id).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(retval))
+}
+
+/// Delete one of the tools added by the logged-in user
+///
+/// Tools that anything still refers to, such as an app's task, a tool request or the tool's
+/// container settings, can't be deleted.
+#[utoipa::path(
+    delete,
+    path = "/tools/{tool_id}",
+    params(
+        ("tool_id" = String, Path, description = "A tool's UUID"),
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The tool was deleted"),
+        (status = 400, description = "The tool is still in use.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or tool wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("tool wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "tools"
+)]
+pub async fn delete_tool(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(tool_id): Path<Uuid>,
+) -> response::Result<(), DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !tools::user_tool_exists(&mut tx, &user, &tool_id).await? {
+        return Err(DiscoError::NotFound(format!("tool {} was not found", tool_id)));
+    }
+
+    let in_use = || {
+        DiscoError::BadRequest(format!(
+            "tool {} is still in use and can't be deleted",
+            tool_id
+        ))
+    };
+
+    if tools::tool_in_use(&mut tx, &tool_id).await? {
+        return Err(in_use());
+    }
+
+    // Anything else that refers to the tool shows up as a foreign key violation.
+    match tools::delete_tool(&mut tx, &tool_id).await {
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => {
+            return Err(in_use())
+        }
+        result => result?,
+    };
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Summarize how much one of the logged-in user's tools was used
+///
+/// Counts the analyses of apps that run the tool that started during the window, along with
+/// the number of distinct users who ran them and the fraction of them with each status. The
+/// window defaults to the last 30 days.
+#[utoipa::path(
+    get,
+    path = "/tools/{tool_id}/usage",
+    params(
+        ("tool_id" = String, Path, description = "A tool's UUID"),
+        UsageWindow,
+    ),
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The tool's usage", body = ToolUsage),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 404, description = "The user or tool wasn't found.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("tool wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "tools"
+)]
+pub async fn get_tool_usage(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+    Path(tool_id): Path<Uuid>,
+    Query(window): Query<UsageWindow>,
+) -> response::Result<Json<ToolUsage>, DiscoError> {
+    window.validate()?;
+
+    let mut tx = state.pool.begin().await?;
+    let user = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    if !tools::user_tool_exists(&mut tx, &user, &tool_id).await? {
+        return Err(DiscoError::NotFound(format!("tool {} was not found", tool_id)));
+    }
+
+    let (by_status, distinct_users) =
+        tools::count_tool_usage(&mut tx, &tool_id, &window, &state.db_timezone).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ToolUsage::new(tool_id, &window, by_status, distinct_users)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_image_name() {
+        for name in [
+            "alpine",
+            "discoenv/jupyter-lab",
+            "harbor.cyverse.org/de/vice-proxy",
+            "localhost:5000/my_image",
+            "gcr.io/a__b/c.d/e---f",
+        ] {
+            assert!(validate_image_name(name).is_ok(), "{name}");
+        }
+
+        for name in [
+            "",
+            "Discoenv/foo",
+            "foo:latest",
+            "foo/",
+            "/foo",
+            "foo//bar",
+            "foo/-bar",
+            "foo/bar_",
+            "foo/a___b",
+            "bad_host:port/foo",
+            "host.example.org:/foo",
+        ] {
+            assert!(validate_image_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_validate_image_tag() {
+        assert!(validate_image_tag("latest").is_ok());
+        assert!(validate_image_tag("v1.2.3-rc_1").is_ok());
+        assert!(validate_image_tag("").is_err());
+        assert!(validate_image_tag(".hidden").is_err());
+        assert!(validate_image_tag("-dash").is_err());
+        assert!(validate_image_tag("has:colon").is_err());
+        assert!(validate_image_tag(&"a".repeat(MAX_IMAGE_TAG_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_validate_tool() {
+        let mut tool = Tool {
+            name: "jupyter".to_owned(),
+            version: "1.0".to_owned(),
+            container_image: Some(Image {
+                name: "discoenv/jupyter-lab".to_owned(),
+                tag: "latest".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(validate_tool(&tool, 1).is_ok());
+
+        tool.time_limit_seconds = 3601;
+        assert!(validate_tool(&tool, 1).is_err());
+
+        tool.time_limit_seconds = 0;
+        tool.gpu_enabled = true;
+        assert!(validate_tool(&tool, 1).is_err());
+
+        tool.time_limit_seconds = 3600;
+        assert!(validate_tool(&tool, 1).is_ok());
+
+        tool.container_image = None;
+        assert!(validate_tool(&tool, 1).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use axum::{
+    extract::{Extension, Json, State},
+    response,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use std::collections::HashMap;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+use crate::app_state::DiscoenvState;
+use crate::auth::UserInfo;
+use crate::db::{bags, preferences, searches, sessions, users};
+use crate::errors::DiscoError;
+
+use super::{common, config};
+
+/// The most users that can be looked up in one request.
+const MAX_LOOKUP_USERS: usize = 1000;
+
+/// Who the logged-in user is, according to their token and the database.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct CurrentUser {
+    /// The user's UUID in the database.
+    pub id: Uuid,
+
+    /// The username with the user domain appended, as it's stored in the database.
+    pub username: String,
+
+    pub email: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+
+    /// The user's realm roles.
+    pub roles: Vec<String>,
+
+    pub entitlements: Vec<String>,
+
+    /// When the user's token expires.
+    pub token_expires_at: Option<DateTime<Utc>>,
+
+    pub has_preferences: bool,
+    pub has_default_bag: bool,
+    pub has_saved_searches: bool,
+    pub has_session: bool,
+}
+
+/// The users to look up, each given as either a username or a UUID.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct UserLookupRequest {
+    pub users: Vec<String>,
+}
+
+/// A user that was found.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct FoundUser {
+    /// The entry from the request the user was found by.
+    pub query: String,
+
+    pub id: Uuid,
+
+    /// The username with the user domain appended, as it's stored in the database.
+    pub username: String,
+}
+
+/// The results of looking up a list of users.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct UserLookup {
+    /// The users that were found, in the order they were requested.
+    pub found: Vec<FoundUser>,
+
+    /// The entries from the request that didn't match a user.
+    pub not_found: Vec<String>,
+}
+
+/// A user lookup entry, with usernames normalized.
+#[derive(Debug, Clone, PartialEq)]
+enum LookupKey {
+    Id(Uuid),
+    Username(String),
+}
+
+fn lookup_key(entry: &str, cfg: &config::HandlerConfiguration) -> LookupKey {
+    match Uuid::parse_str(entry) {
+        Ok(id) => LookupKey::Id(id),
+        Err(_) => LookupKey::Username(common::fix_username(entry, cfg)),
+    }
+}
+
+/// Matches the requested entries up with the users that were found.
+fn match_users(entries: &[String], keys: &[LookupKey], users: &[(Uuid, String)]) -> UserLookup {
+    let mut lookup = UserLookup::default();
+    let by_id: HashMap<&Uuid, &(Uuid, String)> = users.iter().map(|u| (&u.0, u)).collect();
+    let by_username: HashMap<&str, &(Uuid, String)> =
+        users.iter().map(|u| (u.1.as_str(), u)).collect();
+
+    for (entry, key) in entries.iter().zip(keys) {
+        let user = match key {
+            LookupKey::Id(id) => by_id.get(id),
+            LookupKey::Username(username) => by_username.get(username.as_str()),
+        };
+
+        match user {
+            Some((id, username)) => lookup.found.push(FoundUser {
+                query: entry.clone(),
+                id: *id,
+                username: username.clone(),
+            }),
+            None => lookup.not_found.push(entry.clone()),
+        }
+    }
+
+    lookup
+}
+
+/// Get the logged-in user's profile
+///
+/// Combines the claims in the user's token with what the database has stored for them, so
+/// clients don't need to decode the token themselves.
+#[utoipa::path(
+    get,
+    path = "/users/me",
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The logged-in user", body = CurrentUser),
+        (status = 404, description = "User didn't exist.",
+            body = DiscoError,
+            example = json!(DiscoError::NotFound("user wasn't found".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "users"
+)]
+pub async fn get_current_user(
+    State(state): State<Arc<DiscoenvState>>,
+    Extension(user_info): Extension<UserInfo>,
+) -> response::Result<Json<CurrentUser>, DiscoError> {
+    let mut tx = state.pool.begin().await?;
+    let username = common::validate_user(&mut tx, &user_info, &state).await?;
+
+    let id = users::user_id(&mut tx, &username).await?;
+    let has_preferences = preferences::has_preferences(&mut tx, &username).await?;
+    let has_default_bag = bags::has_default_bag(&mut tx, &username).await?;
+    let has_saved_searches = searches::has_saved_searches(&mut tx, &username).await?;
+    let has_session = sessions::has_session(&mut tx, &username).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(CurrentUser {
+        id,
+        username,
+        email: user_info.email.clone(),
+        given_name: user_info.given_name.clone(),
+        family_name: user_info.family_name.clone(),
+        roles: user_info.roles(),
+        entitlements: user_info.entitlement.clone().unwrap_or_default(),
+        token_expires_at: user_info.expires_at(),
+        has_preferences,
+        has_default_bag,
+        has_saved_searches,
+        has_session,
+    }))
+}
+
+/// Look up users by username or UUID
+///
+/// Resolves a list of usernames and UUIDs to the users' IDs and usernames in one query. Usernames
+/// get the user domain appended the same way as everywhere else. Entries that don't match a user
+/// are listed in `not_found`. Up to 1000 users can be looked up at once. Only available to
+/// service accounts and administrators.
+#[utoipa::path(
+    post,
+    path = "/users/lookup",
+    request_body = UserLookupRequest,
+    security(
+      ("api_key" = []),
+    ),
+    responses(
+        (status = 200, description = "The users that were and weren't found", body = UserLookup),
+        (status = 400, description = "Bad request.",
+            body = DiscoError,
+            example = json!(DiscoError::BadRequest("bad request".to_owned()).create_service_error())),
+        (status = 403, description = "The caller isn't a service account or administrator.",
+            body = DiscoError,
+            example = json!(DiscoError::Forbidden("forbidden".to_owned()).create_service_error())),
+        (status = 500, description = "Internal error.",
+            body = DiscoError,
+            example = json!(DiscoError::Internal("internal error".to_owned()).create_service_error())),
+    ),
+    tag = "users"
+)]
+pub async fn lookup_users(
+    State(state): State<Arc<DiscoenvState>>,
+    Json(request): Json<UserLookupRequest>,
+) -> response::Result<Json<UserLookup>, DiscoError> {
+    if request.users.len() > MAX_LOOKUP_USERS {
+        return Err(DiscoError::BadRequest(format!(
+            "no more than {} users can be looked up at once",
+            MAX_LOOKUP_USERS
+        )));
+    }
+
+    let keys: Vec<LookupKey> = request
+        .users
+        .iter()
+        .map(|entry| lookup_key(entry, &state.handler_config))
+        .collect();
+
+    let mut ids = Vec::new();
+    let mut usernames = Vec::new();
+    for key in keys.iter() {
+        match key {
+            LookupKey::Id(id) => ids.push(*id),
+            LookupKey::Username(username) => usernames.push(username.clone()),
+        }
+    }
+
+    let users = users::lookup_users(&state.pool, &ids, &usernames).await?;
+
+    Ok(Json(match_users(&request.users, &keys, &users)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_match_users() {
+        let cfg = config::HandlerConfiguration {
+            append_user_domain: true,
+            user_domain: "@example.org".to_owned(),
+            provision_users: false,
+            track_user_logins: false,
+            do_auth: true,
+            max_extension_hours: 24,
+            max_time_limit_hours: 168,
+        };
+        let alice = Uuid::parse_str("1f6e3b5a-0a7e-11ee-8a1b-0242ac120002").unwrap();
+        let bob = Uuid::parse_str("2a7f4c6b-0a7e-11ee-8a1b-0242ac120002").unwrap();
+        let missing = "3b805d7c-0a7e-11ee-8a1b-0242ac120002";
+        let alice_id = alice.to_string();
+
+        let entries: Vec<String> = [
+            "alice",
+            missing,
+            "bob@example.org",
+            alice_id.as_str(),
+            "carol",
+        ]
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+        let keys: Vec<LookupKey> = entries.iter().map(|e| lookup_key(e, &cfg)).collect();
+
+        assert_eq!(keys[0], LookupKey::Username("alice@example.org".to_owned()));
+        assert_eq!(keys[2], LookupKey::Username("bob@example.org".to_owned()));
+        assert_eq!(keys[3], LookupKey::Id(alice));
+
+        let users = vec![
+            (alice, "alice@example.org".to_owned()),
+            (bob, "bob@example.org".to_owned()),
+        ];
+        let lookup = match_users(&entries, &keys, &users);
+
+        assert_eq!(
+            lookup.found.iter().map(|u| u.id).collect::<Vec<_>>(),
+            vec![alice, bob, alice]
+        );
+        assert_eq!(lookup.found[0].query, "alice");
+        assert_eq!(
+            lookup.not_found,
+            vec![missing.to_owned(), "carol".to_owned()]
+        );
+    }
+}
@@ -1,18 +1,29 @@
 pub mod handlers {
     pub mod analyses;
+    pub mod annotations;
+    pub mod apps;
     pub mod bags;
     pub mod common;
     pub mod config;
+    pub mod container_images;
+    pub mod exports;
     pub mod preferences;
+    pub mod quick_launches;
     pub mod searches;
     pub mod sessions;
     pub mod tokens;
+    pub mod tools;
+    pub mod users;
 }
 
 pub mod db {
     pub mod analyses;
+    pub mod annotations;
+    pub mod apps;
     pub mod bags;
+    pub mod container_images;
     pub mod preferences;
+    pub mod quick_launches;
     pub mod searches;
     pub mod sessions;
     pub mod tools;
@@ -20,7 +31,11 @@ pub mod db {
 }
 
 pub mod app_state;
+pub mod archive;
 pub mod auth;
 pub mod config;
+pub mod crypto;
+pub mod dispatch;
 pub mod errors;
 pub mod signals;
+pub mod tasks;
@@ -1,11 +1,14 @@
 use axum::{
     middleware,
-    routing::get,
+    routing::{delete, get, post, put},
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
-use discoenv::db::{bags, preferences, searches};
+use discoenv::db::{
+    analyses, annotations, apps, bags, container_images, preferences, quick_launches, searches,
+    sessions, tools,
+};
 use sqlx::postgres::PgPool;
 use utoipa::{
     openapi::security::{SecurityScheme, ApiKey, ApiKeyValue, Http, HttpAuthScheme}, 
@@ -13,16 +16,22 @@ use utoipa::{
 };
 use utoipa_swagger_ui::SwaggerUi;
 use discoenv::app_state::DiscoenvState;
-use discoenv::auth::{self, middleware::auth_middleware};
+use discoenv::auth::{self, middleware::{auth_middleware, require_entitlements, require_service_account}};
 use discoenv::errors;
 use discoenv::handlers;
 use discoenv::signals::shutdown_signal;
+use discoenv::tasks;
 use utoipa_swagger_ui::oauth;
 use std::sync::Arc;
 use std::process;
 use std::path::PathBuf;
-use tracing::{info, debug, error};
+use std::time::Duration;
+use chrono_tz::Tz;
+use tokio::sync::broadcast;
+use tracing::{info, debug, error, warn};
 use discoenv::config;
+use discoenv::crypto;
+use discoenv::dispatch::{JobDispatcher, NatsDispatcher};
 
 #[tokio::main]
 async fn main() {
@@ -58,12 +67,111 @@ async fn main() {
 
     debug!("append_user_domain: {}", cli.append_user_domain);
     debug!("user_domain: {}", cfg.users.domain);
+    debug!("provision_users: {}", cfg.users.provision);
+    debug!("track_user_logins: {}", cfg.users.track_logins);
     debug!("do_auth: {}", cfg.oauth.is_some());
     
     let handler_config = handlers::config::HandlerConfiguration{
         append_user_domain: cli.append_user_domain,
         user_domain: cfg.users.domain.clone(),
+        provision_users: cfg.users.provision,
+        track_user_logins: cfg.users.track_logins,
         do_auth: cfg.oauth.is_some(),
+        max_extension_hours: cfg.analyses.max_extension_hours,
+        max_time_limit_hours: cfg.analyses.max_time_limit_hours,
+    };
+
+    // A configured time zone has to be valid, but the server's setting may be something
+    // like "localtime" that isn't an IANA name, so that falls back to UTC.
+    let db_timezone: Tz = match &cfg.db.timezone {
+        Some(tz_name) => tz_name.parse().unwrap_or_else(|err| {
+            eprintln!("invalid time zone {tz_name}: {err}");
+            process::exit(exitcode::CONFIG);
+        }),
+        None => {
+            let tz_name = sqlx::query_scalar::<_, String>("SELECT current_setting('TimeZone')")
+                .fetch_one(&pool)
+                .await
+                .unwrap_or_else(|err| {
+                    eprintln!("error looking up the database time zone: {err}");
+                    process::exit(exitcode::IOERR);
+                });
+            tz_name.parse().unwrap_or_else(|err| {
+                warn!("database time zone {tz_name} isn't supported, using UTC instead: {err}");
+                Tz::UTC
+            })
+        }
+    };
+    debug!("db_timezone: {}", db_timezone);
+
+    let keyring = cfg.encryption.as_ref().map(|e| {
+        debug!("loading encryption keys from {}", e.key_file);
+        crypto::Keyring::from_file(&e.key_file).unwrap_or_else(|err| {
+            eprintln!("error loading encryption keys: {err}");
+            process::exit(exitcode::CONFIG);
+        })
+    });
+
+    if cli.reencrypt {
+        let k = keyring.unwrap_or_else(|| {
+            eprintln!("re-encryption requires the encryption configuration");
+            process::exit(exitcode::CONFIG);
+        });
+
+        info!("re-encrypting with key {}", k.current_key_id());
+        match tasks::reencrypt(&pool, &k).await {
+            Ok((sessions, bags)) => {
+                info!("re-encrypted {sessions} sessions and {bags} bags");
+                process::exit(exitcode::OK);
+            }
+            Err(err) => {
+                eprintln!("error re-encrypting: {err}");
+                process::exit(exitcode::SOFTWARE);
+            }
+        }
+    }
+
+    debug!("starting expired session purge task");
+    tokio::spawn(tasks::purge_expired_sessions(
+        pool.clone(),
+        Duration::from_secs(cfg.sessions.purge_interval_seconds.max(1)),
+    ));
+
+    debug!("starting analysis status event listener");
+    let (status_events, _) = broadcast::channel(1024);
+    tokio::spawn(tasks::listen_for_status_events(
+        pool.clone(),
+        db_timezone,
+        status_events.clone(),
+    ));
+    tokio::spawn(tasks::purge_status_events(
+        pool.clone(),
+        cfg.analyses.event_retention_hours,
+        Duration::from_secs(3600),
+    ));
+
+    let dispatcher: Option<Arc<dyn JobDispatcher>> = match &cfg.nats {
+        Some(n) => {
+            debug!("connecting to NATS at {}", n.uri);
+            let opts = match &n.creds_file {
+                Some(f) => async_nats::ConnectOptions::with_credentials_file(PathBuf::from(f))
+                    .await
+                    .unwrap_or_else(|err| {
+                        eprintln!("error reading NATS credentials: {err}");
+                        process::exit(exitcode::CONFIG);
+                    }),
+                None => async_nats::ConnectOptions::new(),
+            };
+            let client = opts.connect(n.uri.as_str()).await.unwrap_or_else(|err| {
+                eprintln!("error connecting to NATS: {err}");
+                process::exit(exitcode::IOERR);
+            });
+            Some(Arc::new(NatsDispatcher::new(client, &n.cancel_subject, &n.submit_subject)))
+        }
+        None => {
+            info!("no NATS configuration, so analyses can't be canceled or relaunched");
+            None
+        }
     };
 
     let mut state = DiscoenvState {
@@ -71,6 +179,11 @@ async fn main() {
         handler_config,
         auth: auth::Authenticator::default(),
         admin_entitlements: vec![],
+        service_accounts: vec![],
+        keyring,
+        status_events,
+        db_timezone,
+        dispatcher,
     };
 
     debug!("setting up swagger ui");
@@ -100,6 +213,8 @@ async fn main() {
             process::exit(exitcode::SOFTWARE);
         });
 
+    state.service_accounts = o.service_accounts.clone();
+
     if o.entitlements.is_some() {
         let e = o.entitlements.unwrap_or_default();
         let ent_parts: Vec<String> = e.admin.as_str().split(',').map(String::from).collect();
@@ -111,6 +226,26 @@ async fn main() {
         paths(
             handlers::tokens::get_token,
             handlers::analyses::get_user_analyses,
+            handlers::analyses::get_user_analysis,
+            handlers::analyses::get_analysis_children,
+            handlers::analyses::get_analysis_events,
+            handlers::analyses::get_analyses_summary,
+            handlers::analyses::get_all_analyses_summary,
+            handlers::analyses::get_analysis_by_subdomain,
+            handlers::analyses::get_subdomain_access,
+            handlers::analyses::extend_time_limit,
+            handlers::analyses::get_time_limit_extensions,
+            handlers::analyses::cancel_analysis,
+            handlers::analyses::relaunch_analysis,
+            handlers::analyses::export_user_analyses,
+            handlers::annotations::get_user_tags,
+            handlers::annotations::get_analysis_tags,
+            handlers::annotations::add_analysis_tag,
+            handlers::annotations::delete_analysis_tag,
+            handlers::annotations::get_analysis_notes,
+            handlers::annotations::add_analysis_note,
+            handlers::annotations::update_analysis_note,
+            handlers::annotations::delete_analysis_note,
             handlers::bags::get_user_bags,
             handlers::bags::delete_user_bags,
             handlers::bags::add_user_bag,
@@ -125,18 +260,108 @@ async fn main() {
             handlers::preferences::add_user_preferences,
             handlers::preferences::update_user_preferences,
             handlers::preferences::delete_user_preferences,
+            handlers::sessions::get_user_sessions,
+            handlers::sessions::add_user_sessions,
+            handlers::sessions::delete_user_sessions,
+            handlers::sessions::get_user_session,
+            handlers::sessions::update_user_session,
+            handlers::sessions::delete_user_session,
             handlers::searches::get_saved_searches,
             handlers::searches::add_saved_searches,
             handlers::searches::update_saved_searches,
             handlers::searches::delete_saved_searches,
+            handlers::searches::list_search_shares,
+            handlers::searches::share_saved_search,
+            handlers::searches::revoke_search_share,
+            handlers::searches::get_shared_searches,
+            handlers::searches::copy_shared_search,
+            handlers::tools::get_user_tools,
+            handlers::tools::get_user_tool,
+            handlers::tools::add_tool,
+            handlers::tools::update_tool,
+            handlers::tools::delete_tool,
+            handlers::tools::get_tool_usage,
+            handlers::container_images::get_container_images,
+            handlers::container_images::get_image_dependents,
+            handlers::apps::get_apps,
+            handlers::apps::get_app,
+            handlers::apps::get_favorite_apps,
+            handlers::apps::add_favorite_app,
+            handlers::apps::delete_favorite_app,
+            handlers::apps::get_recent_apps,
+            handlers::quick_launches::get_quick_launches,
+            handlers::quick_launches::add_quick_launch,
+            handlers::quick_launches::get_quick_launch,
+            handlers::quick_launches::update_quick_launch,
+            handlers::quick_launches::delete_quick_launch,
+            handlers::quick_launches::get_app_quick_launches,
+            handlers::users::get_current_user,
+            handlers::users::lookup_users,
+            handlers::exports::export_user_data,
         ),
         components(
             schemas(
                 handlers::common::ID,
+                analyses::AnalysisListing,
+                analyses::ListedAnalysis,
+                analyses::ChildCounts,
+                analyses::AnalysisDetail,
+                analyses::AnalysisStep,
+                analyses::AnalysisStatusEvent,
+                analyses::AnalysisSummary,
+                analyses::AdminAnalysisSummary,
+                analyses::UsageBucket,
+                analyses::SubdomainAnalysis,
+                handlers::analyses::SubdomainAccess,
+                handlers::analyses::TimeLimitRequest,
+                handlers::analyses::TimeLimit,
+                analyses::TimeLimitExtensionCount,
+                handlers::analyses::RelaunchRequest,
+                handlers::analyses::Relaunch,
+                analyses::AnalysisSortField,
+                analyses::SortOrder,
+                annotations::TagCount,
+                annotations::Note,
+                handlers::annotations::UserTags,
+                handlers::annotations::AnalysisTags,
+                handlers::annotations::Notes,
+                handlers::annotations::NoteRequest,
                 bags::Bag, 
                 bags::Bags, 
                 preferences::Preferences,
                 searches::SavedSearches,
+                searches::SavedSearchListing,
+                sessions::Session,
+                sessions::Sessions,
+                handlers::sessions::SessionUpdate,
+                searches::SharedSearch,
+                searches::SharedSearches,
+                searches::SearchShare,
+                searches::SearchShares,
+                tools::Tools,
+                tools::ToolDetail,
+                tools::ToolUsage,
+                tools::ToolStatusCount,
+                container_images::ContainerImage,
+                container_images::ContainerImages,
+                container_images::DependentTool,
+                container_images::AffectedUser,
+                container_images::ImageDependents,
+                apps::AppListing,
+                apps::AppDetail,
+                apps::FavoriteApp,
+                apps::FavoriteApps,
+                apps::RecentApp,
+                apps::RecentApps,
+                quick_launches::QuickLaunch,
+                quick_launches::QuickLaunchRequest,
+                quick_launches::QuickLaunches,
+                handlers::users::CurrentUser,
+                handlers::users::UserLookupRequest,
+                handlers::users::FoundUser,
+                handlers::users::UserLookup,
+                handlers::searches::ShareRequest,
+                handlers::searches::CopyRequest,
                 errors::DiscoError,
                 auth::Token,
             )
@@ -174,7 +399,8 @@ async fn main() {
     debug!("setting up routes");
     let service_state = Arc::new(state);
     let auth_m = |s| middleware::from_fn_with_state(s, auth_middleware);
-    // let ent_m = |s: Arc<DiscoenvState>| middleware::from_fn_with_state(s, require_entitlements);
+    let ent_m = |s| middleware::from_fn_with_state(s, require_entitlements);
+    let svc_m = |s| middleware::from_fn_with_state(s, require_service_account);
 
     let pref_routes = Router::new()
         .route(
@@ -194,6 +420,26 @@ async fn main() {
                 .post(handlers::searches::update_saved_searches)
                 .delete(handlers::searches::delete_saved_searches)
         )
+        .route(
+            "/shares",
+            get(handlers::searches::list_search_shares)
+        )
+        .route(
+            "/shares/:name",
+            put(handlers::searches::share_saved_search)
+        )
+        .route(
+            "/shares/:name/:recipient",
+            delete(handlers::searches::revoke_search_share)
+        )
+        .route(
+            "/shared",
+            get(handlers::searches::get_shared_searches)
+        )
+        .route(
+            "/shared/:share_id/copy",
+            post(handlers::searches::copy_shared_search)
+        )
         .layer(auth_m(service_state.clone()));
 
     let sessions_routes = Router::new()
@@ -201,9 +447,14 @@ async fn main() {
             "/:username",
             get(handlers::sessions::get_user_sessions)
                 .put(handlers::sessions::add_user_sessions)
-                .post(handlers::sessions::update_user_sessions)
                 .delete(handlers::sessions::delete_user_sessions)
         )
+        .route(
+            "/:username/:session_id",
+            get(handlers::sessions::get_user_session)
+                .post(handlers::sessions::update_user_session)
+                .delete(handlers::sessions::delete_user_session)
+        )
         .layer(auth_m(service_state.clone()));
 
     let bag_routes = Router::new()
@@ -234,16 +485,194 @@ async fn main() {
             "/",
             get(handlers::analyses::get_user_analyses)
         )
+        .route(
+            "/events",
+            get(handlers::analyses::get_analysis_events)
+        )
+        .route(
+            "/export",
+            get(handlers::analyses::export_user_analyses)
+        )
+        .route(
+            "/summary",
+            get(handlers::analyses::get_analyses_summary)
+        )
+        .route(
+            "/tags",
+            get(handlers::annotations::get_user_tags)
+        )
+        .route(
+            "/by-subdomain/:subdomain/access",
+            get(handlers::analyses::get_subdomain_access)
+        )
+        .route(
+            "/:analysis_id",
+            get(handlers::analyses::get_user_analysis)
+        )
+        .route(
+            "/:analysis_id/children",
+            get(handlers::analyses::get_analysis_children)
+        )
+        .route(
+            "/:analysis_id/time-limit",
+            post(handlers::analyses::extend_time_limit)
+        )
+        .route(
+            "/:analysis_id/cancel",
+            post(handlers::analyses::cancel_analysis)
+        )
+        .route(
+            "/:analysis_id/relaunch",
+            post(handlers::analyses::relaunch_analysis)
+        )
+        .route(
+            "/:analysis_id/tags",
+            get(handlers::annotations::get_analysis_tags)
+        )
+        .route(
+            "/:analysis_id/tags/:tag",
+            put(handlers::annotations::add_analysis_tag)
+                .delete(handlers::annotations::delete_analysis_tag)
+        )
+        .route(
+            "/:analysis_id/notes",
+            get(handlers::annotations::get_analysis_notes)
+                .post(handlers::annotations::add_analysis_note)
+        )
+        .route(
+            "/:analysis_id/notes/:note_id",
+            post(handlers::annotations::update_analysis_note)
+                .delete(handlers::annotations::delete_analysis_note)
+        )
+        .layer(auth_m(service_state.clone()));
+
+    // The last layer added runs first, so these routes check the caller's
+    // permissions after the token has been validated.
+    let subdomain_routes = Router::new()
+        .route(
+            "/by-subdomain/:subdomain",
+            get(handlers::analyses::get_analysis_by_subdomain)
+        )
+        .layer(svc_m(service_state.clone()))
+        .layer(auth_m(service_state.clone()));
+
+    let tools_routes = Router::new()
+        .route(
+            "/",
+            get(handlers::tools::get_user_tools)
+                .post(handlers::tools::add_tool)
+        )
+        .route(
+            "/:tool_id",
+            get(handlers::tools::get_user_tool)
+                .put(handlers::tools::update_tool)
+                .delete(handlers::tools::delete_tool)
+        )
+        .route(
+            "/:tool_id/usage",
+            get(handlers::tools::get_tool_usage)
+        )
+        .layer(auth_m(service_state.clone()));
+
+    let apps_routes = Router::new()
+        .route(
+            "/",
+            get(handlers::apps::get_apps)
+        )
+        .route(
+            "/favorites",
+            get(handlers::apps::get_favorite_apps)
+        )
+        .route(
+            "/favorites/:app_id",
+            put(handlers::apps::add_favorite_app)
+                .delete(handlers::apps::delete_favorite_app)
+        )
+        .route(
+            "/recent",
+            get(handlers::apps::get_recent_apps)
+        )
+        .route(
+            "/:app_id",
+            get(handlers::apps::get_app)
+        )
+        .route(
+            "/:app_id/quick-launches",
+            get(handlers::quick_launches::get_app_quick_launches)
+        )
+        .layer(auth_m(service_state.clone()));
+
+    let quick_launch_routes = Router::new()
+        .route(
+            "/",
+            get(handlers::quick_launches::get_quick_launches)
+                .post(handlers::quick_launches::add_quick_launch)
+        )
+        .route(
+            "/:quick_launch_id",
+            get(handlers::quick_launches::get_quick_launch)
+                .put(handlers::quick_launches::update_quick_launch)
+                .delete(handlers::quick_launches::delete_quick_launch)
+        )
+        .layer(auth_m(service_state.clone()));
+
+    let users_routes = Router::new()
+        .route(
+            "/me",
+            get(handlers::users::get_current_user)
+        )
+        .route(
+            "/:username/export",
+            get(handlers::exports::export_user_data)
+        )
+        .layer(auth_m(service_state.clone()));
+
+    let users_lookup_routes = Router::new()
+        .route(
+            "/lookup",
+            post(handlers::users::lookup_users)
+        )
+        .layer(svc_m(service_state.clone()))
+        .layer(auth_m(service_state.clone()));
+
+    let container_image_routes = Router::new()
+        .route(
+            "/",
+            get(handlers::container_images::get_container_images)
+        )
+        .route(
+            "/:image_id/dependents",
+            get(handlers::container_images::get_image_dependents)
+        )
+        .layer(ent_m(service_state.clone()))
+        .layer(auth_m(service_state.clone()));
+
+    let admin_routes = Router::new()
+        .route(
+            "/analyses/summary",
+            get(handlers::analyses::get_all_analyses_summary)
+        )
+        .route(
+            "/analyses/time-limit-extensions",
+            get(handlers::analyses::get_time_limit_extensions)
+        )
+        .layer(ent_m(service_state.clone()))
         .layer(auth_m(service_state.clone()));
 
     let app = Router::new()
         .route("/", get(|| async {}))
         .merge(swagger_ui)
-        .nest("/analyses", analyses_routes)
+        .nest("/admin", admin_routes)
+        .nest("/analyses", analyses_routes.merge(subdomain_routes))
+        .nest("/apps", apps_routes)
         .nest("/bags", bag_routes)
+        .nest("/container-images", container_image_routes)
         .nest("/searches", searches_routes)
         .nest("/sessions", sessions_routes)
         .nest("/preferences", pref_routes)
+        .nest("/quick-launches", quick_launch_routes)
+        .nest("/tools", tools_routes)
+        .nest("/users", users_routes.merge(users_lookup_routes))
         .route("/token", get(handlers::tokens::get_token))
         .with_state(service_state);
 
@@ -0,0 +1,125 @@
+use chrono_tz::Tz;
+use sqlx::{postgres::PgListener, PgPool};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::crypto::Keyring;
+use crate::db::{
+    analyses::{self, AnalysisStatusEvent, StatusEventRecord},
+    bags, sessions,
+};
+
+const REENCRYPT_BATCH_SIZE: i64 = 500;
+
+/// Periodically deletes sessions that have outlived their TTL. Runs until the
+/// process exits.
+pub async fn purge_expired_sessions(pool: PgPool, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        match sessions::purge_expired_sessions(&pool).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("purged {} expired sessions", count),
+            Err(e) => tracing::error!("error purging expired sessions: {e}"),
+        }
+    }
+}
+
+/// Listens for analysis status changes announced by the database and passes
+/// them along to the SSE connections. Reconnects if the connection drops.
+pub async fn listen_for_status_events(
+    pool: PgPool,
+    tz: Tz,
+    sender: broadcast::Sender<AnalysisStatusEvent>,
+) {
+    loop {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("error connecting the status event listener: {e}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        if let Err(e) = listener.listen(analyses::STATUS_EVENTS_CHANNEL).await {
+            tracing::error!("error listening for status events: {e}");
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        loop {
+            match listener.recv().await {
+                Ok(n) => match serde_json::from_str::<StatusEventRecord>(n.payload()) {
+                    // An error only means nobody is connected right now.
+                    Ok(ev) => {
+                        let _ = sender.send(ev.into_event(&tz));
+                    }
+                    Err(e) => tracing::warn!("ignoring malformed status event: {e}"),
+                },
+                Err(e) => {
+                    tracing::error!("error receiving status events: {e}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Periodically deletes status events older than the retention period. Runs
+/// until the process exits.
+pub async fn purge_status_events(pool: PgPool, retention_hours: i32, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        match analyses::purge_status_events(&pool, retention_hours).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("purged {} analysis status events", count),
+            Err(e) => tracing::error!("error purging analysis status events: {e}"),
+        }
+    }
+}
+
+/// Re-encrypts every stored session and sensitive bag that isn't already
+/// encrypted with the keyring's current key. Rows are processed in batches,
+/// each in its own transaction, so the process can be interrupted and rerun.
+/// Returns the number of sessions and bags that were updated.
+pub async fn reencrypt(pool: &PgPool, keyring: &Keyring) -> Result<(u64, u64), sqlx::Error> {
+    let current = keyring.current_key_id();
+
+    let mut session_count = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let stale = sessions::list_stale_sessions(&mut tx, current, REENCRYPT_BATCH_SIZE).await?;
+        if stale.is_empty() {
+            break;
+        }
+        for s in stale {
+            session_count += sessions::reencrypt_session(&mut tx, s, keyring).await?;
+        }
+        tx.commit().await?;
+        tracing::info!("re-encrypted {} sessions", session_count);
+    }
+
+    let mut bag_count = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let stale = bags::list_stale_bags(&mut tx, current, REENCRYPT_BATCH_SIZE).await?;
+        if stale.is_empty() {
+            break;
+        }
+        for b in stale {
+            bag_count += bags::reencrypt_bag(&mut tx, b, keyring).await?;
+        }
+        tx.commit().await?;
+        tracing::info!("re-encrypted {} bags", bag_count);
+    }
+
+    Ok((session_count, bag_count))
+}
@@ -0,0 +1,15 @@
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS saved_search_shares (
+    id uuid NOT NULL DEFAULT uuid_generate_v1() PRIMARY KEY,
+    owner_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    recipient_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    search_name text NOT NULL,
+    created_date timestamp NOT NULL DEFAULT now(),
+    UNIQUE (owner_id, recipient_id, search_name)
+);
+
+CREATE INDEX IF NOT EXISTS saved_search_shares_recipient_id_idx
+    ON saved_search_shares(recipient_id);
+
+COMMIT;
@@ -0,0 +1,18 @@
+BEGIN;
+
+-- Keep only the most recently used session for each user.
+DELETE FROM user_sessions s
+USING user_sessions newer
+WHERE s.user_id = newer.user_id
+AND (s.last_access_date, s.id) < (newer.last_access_date, newer.id);
+
+DROP INDEX IF EXISTS user_sessions_user_id_idx;
+
+ALTER TABLE user_sessions
+    DROP COLUMN IF EXISTS device_label,
+    DROP COLUMN IF EXISTS created_date,
+    DROP COLUMN IF EXISTS last_access_date,
+    DROP COLUMN IF EXISTS ttl_seconds,
+    ADD CONSTRAINT user_sessions_user_id_key UNIQUE (user_id);
+
+COMMIT;
@@ -0,0 +1,29 @@
+BEGIN;
+
+-- Allow more than one session per user.
+DO $$
+DECLARE
+    con record;
+BEGIN
+    FOR con IN
+        SELECT c.conname
+        FROM pg_constraint c
+        JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = ANY(c.conkey)
+        WHERE c.conrelid = 'user_sessions'::regclass
+        AND c.contype = 'u'
+        AND array_length(c.conkey, 1) = 1
+        AND a.attname = 'user_id'
+    LOOP
+        EXECUTE format('ALTER TABLE user_sessions DROP CONSTRAINT %I', con.conname);
+    END LOOP;
+END $$;
+
+ALTER TABLE user_sessions
+    ADD COLUMN IF NOT EXISTS device_label text,
+    ADD COLUMN IF NOT EXISTS created_date timestamp NOT NULL DEFAULT now(),
+    ADD COLUMN IF NOT EXISTS last_access_date timestamp NOT NULL DEFAULT now(),
+    ADD COLUMN IF NOT EXISTS ttl_seconds integer CHECK (ttl_seconds > 0);
+
+CREATE INDEX IF NOT EXISTS user_sessions_user_id_idx ON user_sessions(user_id);
+
+COMMIT;
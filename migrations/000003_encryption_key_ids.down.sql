@@ -0,0 +1,15 @@
+BEGIN;
+
+-- Encrypted rows can't be read without their keys, so drop them rather than
+-- leaving ciphertext behind that looks like plaintext.
+DELETE FROM user_sessions WHERE session_key_id IS NOT NULL;
+DELETE FROM bags WHERE contents_key_id IS NOT NULL;
+
+ALTER TABLE user_sessions
+    DROP COLUMN IF EXISTS session_key_id;
+
+ALTER TABLE bags
+    DROP COLUMN IF EXISTS sensitive,
+    DROP COLUMN IF EXISTS contents_key_id;
+
+COMMIT;
@@ -0,0 +1,10 @@
+BEGIN;
+
+ALTER TABLE user_sessions
+    ADD COLUMN IF NOT EXISTS session_key_id text;
+
+ALTER TABLE bags
+    ADD COLUMN IF NOT EXISTS sensitive boolean NOT NULL DEFAULT false,
+    ADD COLUMN IF NOT EXISTS contents_key_id text;
+
+COMMIT;
@@ -0,0 +1,5 @@
+BEGIN;
+
+DROP INDEX IF EXISTS jobs_user_id_deleted_start_date_idx;
+
+COMMIT;
@@ -0,0 +1,7 @@
+BEGIN;
+
+-- Supports the default listing of a user's analyses, newest first.
+CREATE INDEX IF NOT EXISTS jobs_user_id_deleted_start_date_idx
+    ON jobs(user_id, deleted, (COALESCE(start_date, '-infinity'::timestamp)) DESC, id DESC);
+
+COMMIT;
@@ -0,0 +1,8 @@
+BEGIN;
+
+DROP TRIGGER IF EXISTS jobs_status_updated ON jobs;
+DROP TRIGGER IF EXISTS jobs_status_inserted ON jobs;
+DROP FUNCTION IF EXISTS job_status_events_notify();
+DROP TABLE IF EXISTS job_status_events;
+
+COMMIT;
@@ -0,0 +1,52 @@
+BEGIN;
+
+-- A log of analysis status changes. Each change is also sent to listeners on
+-- the job_status_events channel so that every discoenv replica sees it.
+CREATE TABLE IF NOT EXISTS job_status_events (
+    id bigserial PRIMARY KEY,
+    job_id uuid NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    status varchar(64) NOT NULL,
+    previous_status varchar(64),
+    created_date timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS job_status_events_user_id_id_idx
+    ON job_status_events(user_id, id);
+
+CREATE INDEX IF NOT EXISTS job_status_events_created_date_idx
+    ON job_status_events(created_date);
+
+CREATE OR REPLACE FUNCTION job_status_events_notify() RETURNS trigger AS $$
+DECLARE
+    ev job_status_events;
+BEGIN
+    INSERT INTO job_status_events (job_id, user_id, status, previous_status)
+    VALUES (
+        NEW.id,
+        NEW.user_id,
+        NEW.status,
+        CASE WHEN TG_OP = 'UPDATE' THEN OLD.status END
+    )
+    RETURNING * INTO ev;
+
+    PERFORM pg_notify('job_status_events', row_to_json(ev)::text);
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS jobs_status_inserted ON jobs;
+CREATE TRIGGER jobs_status_inserted
+    AFTER INSERT ON jobs
+    FOR EACH ROW
+    EXECUTE FUNCTION job_status_events_notify();
+
+DROP TRIGGER IF EXISTS jobs_status_updated ON jobs;
+CREATE TRIGGER jobs_status_updated
+    AFTER UPDATE OF status ON jobs
+    FOR EACH ROW
+    WHEN (OLD.status IS DISTINCT FROM NEW.status)
+    EXECUTE FUNCTION job_status_events_notify();
+
+COMMIT;
@@ -0,0 +1,20 @@
+BEGIN;
+
+-- Every extension of an interactive analysis's time limit, kept so that
+-- admins can see how often users extend their analyses.
+CREATE TABLE IF NOT EXISTS job_time_limit_extensions (
+    id uuid NOT NULL DEFAULT uuid_generate_v1() PRIMARY KEY,
+    job_id uuid NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    previous_end_date timestamp,
+    new_end_date timestamp NOT NULL,
+    created_date timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS job_time_limit_extensions_job_id_idx
+    ON job_time_limit_extensions(job_id);
+
+CREATE INDEX IF NOT EXISTS job_time_limit_extensions_created_date_idx
+    ON job_time_limit_extensions(created_date);
+
+COMMIT;
@@ -0,0 +1,6 @@
+BEGIN;
+
+DROP TABLE IF EXISTS job_notes;
+DROP TABLE IF EXISTS job_tags;
+
+COMMIT;
@@ -0,0 +1,32 @@
+BEGIN;
+
+-- Labels users put on their own analyses.
+CREATE TABLE IF NOT EXISTS job_tags (
+    id uuid NOT NULL DEFAULT uuid_generate_v1() PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    job_id uuid NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    tag text NOT NULL,
+    created_date timestamp NOT NULL DEFAULT now(),
+    UNIQUE (user_id, job_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS job_tags_user_id_tag_idx
+    ON job_tags(user_id, tag);
+
+CREATE INDEX IF NOT EXISTS job_tags_job_id_idx
+    ON job_tags(job_id);
+
+-- Free-text notes users keep about their own analyses.
+CREATE TABLE IF NOT EXISTS job_notes (
+    id uuid NOT NULL DEFAULT uuid_generate_v1() PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    job_id uuid NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    note text NOT NULL,
+    created_date timestamp NOT NULL DEFAULT now(),
+    modified_date timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS job_notes_job_id_idx
+    ON job_notes(job_id);
+
+COMMIT;
@@ -0,0 +1,12 @@
+BEGIN;
+
+-- Apps users have marked as favorites.
+CREATE TABLE IF NOT EXISTS app_favorites (
+    id uuid NOT NULL DEFAULT uuid_generate_v1() PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    app_id uuid NOT NULL REFERENCES apps(id) ON DELETE CASCADE,
+    created_date timestamp NOT NULL DEFAULT now(),
+    UNIQUE (user_id, app_id)
+);
+
+COMMIT;
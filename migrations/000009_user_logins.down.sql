@@ -0,0 +1,7 @@
+BEGIN;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS first_login_date,
+    DROP COLUMN IF EXISTS last_login_date;
+
+COMMIT;
@@ -0,0 +1,9 @@
+BEGIN;
+
+-- When users first and last made an authenticated request. Only set when login
+-- tracking is turned on.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS first_login_date timestamp,
+    ADD COLUMN IF NOT EXISTS last_login_date timestamp;
+
+COMMIT;
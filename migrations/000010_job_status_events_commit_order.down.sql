@@ -0,0 +1,35 @@
+BEGIN;
+
+CREATE OR REPLACE FUNCTION job_status_events_notify() RETURNS trigger AS $$
+DECLARE
+    ev job_status_events;
+BEGIN
+    INSERT INTO job_status_events (job_id, user_id, status, previous_status)
+    VALUES (
+        NEW.id,
+        NEW.user_id,
+        NEW.status,
+        CASE WHEN TG_OP = 'UPDATE' THEN OLD.status END
+    )
+    RETURNING * INTO ev;
+
+    PERFORM pg_notify('job_status_events', row_to_json(ev)::text);
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS jobs_status_inserted ON jobs;
+CREATE TRIGGER jobs_status_inserted
+    AFTER INSERT ON jobs
+    FOR EACH ROW
+    EXECUTE FUNCTION job_status_events_notify();
+
+DROP TRIGGER IF EXISTS jobs_status_updated ON jobs;
+CREATE TRIGGER jobs_status_updated
+    AFTER UPDATE OF status ON jobs
+    FOR EACH ROW
+    WHEN (OLD.status IS DISTINCT FROM NEW.status)
+    EXECUTE FUNCTION job_status_events_notify();
+
+COMMIT;
@@ -0,0 +1,44 @@
+BEGIN;
+
+-- Status event IDs come from a sequence, so with the triggers firing when the
+-- row changes, a transaction could take an ID and commit after one that took a
+-- higher ID. Clients resuming from the higher ID would never see the event.
+-- Firing the triggers at commit time and holding a lock until the commit
+-- finishes hands IDs out in commit order instead.
+CREATE OR REPLACE FUNCTION job_status_events_notify() RETURNS trigger AS $$
+DECLARE
+    ev job_status_events;
+BEGIN
+    PERFORM pg_advisory_xact_lock(hashtext('job_status_events'));
+
+    INSERT INTO job_status_events (job_id, user_id, status, previous_status)
+    VALUES (
+        NEW.id,
+        NEW.user_id,
+        NEW.status,
+        CASE WHEN TG_OP = 'UPDATE' THEN OLD.status END
+    )
+    RETURNING * INTO ev;
+
+    PERFORM pg_notify('job_status_events', row_to_json(ev)::text);
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS jobs_status_inserted ON jobs;
+CREATE CONSTRAINT TRIGGER jobs_status_inserted
+    AFTER INSERT ON jobs
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW
+    EXECUTE FUNCTION job_status_events_notify();
+
+DROP TRIGGER IF EXISTS jobs_status_updated ON jobs;
+CREATE CONSTRAINT TRIGGER jobs_status_updated
+    AFTER UPDATE OF status ON jobs
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW
+    WHEN (OLD.status IS DISTINCT FROM NEW.status)
+    EXECUTE FUNCTION job_status_events_notify();
+
+COMMIT;